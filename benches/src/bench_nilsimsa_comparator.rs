@@ -1,24 +1,15 @@
-#![feature(generic_const_exprs)]
-
 use criterion::{Criterion, criterion_group, criterion_main};
 use fe::Instance;
-use fe::traits::{FEInstance, FEPrivKey, FEPubKey};
+use fe::traits::{FEInstance, FEPubKey, FESecretKey};
+use fuzzy_hashes::complement_concat;
 use rand::RngExt;
 use rand::SeedableRng;
 use rand::rngs::StdRng;
 use rand::rngs::SysRng;
-use std::array;
 use std::hint::black_box;
 
 const N: usize = 256;
 
-fn not_concat<const N: usize>(v: [u8; N]) -> [u8; N + N]
-where
-    [(); N + N]:,
-{
-    array::from_fn(|i| if i < N { v[i] } else { 1 - v[i % N] })
-}
-
 fn bench_fe(c: &mut Criterion) {
     let mut vector = [0u8; N];
     let mut rand_bit_vector = [0u8; N];
@@ -29,7 +20,7 @@ fn bench_fe(c: &mut Criterion) {
         rand_bit_vector[i] = e % 2;
     }
 
-    let h1_not_concat = not_concat(rand_bit_vector);
+    let h1_not_concat: [u8; 2 * N] = complement_concat(rand_bit_vector, 1);
 
     rng.fill(&mut vector);
     // Bit vector
@@ -38,10 +29,10 @@ fn bench_fe(c: &mut Criterion) {
     }
 
     println!("{:?}", rand_bit_vector);
-    let h2_not_concat = not_concat(rand_bit_vector);
+    let h2_not_concat: [u8; 2 * N] = complement_concat(rand_bit_vector, 1);
 
     let instance = Instance::setup();
-    let pk = instance.public_key::<u8>();
+    let pk = instance.public_key();
     //let sk = instance.secret_key::<u8>(h1_not_concat);
 
     let ct = pk.encrypt(&mut rng, h2_not_concat);
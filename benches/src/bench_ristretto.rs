@@ -1,6 +1,6 @@
-use criterion::{Criterion, criterion_group, criterion_main};
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
 use fe::Instance;
-use fe::traits::{FEInstance, FEPrivKey, FEPubKey};
+use fe::traits::{FEInstance, FEPubKey, FESecretKey};
 use rand::RngExt;
 use rand::SeedableRng;
 use rand::rngs::StdRng;
@@ -9,14 +9,24 @@ use std::hint::black_box;
 
 const N: usize = 512;
 
+// The same workload (vector size, bit vector, bound) is used for every backend of the fe crate,
+// so that the results of the different backends are directly comparable.
 fn bench_fe(c: &mut Criterion) {
     #[cfg(feature = "elliptic-curve")]
     let mut group = c.benchmark_group("Ristretto FE");
-    #[cfg(feature = "finite-field")]
-    let mut group = c.benchmark_group("DH n°15 FE");
+    #[cfg(feature = "malachite")]
+    let mut group = c.benchmark_group("DH n°15 FE (malachite)");
+    #[cfg(feature = "num-bigint")]
+    let mut group = c.benchmark_group("DH n°15 FE (num-bigint)");
 
     let instance = Instance::<N>::setup();
-    let pk = instance.public_key::<u8>();
+    let pk = instance.public_key();
+
+    // Copy of the public key of the instance, or reference to the one computed at setup
+    group.bench_function("Public key (copy)", |b| b.iter(|| instance.public_key()));
+    group.bench_function("Public key (shared)", |b| {
+        b.iter(|| instance.shared_public_key())
+    });
 
     let mut vector = [0u8; N];
     let mut rand_bit_vector = [0u8; N];
@@ -27,17 +37,143 @@ fn bench_fe(c: &mut Criterion) {
         rand_bit_vector[i] = e % 2;
     }
     group.bench_function("Encrypt", |b| {
-        b.iter(|| pk.encrypt(&mut rng, black_box(vector)))
+        b.iter(|| pk.encrypt(&mut rng, black_box(rand_bit_vector)))
+    });
+    group.bench_function("Encrypt (by reference)", |b| {
+        b.iter(|| pk.encrypt_ref(&mut rng, black_box(&rand_bit_vector)))
     });
 
-    let ct = pk.encrypt(&mut rng, vector);
-    let sk = instance.secret_key(vector);
-    let bound = N as u16;
+    // Several vectors encrypted at once, the backend may share some work between them
+    let batch = vec![rand_bit_vector; 4];
+    group.bench_function("Encrypt batch of 4", |b| {
+        b.iter(|| pk.encrypt_batch(&mut rng, black_box(batch.clone())))
+    });
+
+    // Encryption right after receiving the key, including the decompression of its points
+    #[cfg(feature = "elliptic-curve")]
+    {
+        let bytes = postcard::to_stdvec(&pk).unwrap();
+        group.bench_function("Receive key and encrypt", |b| {
+            b.iter(|| {
+                let pk: fe::PublicKey<N> = postcard::from_bytes(black_box(&bytes)).unwrap();
+                pk.encrypt(&mut rng, black_box(rand_bit_vector))
+            })
+        });
+    }
+
+    // Key derivation of a bit vector, and of any other vector (i.e every entry multiplied)
+    group.bench_function("Secret key", |b| {
+        b.iter(|| instance.secret_key(black_box(rand_bit_vector)))
+    });
+    group.bench_function("Secret key (weighted)", |b| {
+        b.iter(|| instance.secret_key(black_box(vector)))
+    });
+
+    let ct = pk.encrypt(&mut rng, rand_bit_vector);
+    let sk = instance.secret_key(rand_bit_vector);
+    // The inner product of a bit vector with itself is at most N
+    let bound = (N + 1) as u16;
 
     group.bench_function("Decrypt", |b| {
-        b.iter(|| sk.decrypt(black_box(ct.clone()), black_box(bound.clone())))
+        b.iter(|| sk.decrypt(black_box(ct.clone()), black_box(bound)))
+    });
+
+    // Scalars of the secret key prepared once for all the decryptions
+    #[cfg(feature = "elliptic-curve")]
+    {
+        let context = sk.decrypt_context();
+        group.bench_function("Decrypt (context)", |b| {
+            b.iter(|| context.decrypt(black_box(ct.clone()), black_box(bound)))
+        });
+    }
+}
+
+// The discrete logarithm is brute forced up to the inner product, so the cost of a
+// decryption depends on the inner product (i.e on the similarity) rather than on the bound.
+// Vectors of size 4 reach the same inner products as bit vectors of size N, so that the
+// inner product computation doesn't hide the cost of the discrete logarithm.
+fn bench_decrypt_by_inner_product(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Decrypt by inner product");
+
+    let instance = Instance::<4>::setup();
+    let pk = instance.public_key();
+    let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+    let sk = instance.secret_key([1u8; 4]);
+    let bound = (N + 1) as u16;
+    #[cfg(feature = "elliptic-curve")]
+    let (context, table) = (sk.decrypt_context(), fe::DlogTable::new(&pk, bound));
+    // Smallest useful table and a baby step for each value, around the default size
+    #[cfg(feature = "elliptic-curve")]
+    let (small_table, full_table) = (
+        fe::DlogTable::with_table_size(&pk, bound, 4),
+        fe::DlogTable::with_table_size(&pk, bound, bound),
+    );
+
+    for inner_product in [0, N / 4, N / 2, 3 * N / 4, N] {
+        let ct = pk.encrypt(&mut rng, [(inner_product / 4) as u8; 4]);
+
+        group.bench_with_input(BenchmarkId::new("Decrypt", inner_product), &ct, |b, ct| {
+            b.iter(|| sk.decrypt(black_box(ct.clone()), black_box(bound)))
+        });
+
+        // Baby-step giant-step table instead of the linear search
+        #[cfg(feature = "elliptic-curve")]
+        group.bench_with_input(
+            BenchmarkId::new("Decrypt (table)", inner_product),
+            &ct,
+            |b, ct| b.iter(|| context.decrypt_with_table(black_box(ct.clone()), &table)),
+        );
+        #[cfg(feature = "elliptic-curve")]
+        group.bench_with_input(
+            BenchmarkId::new("Decrypt (small table)", inner_product),
+            &ct,
+            |b, ct| b.iter(|| context.decrypt_with_table(black_box(ct.clone()), &small_table)),
+        );
+        #[cfg(feature = "elliptic-curve")]
+        group.bench_with_input(
+            BenchmarkId::new("Decrypt (full table)", inner_product),
+            &ct,
+            |b, ct| b.iter(|| context.decrypt_with_table(black_box(ct.clone()), &full_table)),
+        );
+    }
+}
+
+// Secret keys of a full batch (as many vectors as a single instance allows), derived one
+// after the other or on all the cores (see the `parallel` feature of the authority).
+fn bench_secret_keys_batch(c: &mut Criterion) {
+    use rayon::prelude::*;
+
+    let mut group = c.benchmark_group("Secret keys of a batch");
+    group.sample_size(10);
+
+    let instance = Instance::<N>::setup();
+    let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+    let vectors: Vec<[u8; N]> = (0..N - 1)
+        .map(|_| core::array::from_fn(|_| rng.random::<u8>() % 2))
+        .collect();
+
+    group.bench_function("Sequential", |b| {
+        b.iter(|| {
+            black_box(&vectors)
+                .iter()
+                .map(|v| instance.secret_key(*v))
+                .collect::<Vec<_>>()
+        })
+    });
+    group.bench_function("Parallel", |b| {
+        b.iter(|| {
+            black_box(&vectors)
+                .par_iter()
+                .map(|v| instance.secret_key(*v))
+                .collect::<Vec<_>>()
+        })
     });
 }
 
-criterion_group!(benches, bench_fe);
+criterion_group!(
+    benches,
+    bench_fe,
+    bench_decrypt_by_inner_product,
+    bench_secret_keys_batch
+);
 criterion_main!(benches);
@@ -1,97 +1,340 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+use fe::SecretKey;
+use fe::traits::FEPubKey;
 use futures::SinkExt;
 use futures::StreamExt;
 use fuzzy_hashes::{FHVector, NILSIMSA_VECTOR_SIZE_BITS};
 use log::{debug, info};
-use messages::{EncryptionRequest, EncryptionResponse, HashComparisonRequest};
-use postcard;
-use std::error::Error;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
-use fe::traits::FEPubKey;
+use messages::{
+    ClientRequest, EncryptionRequest, EncryptionResponse, HashComparisonRequest, HashParameters,
+    SupportedHashesResponse, codec, encode_versioned, key_fingerprint, received,
+};
 use rand::{
     SeedableRng,
     rngs::{StdRng, SysRng},
 };
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
-pub struct Client {
-    stream: TcpStream,
+/// Comparison of a fuzzy hash to the corpus of the compute server, over any stream (a
+/// TcpStream, or an in-memory pipe in the tests).
+pub struct Client<S> {
+    // Held for the whole comparison, so that a frame sent by the server right after the
+    // previous one is never lost between two reads
+    stream: Framed<S, LengthDelimitedCodec>,
     fuzzy_hash: FHVector<u8>,
+    tag: Option<String>,
+    // Vectors announced by the compute server, if it was asked (see negotiate)
+    parameters: Option<HashParameters>,
+    // Score from which the comparison is ended early
+    min_score: Option<i16>,
 }
 
-impl Client {
-    /// The protocol is using framed content, encoded by prefixing the length of the payload
-    /// This reads an entire frame and returns what the readed frame.
-    async fn read_frame(&mut self) -> Result<Vec<u8>> {
-        let mut reader = FramedRead::new(&mut self.stream, LengthDelimitedCodec::new());
-        let frame = reader.next().await.unwrap().unwrap().to_vec();
-        Ok(frame)
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    /// Create a client comparing the given fuzzy hash to the corpus of the compute server,
+    /// or only to the fuzzy hashes of the corpus with the given tag.
+    pub fn new(stream: S, fuzzy_hash: FHVector<u8>, tag: Option<String>) -> Self {
+        Self {
+            stream: Framed::new(stream, codec()),
+            fuzzy_hash,
+            tag,
+            parameters: None,
+            min_score: None,
+        }
     }
 
-    /// The protocol is using framed content, encoded by prefixing the length of the payload
-    /// This write an entire frame made of the given bytes.
-    async fn write_frame(&mut self, bytes: Vec<u8>) -> Result<()> {
-        let mut writer = FramedWrite::new(&mut self.stream, LengthDelimitedCodec::new());
-        writer.send(bytes.into()).await?;
-        Ok(())
+    /// End the comparison as soon as the best score sent by the compute server reaches
+    /// min_score, instead of encrypting the vector for the rest of the corpus. The score of
+    /// a batch is sent along the key of the batch after the next one (the server compares
+    /// a batch while the next one is encrypted), so the stop lags by one batch.
+    pub fn with_min_score(mut self, min_score: i16) -> Self {
+        self.min_score = Some(min_score);
+        self
     }
 
-    pub fn new(stream: TcpStream, fuzzy_hash: FHVector<u8>) -> Self {
-        Self { stream, fuzzy_hash }
+    /// Ask the compute server which vectors to encrypt for the fuzzy hash, on the connection
+    /// used by start for the comparison. start then produces these vectors instead of
+    /// assuming the ones this client was built for, and fails if it can't.
+    pub async fn negotiate(&mut self) -> Result<HashParameters> {
+        let request = ClientRequest::DescribeHash(hash_type(&self.fuzzy_hash)?);
+        self.stream.send(encode_versioned(&request)?.into()).await?;
+
+        let frame = received(self.stream.next().await)?;
+        let parameters = postcard::from_bytes(&frame)?;
+        self.parameters = Some(parameters);
+        Ok(parameters)
     }
 
+    /// Compare the fuzzy hash to the corpus and return the max similarity score, i16::MIN
+    /// if the corpus is empty (i.e there is nothing to match).
     pub async fn start(&mut self) -> Result<i16> {
         info!("Started connection with server");
 
-        let message = match self.fuzzy_hash {
-            FHVector::NilsimsaVector(v) => HashComparisonRequest::NILSIMSA,
-        };
+        // The keys and ciphertexts are sized at compile time, one arm per fuzzy hash
+        match hash_type(&self.fuzzy_hash)? {
+            hash_type @ HashComparisonRequest::NILSIMSA => {
+                self.compare::<NILSIMSA_VECTOR_SIZE_BITS>(hash_type).await
+            }
+        }
+    }
+
+    async fn compare<const N: usize>(&mut self, hash_type: HashComparisonRequest) -> Result<i16> {
+        let message = ClientRequest::Compare(hash_type, self.tag.clone());
 
         // Init similarity score
         let mut score = i16::MIN;
         // Init the vector to compute the fuzzy hash comparison
-        let vector = match self.fuzzy_hash {
-        	FHVector::NilsimsaVector(_) => self.fuzzy_hash.to_bits::<NILSIMSA_VECTOR_SIZE_BITS>()?,
-        };
+        let vector = self.fuzzy_hash.to_fe_vector::<N>()?;
+        if let Some(parameters) = self.parameters {
+            check_parameters(parameters, &vector)?;
+        }
         // Init the RNG to perform encryption
         let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
 
         // Compute the vector to compare fuzzy hashes
         info!("Sending request to server");
-        self.write_frame(postcard::to_stdvec(&message)?).await?;
+        self.stream.send(encode_versioned(&message)?.into()).await?;
 
         loop {
-	        let encryption_rq = match self.fuzzy_hash {
-	            FHVector::NilsimsaVector(_) => postcard::from_bytes::<
-	                EncryptionRequest<NILSIMSA_VECTOR_SIZE_BITS, i16>,
-	            >(&self.read_frame().await?)?,
-	        };
-
-	        debug!("Received a public key from the server");
-
-	        // Update similarity score if any
-	        match encryption_rq.similarity_score {
-	        	Some(s) => score = if score > s {score} else {s},
-	        	None => {}
-	        };
-
-	        // Retrieve the pk if any
-	        let pk = match encryption_rq.pk {
-	        	Some(pk) => pk,
-	        	// None means no more vectors to compare to on the server side
-	        	None => return Ok(score),
-	        };
-
-	        
-
-	        info!("Encrypting vector...");
-	        let encrypted_vector = pk.encrypt(&mut rng, vector);
-	        info!("Sending ct to server");
-	        let encryption_response = EncryptionResponse::EncryptedVector(encrypted_vector);
-	        self.write_frame(postcard::to_stdvec(&encryption_response)?).await?;
-	    }
-
-        Ok(i16::MIN)
+            let frame = received(self.stream.next().await)?;
+            match self.reply::<N>(&frame, &mut score, vector, &mut rng)? {
+                Some(reply) => self.stream.send(reply.into()).await?,
+                // None means no more vectors to compare to on the server side
+                None => {
+                    self.stream.close().await?;
+                    return Ok(score);
+                }
+            }
+        }
+    }
+
+    /// Serialized reply to a request of the compute server (the encrypted vector, or the end
+    /// of the comparison once min_score is reached), None at the end of the comparison.
+    fn reply<const N: usize>(
+        &self,
+        frame: &[u8],
+        score: &mut i16,
+        vector: [u8; N],
+        rng: &mut StdRng,
+    ) -> Result<Option<Vec<u8>>> {
+        let encryption_rq = postcard::from_bytes::<EncryptionRequest<N, i16>>(frame)?;
+
+        debug!("Received a public key from the server");
+
+        // Update similarity score if any
+        if let Some(s) = encryption_rq.similarity_score {
+            *score = (*score).max(s);
+        }
+
+        // Similar enough, the server replies with the end of the comparison
+        if !encryption_rq.is_end() && self.min_score.is_some_and(|min| *score >= min) {
+            info!("Reached the minimum score, ending the comparison");
+            let end = EncryptionResponse::<N>::EndOfComparison;
+            return Ok(Some(postcard::to_stdvec(&end)?));
+        }
+
+        // Retrieve the pk if any
+        let Some(pk) = encryption_rq.pk else {
+            return Ok(None);
+        };
+
+        // Do not encrypt under a key that would leak the vector
+        pk.validate()?;
+
+        info!("Encrypting vector...");
+        let fingerprint = key_fingerprint(&pk)?;
+        let encryption_response = match encryption_rq.session {
+            // Prove that the ciphertext was computed for this session
+            Some(session) => {
+                let (encrypted_vector, proof) = pk.encrypt_bound(rng, vector, &session);
+                EncryptionResponse::BoundVector(encrypted_vector, fingerprint, proof)
+            }
+            None => EncryptionResponse::EncryptedVector(pk.encrypt(rng, vector), fingerprint),
+        };
+        info!("Sending ct to server");
+        // The server compares it while sending the next public key
+        Ok(Some(postcard::to_stdvec(&encryption_response)?))
+    }
+}
+
+/// Type of comparison to request to the compute server for the given fuzzy hash.
+pub fn hash_type(fuzzy_hash: &FHVector<u8>) -> Result<HashComparisonRequest> {
+    match fuzzy_hash {
+        FHVector::NilsimsaVector(_) => Ok(HashComparisonRequest::NILSIMSA),
+        FHVector::FeatureVector(_) => Err(anyhow!(
+            "Feature vectors can't be compared by the compute server"
+        )),
+    }
+}
+
+/// Check that the vectors announced by the compute server are those this client produces
+/// for the fuzzy hash, and that the server can recover the score of an identical fuzzy hash.
+fn check_parameters<const N: usize>(parameters: HashParameters, vector: &[u8; N]) -> Result<()> {
+    if parameters.dimension as usize != N {
+        return Err(anyhow!(
+            "The compute server expects vectors of {} entries, this client produces {} for this fuzzy hash",
+            parameters.dimension,
+            N
+        ));
+    }
+
+    // Inner product of the vector with itself
+    let identical: u32 = vector.iter().map(|&x| u32::from(x) * u32::from(x)).sum();
+    let max = SecretKey::<N>::max_recoverable(parameters.bound);
+    if max.is_none_or(|max| u32::from(max) < identical) {
+        return Err(anyhow!(
+            "The compute server decrypts the scores up to {:?}, a match with an identical fuzzy hash scores {}",
+            max,
+            identical
+        ));
+    }
+    Ok(())
+}
+
+/// Ask the compute server which fuzzy hashes it is able to compare.
+pub async fn list_supported_hashes(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) -> Result<Vec<HashComparisonRequest>> {
+    let mut framed = Framed::new(stream, codec());
+    framed
+        .send(encode_versioned(&ClientRequest::ListSupportedHashes)?.into())
+        .await?;
+
+    let frame = received(framed.next().await)?;
+    let response: SupportedHashesResponse = postcard::from_bytes(&frame)?;
+
+    Ok(response.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fe::traits::FEInstance;
+    use messages::decode_versioned;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::FramedRead;
+
+    #[tokio::test]
+    async fn test_negotiation() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let announced = HashParameters {
+            dimension: NILSIMSA_VECTOR_SIZE_BITS as u32,
+            bound: 257,
+        };
+
+        // Compute server announcing the parameters, then ending the comparison at once
+        let server = tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            let mut framed = tokio_util::codec::Framed::new(&mut s, codec());
+            let request = decode_versioned(&framed.next().await.unwrap().unwrap()).unwrap();
+            assert!(matches!(
+                request,
+                ClientRequest::DescribeHash(HashComparisonRequest::NILSIMSA)
+            ));
+            let response = postcard::to_stdvec(&announced).unwrap();
+            framed.send(response.into()).await.unwrap();
+
+            // On the same connection
+            let request = decode_versioned(&framed.next().await.unwrap().unwrap()).unwrap();
+            assert!(matches!(request, ClientRequest::Compare(_, None)));
+            let end = EncryptionRequest::<NILSIMSA_VECTOR_SIZE_BITS, i16>::end(42);
+            let response = postcard::to_stdvec(&end).unwrap();
+            framed.send(response.into()).await.unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut client = Client::new(stream, FHVector::from([0x17; 32]), None);
+        assert_eq!(client.negotiate().await.unwrap(), announced);
+        assert_eq!(client.start().await.unwrap(), 42);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_min_score() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Compute server with 3 batches, the second one matching
+        let pk = fe::Instance::<NILSIMSA_VECTOR_SIZE_BITS>::setup().public_key();
+        let frames: Vec<Vec<u8>> = [i16::MIN, 10, 120]
+            .into_iter()
+            .map(|score| postcard::to_stdvec(&EncryptionRequest::with_key(pk.clone(), score)))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let server = tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            let mut framed = tokio_util::codec::Framed::new(&mut s, codec());
+            framed.next().await.unwrap().unwrap();
+
+            let mut encrypted = 0;
+            for frame in frames {
+                framed.send(frame.into()).await.unwrap();
+                let response: EncryptionResponse<NILSIMSA_VECTOR_SIZE_BITS> =
+                    postcard::from_bytes(&framed.next().await.unwrap().unwrap()).unwrap();
+                match response {
+                    EncryptionResponse::EndOfComparison => break,
+                    _ => encrypted += 1,
+                }
+            }
+            let end = EncryptionRequest::<NILSIMSA_VECTOR_SIZE_BITS, i16>::end(121);
+            framed
+                .send(postcard::to_stdvec(&end).unwrap().into())
+                .await
+                .unwrap();
+            encrypted
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut client = Client::new(stream, FHVector::from([0x17; 32]), None).with_min_score(100);
+        // The final score includes the last batch compared
+        assert_eq!(client.start().await.unwrap(), 121);
+        // No encryption under the key of the third batch
+        assert_eq!(server.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_dimension() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut s, _) = listener.accept().await.unwrap();
+
+        let parameters = HashParameters {
+            dimension: 128,
+            bound: 65,
+        };
+        let mut client = Client::new(stream, FHVector::from([0x17; 32]), None);
+        client.parameters = Some(parameters);
+        let error = client.start().await.unwrap_err();
+        assert!(error.to_string().contains("128"), "{}", error);
+
+        // Nothing was sent to the server
+        drop(client);
+        let mut reader = FramedRead::new(&mut s, codec());
+        assert!(reader.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bound_too_low() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut s, _) = listener.accept().await.unwrap();
+
+        // The score of identical Nilsimsa hashes is 256
+        let parameters = HashParameters {
+            dimension: NILSIMSA_VECTOR_SIZE_BITS as u32,
+            bound: 256,
+        };
+        let mut client = Client::new(stream, FHVector::from([0x17; 32]), None);
+        client.parameters = Some(parameters);
+        let error = client.start().await.unwrap_err();
+        assert!(error.to_string().contains("255"), "{}", error);
+
+        // Nothing was sent to the server
+        drop(client);
+        let mut reader = FramedRead::new(&mut s, codec());
+        assert!(reader.next().await.is_none());
     }
 }
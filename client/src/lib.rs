@@ -0,0 +1,4 @@
+//! Client of the compute server, also used by its tests to drive a real comparison.
+mod client;
+
+pub use client::{Client, list_supported_hashes};
@@ -1,70 +1,324 @@
 use anyhow::Result;
 use anyhow::anyhow;
-use clap::Parser;
-use fuzzy_hashes::{FHVector, Nilsimsa};
+use clap::{Parser, ValueEnum};
+use fuzzy_hashes::{FHVector, FuzzyHasher, Nilsimsa};
 use log::{debug, info};
+use serde::Serialize;
 use std::fs::File;
-use std::io::Read;
-use std::io::{BufRead, BufReader};
+use std::io::BufReader;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use tokio::net::TcpStream;
 
-mod client;
-use client::Client;
+use client::{Client, list_supported_hashes};
 
 /// Arguments of the program
 #[derive(Parser)]
 struct Cli {
-    compute_addr: String,
-    file: std::path::PathBuf,
-    #[clap(long, action, default_value = "true", conflicts_with = "sdhash")]
-    nilsimsa: bool,
-    #[clap(long, action, conflicts_with = "nilsimsa")]
-    sdhash: bool,
+    /// Address of the compute server (omitted with --hash-only)
+    #[clap(required_unless_present = "hash_only", conflicts_with = "hash_only")]
+    compute_addr: Option<String>,
+    /// File to hash, stdin is read if it is omitted or set to `-`
+    #[clap(conflicts_with = "hash_only")]
+    file: Option<PathBuf>,
+    /// Fuzzy hash algorithm used to hash the file
+    #[clap(long, value_enum, default_value_t = Algorithm::Nilsimsa)]
+    algo: Algorithm,
+    /// Only list the fuzzy hashes supported by the compute server
+    #[clap(long, action)]
+    list_hashes: bool,
+    /// Only print the Nilsimsa digest (hex) of the given file, without connecting to any
+    /// server. Stdin is read if the file is omitted or set to `-`
+    #[clap(
+        long,
+        value_name = "FILE",
+        num_args = 0..=1,
+        default_missing_value = "-",
+        conflicts_with = "list_hashes"
+    )]
+    hash_only: Option<PathBuf>,
+    /// Only compare to the fuzzy hashes of the corpus with this tag (e.g a dataset label)
+    #[clap(long)]
+    tag: Option<String>,
+    /// End the comparison as soon as a fuzzy hash of the corpus reaches this score
+    #[clap(long, allow_negative_numbers = true)]
+    min_score: Option<i16>,
+    /// Format of the result printed on stdout
+    #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+}
+
+impl Cli {
+    /// Address of the compute server
+    fn compute_addr(&self) -> Result<&str> {
+        self.compute_addr
+            .as_deref()
+            .ok_or(anyhow!("Please give the address of the compute server"))
+    }
+
+    /// File to hash, None for stdin.
+    fn input(&self) -> Option<&Path> {
+        self.hash_only
+            .as_deref()
+            .or(self.file.as_deref())
+            .filter(|path| *path != Path::new("-"))
+    }
+}
+
+/// Fuzzy hash algorithm
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Algorithm {
+    /// Nilsimsa
+    Nilsimsa,
+    /// SDHash (not implemented yet)
+    Sdhash,
+}
+
+impl Algorithm {
+    /// Hasher computing the fuzzy hash of the file
+    fn hasher(self) -> Result<Box<dyn FuzzyHasher>> {
+        match self {
+            Algorithm::Nilsimsa => Ok(Box::new(Nilsimsa::new())),
+            Algorithm::Sdhash => Err(anyhow!("SDHash is not implemented")),
+        }
+    }
+}
+
+/// Format of the result of the comparison
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum OutputFormat {
+    /// Human readable sentence
+    Text,
+    /// JSON object, for scripts
+    Json,
+}
+
+/// Result of the comparison, as printed in JSON format
+#[derive(Serialize)]
+struct ComparisonOutput {
+    max_similarity_score: i16,
+}
+
+/// Print the result of the comparison using the given format.
+fn print_result(out: &mut impl Write, format: OutputFormat, score: i16) -> Result<()> {
+    match format {
+        OutputFormat::Text => writeln!(out, "Max similarity score is {:?}", score)?,
+        OutputFormat::Json => {
+            let output = ComparisonOutput {
+                max_similarity_score: score,
+            };
+            serde_json::to_writer(&mut *out, &output)?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
 }
 
 // 2^24 bytes
 const BUF_SIZE: usize = 16777216;
 
+/// Read the whole content of the reader, chunk by chunk.
+fn read_chunks(reader: &mut impl Read, mut f: impl FnMut(&[u8])) -> Result<()> {
+    let mut buffer = vec![0; BUF_SIZE];
+
+    loop {
+        let c = reader.read(&mut buffer)?;
+        if c == 0 {
+            return Ok(());
+        }
+        f(&buffer[..c]);
+    }
+}
+
+/// Hash the whole content of the reader in a single pass, feeding each chunk to
+/// all the hashers. Vectors are returned in the same order as the hashers.
+fn hash_reader(
+    reader: &mut impl Read,
+    mut hashers: Vec<Box<dyn FuzzyHasher>>,
+) -> Result<Vec<FHVector<u8>>> {
+    read_chunks(reader, |chunk| {
+        for hasher in hashers.iter_mut() {
+            hasher.update(chunk);
+        }
+    })?;
+
+    Ok(hashers.into_iter().map(|h| h.finalize()).collect())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
 
-    let args = Cli::parse();
-
-    info!("Computing fuzzy hash for {}", &args.file.display());
+    run(Cli::parse(), &mut std::io::stdout()).await
+}
 
-    // Read the file and hash it
-    let mut f = File::open(&args.file)?;
-    let mut reader = BufReader::new(f);
-    let mut hash: FHVector<u8>;
+async fn run(args: Cli, out: &mut impl Write) -> Result<()> {
+    if args.list_hashes {
+        let mut stream = TcpStream::connect(args.compute_addr()?).await?;
+        let supported = list_supported_hashes(&mut stream).await?;
+        writeln!(out, "Supported fuzzy hashes : {:?}", supported)?;
+        return Ok(());
+    }
 
-    if args.nilsimsa {
-        debug!("Hashing using nilsimsa");
-        let mut hasher = Nilsimsa::new();
-        let mut buffer = vec![0; BUF_SIZE];
-
-        loop {
-            let c = reader.read(&mut buffer)?;
-            if c == 0 {
-                break;
-            }
-            hasher.update(&buffer[..c]);
+    // Open the file (or stdin) to hash
+    let input: Box<dyn Read> = match args.input() {
+        Some(path) => {
+            info!("Computing fuzzy hash for {}", path.display());
+            Box::new(File::open(path)?)
+        }
+        _ => {
+            info!("Computing fuzzy hash for stdin");
+            Box::new(std::io::stdin().lock())
         }
-        hash = FHVector::from(hasher.digest());
-    } else if args.sdhash {
-        return Err(anyhow!("Not implemented"));
-    } else {
-        return Err(anyhow!("Please select a fuzzy hash algorithm"));
+    };
+    let mut reader = BufReader::new(input);
+
+    if args.hash_only.is_some() {
+        let mut hasher = args.algo.hasher()?;
+        read_chunks(&mut reader, |chunk| hasher.update(chunk))?;
+        writeln!(out, "{}", hasher.finalize_hex())?;
+        return Ok(());
     }
 
+    debug!("Hashing using {:?}", args.algo);
+    // A single hasher, so a single vector
+    let hash = hash_reader(&mut reader, vec![args.algo.hasher()?])?.remove(0);
+
     debug!("Computed hash : {:?}", hash);
 
     // Connect to a peer
-    let mut stream = TcpStream::connect(&args.compute_addr).await?;
+    let stream = TcpStream::connect(args.compute_addr()?).await?;
 
-    let mut client = Client::new(stream, hash);
+    let mut client = Client::new(stream, hash, args.tag);
+    // Ask the server which vectors it expects, before the comparison
+    let parameters = client.negotiate().await?;
+    debug!("Parameters announced by the server : {:?}", parameters);
+    if let Some(min_score) = args.min_score {
+        client = client.with_min_score(min_score);
+    }
     let max_similarity_score = client.start().await?;
 
-    println!("Max similarity score is {:?}", max_similarity_score);
-    Ok(())
+    print_result(out, args.format, max_similarity_score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_nilsimsa_hash_from_reader() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+
+        let mut hasher = Nilsimsa::new();
+        hasher.update(&data);
+        let expected = FHVector::from(hasher.digest());
+
+        // Same bytes piped through a reader (e.g stdin), that are read in several chunks
+        let mut reader = Cursor::new(&data[..3000]).chain(Cursor::new(&data[3000..]));
+        let hashes = hash_reader(&mut reader, vec![Box::new(Nilsimsa::new())]).unwrap();
+
+        assert_eq!(hashes.len(), 1);
+        assert_eq!(format!("{:?}", hashes[0]), format!("{:?}", expected));
+    }
+
+    #[test]
+    fn test_single_pass_multi_hash() {
+        let a: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let b: Vec<u8> = (0..10_000u32).map(|i| (i * 13 % 241) as u8).collect();
+
+        // Each hasher already fed with different data before the common pass
+        let mut first = Nilsimsa::new();
+        first.update(&a);
+        let mut second = Nilsimsa::new();
+        second.update(&b);
+        let hashers: Vec<Box<dyn FuzzyHasher>> = vec![Box::new(first), Box::new(second)];
+
+        let mut reader = Cursor::new(&a);
+        let hashes = hash_reader(&mut reader, hashers).unwrap();
+
+        // Independently computed digests
+        let expected: Vec<FHVector<u8>> = [&a, &b]
+            .iter()
+            .map(|prefix| {
+                let mut hasher = Nilsimsa::new();
+                hasher.update(prefix);
+                hasher.update(&a);
+                FHVector::from(hasher.digest())
+            })
+            .collect();
+
+        assert_eq!(format!("{:?}", hashes), format!("{:?}", expected));
+    }
+
+    #[test]
+    fn test_print_result() {
+        let mut out = Vec::new();
+        print_result(&mut out, OutputFormat::Text, 42).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Max similarity score is 42\n"
+        );
+
+        let mut out = Vec::new();
+        print_result(&mut out, OutputFormat::Json, -12).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value, serde_json::json!({ "max_similarity_score": -12 }));
+    }
+
+    #[tokio::test]
+    async fn test_hash_only() {
+        let path = std::env::temp_dir().join(format!("hash_only_{}", std::process::id()));
+        std::fs::write(&path, b"abcdefgh").unwrap();
+
+        // Connecting to a server would fail as there is no valid address to connect to
+        let args = Cli::try_parse_from(["client", "--hash-only", path.to_str().unwrap()]).unwrap();
+        assert_eq!(args.input(), Some(path.as_path()));
+
+        let mut out = Vec::new();
+        let result = run(args, &mut out).await;
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "14c8118000000000030800000004042004189020001308014088003280000078\n"
+        );
+
+        // The positional arguments of a comparison are refused
+        assert!(Cli::try_parse_from(["client", "--hash-only", "file", "addr"]).is_err());
+        assert!(Cli::try_parse_from(["client", "addr", "--hash-only", "file"]).is_err());
+        assert!(Cli::try_parse_from(["client", "addr", "file", "--hash-only"]).is_err());
+
+        // Stdin without a file
+        let args = Cli::try_parse_from(["client", "--hash-only"]).unwrap();
+        assert_eq!(args.hash_only.as_deref(), Some(Path::new("-")));
+        assert_eq!(args.input(), None);
+    }
+
+    #[test]
+    fn test_algo_selection() {
+        let algo = |args: &[&str]| {
+            Cli::try_parse_from([&["client", "addr"], args].concat()).map(|cli| cli.algo)
+        };
+        assert_eq!(algo(&[]).unwrap(), Algorithm::Nilsimsa);
+        assert_eq!(algo(&["--algo", "nilsimsa"]).unwrap(), Algorithm::Nilsimsa);
+        assert_eq!(algo(&["--algo", "sdhash"]).unwrap(), Algorithm::Sdhash);
+
+        assert!(algo(&["--algo", "ssdeep"]).is_err());
+        assert!(algo(&["--algo"]).is_err());
+        assert!(algo(&["--algo", "nilsimsa", "--algo", "sdhash"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sdhash_not_implemented() {
+        // The file is never hashed nor sent, nothing listens on the address
+        let args = Cli::try_parse_from(["client", "addr", "Cargo.toml", "--algo", "sdhash"]);
+        let error = run(args.unwrap(), &mut Vec::new()).await.unwrap_err();
+        assert!(error.to_string().contains("not implemented"));
+
+        let args = Cli::try_parse_from(["client", "--hash-only", "Cargo.toml", "--algo", "sdhash"]);
+        let error = run(args.unwrap(), &mut Vec::new()).await.unwrap_err();
+        assert!(error.to_string().contains("not implemented"));
+    }
 }
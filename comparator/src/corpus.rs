@@ -0,0 +1,108 @@
+//! Corpus kept encrypted at rest, compared to the secret key of a query.
+//!
+//! This inverts the usual flow : the corpus entries are encrypted once under the public key
+//! of an instance and stored as ciphertexts (e.g by the compute server), and the client gets
+//! the secret key of its query vector to decrypt the inner products with every entry.
+//!
+//! It assumes that the authority holding the instance is trusted by both sides : it derives
+//! the secret key of the query, so it learns the query vector, and it must never hand out
+//! N keys (or more) of an instance of size N, otherwise their owner could solve the inner
+//! products for the vectors of the corpus. Once the budget of keys of an instance is
+//! spent, the corpus has to be encrypted again under a new instance.
+
+use fe::CipherText;
+
+use crate::traits::Comparator;
+
+/// Ciphertexts of the corpus entries, in the order they were added.
+#[derive(Debug, Clone, Default)]
+pub struct EncryptedCorpus<const N: usize> {
+    entries: Vec<CipherText<N>>,
+}
+
+impl<const N: usize> From<Vec<CipherText<N>>> for EncryptedCorpus<N> {
+    fn from(entries: Vec<CipherText<N>>) -> Self {
+        Self { entries }
+    }
+}
+
+impl<const N: usize> EncryptedCorpus<N> {
+    /// Add an entry encrypted under the public key of the instance.
+    pub fn push(&mut self, entry: CipherText<N>) {
+        self.entries.push(entry);
+    }
+
+    /// Number of entries of the corpus.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Return true if the corpus has no entry.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Compare the query (i.e the secret key wrapped by the comparator) to every entry,
+    /// None for the entries whose score can't be recovered (e.g malformed ones).
+    pub fn compare<T, C: Comparator<N, T, CipherText<N>>>(&self, comparator: &C) -> Vec<Option<T>> {
+        self.entries
+            .iter()
+            .map(|entry| comparator.try_compare(entry.clone()))
+            .collect()
+    }
+
+    /// Index and score of the entry the most similar to the query, if any.
+    pub fn best_match<T: Ord, C: Comparator<N, T, CipherText<N>>>(
+        &self,
+        comparator: &C,
+    ) -> Option<(usize, T)> {
+        self.compare(comparator)
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, score)| Some((i, score?)))
+            .max_by(|a, b| a.1.cmp(&b.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NilsimsaInstance;
+    use fe::Instance;
+    use fe::traits::{FEInstance, FEPubKey};
+    use fuzzy_hashes::{FHVector, NILSIMSA_VECTOR_SIZE_BITS};
+    use rand::SeedableRng;
+    use rand::rngs::{StdRng, SysRng};
+
+    #[test]
+    fn test_compare_encrypted_corpus() {
+        let instance = Instance::<NILSIMSA_VECTOR_SIZE_BITS>::setup();
+        let pk = instance.public_key();
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+
+        let query = [0x3cu8; 32];
+        let mut close = query;
+        close[3] ^= 0b101;
+        let digests = [[0u8; 32], close, [0xc3; 32]];
+
+        // Encrypted once, e.g by the compute server
+        let mut corpus = EncryptedCorpus::default();
+        for digest in digests {
+            let vector: [u8; NILSIMSA_VECTOR_SIZE_BITS] =
+                FHVector::from(digest).to_fe_vector().unwrap();
+            corpus.push(pk.encrypt(&mut rng, vector));
+        }
+        assert_eq!(corpus.len(), 3);
+
+        // Secret key of the query, handed to the client
+        let sk = instance.secret_key_from_nilsimsa(query);
+        let expected: Vec<Option<i16>> = digests
+            .iter()
+            .map(|digest| Some(fuzzy_hashes::nilsimsa_compare(&query, digest)))
+            .collect();
+        assert_eq!(corpus.compare(&sk), expected);
+        assert_eq!(corpus.best_match(&sk), Some((1, 126)));
+
+        assert_eq!(EncryptedCorpus::default().best_match(&sk), None);
+    }
+}
@@ -0,0 +1,69 @@
+use crate::traits::Comparator;
+
+/// Score of the encrypted vector against each secret key of a corpus, along with the
+/// index of the key in the corpus (e.g for calibration). The comparisons are done lazily,
+/// one key at a time, so the scores are never all kept in memory.
+///
+/// The score is None for a key the ciphertext can't be compared with (e.g a malformed
+/// ciphertext sent by a client), the other keys are still compared.
+///
+/// Unlike the max score, the distribution tells how close the vector is to each fuzzy hash
+/// of the corpus, so it must not be sent to a client.
+pub fn score_distribution<'a, const N: usize, T, E, K>(
+    keys: impl IntoIterator<Item = &'a K> + 'a,
+    encrypted_vector: &'a E,
+) -> impl Iterator<Item = (usize, Option<T>)> + 'a
+where
+    E: Clone,
+    K: Comparator<N, T, E> + 'a,
+{
+    keys.into_iter()
+        .map(|key| key.try_compare(encrypted_vector.clone()))
+        .enumerate()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NilsimsaInstance;
+    use fe::Instance;
+    use fe::traits::{FEInstance, FEPubKey};
+    use fuzzy_hashes::{FHVector, NILSIMSA_VECTOR_SIZE_BITS};
+    use rand::SeedableRng;
+    use rand::rngs::{StdRng, SysRng};
+    use std::array;
+
+    #[test]
+    fn test_score_distribution() {
+        let corpus: Vec<[u8; 32]> = (0..6u8)
+            .map(|i| array::from_fn(|j| (j as u8).wrapping_mul(i + 1) ^ (i << 4)))
+            .collect();
+        let client = corpus[2];
+
+        let instance = Instance::setup();
+        let sks: Vec<_> = corpus
+            .iter()
+            .map(|digest| instance.secret_key_from_nilsimsa(*digest))
+            .collect();
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let ct = instance
+            .public_key()
+            .encrypt(&mut rng, FHVector::from(client).to_fe_vector().unwrap());
+
+        let distribution: Vec<(usize, Option<i16>)> = score_distribution(&sks, &ct).collect();
+        let expected: Vec<(usize, Option<i16>)> = corpus
+            .iter()
+            .map(|digest| Some(fuzzy_hashes::nilsimsa_compare(digest, &client)))
+            .enumerate()
+            .collect();
+        assert_eq!(distribution, expected);
+        // The client hash is in the corpus
+        assert_eq!(distribution[2].1, Some(128));
+
+        // A malformed ciphertext is reported for each key instead of panicking
+        let malformed = instance
+            .public_key()
+            .encrypt(&mut rng, [2u8; NILSIMSA_VECTOR_SIZE_BITS]);
+        assert!(score_distribution(&sks, &malformed).all(|(_, score)| score.is_none()));
+    }
+}
@@ -0,0 +1,30 @@
+use std::fmt;
+
+/// Errors returned when building a comparator, or when turning an inner product into a score.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ComparatorError {
+    /// The largest inner product the comparator may have to decrypt doesn't fit in an u16.
+    BoundOverflow,
+    /// The inner product can't be obtained from two vectors of this kind (e.g a Nilsimsa
+    /// inner product above 256).
+    InnerProductOutOfRange(u16),
+}
+
+impl fmt::Display for ComparatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ComparatorError::BoundOverflow => write!(
+                f,
+                "the largest weighted inner product can't be decrypted (it exceeds {})",
+                u16::MAX - 1
+            ),
+            ComparatorError::InnerProductOutOfRange(inner_product) => write!(
+                f,
+                "the inner product {} is out of the range of the scores",
+                inner_product
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ComparatorError {}
@@ -0,0 +1,102 @@
+use fe::traits::FESecretKey;
+use fe::{CipherText, SecretKey};
+
+use crate::ComparatorError;
+use crate::traits::Comparator;
+
+/// Comparator for sets of features encoded as indicator vectors (see
+/// `FHVector::FeatureVector`). The inner product of two indicator vectors is the size
+/// of the intersection of both sets, from which the Jaccard index can be derived.
+#[derive(Debug, Clone)]
+pub struct IntersectionComparator<const N: usize> {
+    sk: SecretKey<N>,
+}
+
+impl<const N: usize> From<SecretKey<N>> for IntersectionComparator<N> {
+    fn from(sk: SecretKey<N>) -> Self {
+        Self { sk }
+    }
+}
+
+impl<const N: usize> Comparator<N, u16, CipherText<N>> for IntersectionComparator<N> {
+    fn try_compare(&self, encrypted_vector: CipherText<N>) -> Option<u16> {
+        self.compare_raw(encrypted_vector)
+    }
+
+    fn compare_raw(&self, encrypted_vector: CipherText<N>) -> Option<u16> {
+        // The intersection can't be bigger than the dimension of the vectors
+        self.sk
+            .decrypt(encrypted_vector, SecretKey::<N>::BIT_VECTOR_BOUND)
+    }
+}
+
+/// Jaccard index of two sets, given the size of their intersection (e.g computed by an
+/// IntersectionComparator) and the size of each set. Two empty sets are considered identical.
+/// An intersection bigger than one of the sets is an error (e.g wrong sizes).
+pub fn jaccard(intersection: u16, size_a: u16, size_b: u16) -> Result<f32, ComparatorError> {
+    if intersection > size_a.min(size_b) {
+        return Err(ComparatorError::InnerProductOutOfRange(intersection));
+    }
+
+    let union = size_a as u32 + size_b as u32 - intersection as u32;
+    if union == 0 {
+        return Ok(1.0);
+    }
+
+    Ok(intersection as f32 / union as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fe::Instance;
+    use fe::traits::{FEInstance, FEPubKey};
+    use fuzzy_hashes::{FEATURE_VECTOR_SIZE_BITS, FHVector};
+    use rand::SeedableRng;
+    use rand::rngs::{StdRng, SysRng};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_intersection_and_jaccard() {
+        let a: HashSet<usize> = [1, 5, 7, 64, 100, 512, 1000].into();
+        let b: HashSet<usize> = [5, 7, 8, 100, 999, 1000].into();
+
+        let vector = |set: &HashSet<usize>| -> [u8; FEATURE_VECTOR_SIZE_BITS] {
+            FHVector::from_features(set.iter().copied())
+                .unwrap()
+                .to_fe_vector()
+                .unwrap()
+        };
+
+        let instance = Instance::<FEATURE_VECTOR_SIZE_BITS>::setup();
+        let pk = instance.public_key();
+        let comparator = IntersectionComparator::from(instance.secret_key(vector(&a)));
+
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let intersection = comparator.compare(pk.encrypt(&mut rng, vector(&b)));
+        assert_eq!(intersection as usize, a.intersection(&b).count());
+
+        // Plaintext Jaccard index
+        let expected = a.intersection(&b).count() as f32 / a.union(&b).count() as f32;
+        assert_eq!(
+            jaccard(intersection, a.len() as u16, b.len() as u16),
+            Ok(expected)
+        );
+    }
+
+    #[test]
+    fn test_jaccard() {
+        assert_eq!(jaccard(0, 0, 0), Ok(1.0));
+        assert_eq!(jaccard(0, 3, 4), Ok(0.0));
+        assert_eq!(jaccard(3, 3, 3), Ok(1.0));
+        assert_eq!(jaccard(2, 4, 3), Ok(0.4));
+        assert_eq!(
+            jaccard(4, 3, 5),
+            Err(ComparatorError::InnerProductOutOfRange(4))
+        );
+        assert_eq!(
+            jaccard(1, 0, 0),
+            Err(ComparatorError::InnerProductOutOfRange(1))
+        );
+    }
+}
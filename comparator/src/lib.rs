@@ -3,6 +3,9 @@
 //! Module containing the implementation of the comparison
 //! functions used for any implemented fuzzy hash.
 //!
+//! It works with both backends of the fe crate, selected with the features
+//! `elliptic-curve` (default) and `finite-field` (along `malachite` or `num-bigint`).
+//!
 //! Here is a basic example of how it's working :
 //!
 //! ```rust
@@ -40,8 +43,7 @@
 //! let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
 //!
 //! let instance = Instance::<512>::setup();
-//! // We want to encrypt vectors of u8
-//! let pk = instance.public_key::<u8>();
+//! let pk = instance.public_key();
 //!
 //! // Get a secret key for v1
 //! let sk = instance.secret_key(v1);
@@ -49,42 +51,185 @@
 //! let encrypted = pk.encrypt(&mut rng, v2);
 //! let score = sk.compare(encrypted);
 //! ```
-use fe::traits::FESecretKey;
-use fe::{CipherText, SecretKey};
-use fuzzy_hashes::NILSIMSA_VECTOR_SIZE_BITS;
+use fe::traits::{FEInstance, FESecretKey};
+use fe::{CipherText, Instance, SecretKey};
+use fuzzy_hashes::{FHVector, NILSIMSA_FH_SIZE_BYTES, NILSIMSA_VECTOR_SIZE_BITS};
+
+mod error;
+pub use error::ComparatorError;
 
 mod traits;
-pub use traits::Comparator;
+pub use traits::{
+    BoundedComparator, BoundedScore, Comparator, DetailedComparator, NormalizedComparator,
+};
+
+mod weighted;
+pub use weighted::{WeightedComparator, fold_weights};
+
+mod features;
+pub use features::{IntersectionComparator, jaccard};
+
+mod distribution;
+pub use distribution::score_distribution;
+
+mod projection;
+pub use projection::{NilsimsaProjection, ProjectedComparator, estimate_score};
+
+mod corpus;
+pub use corpus::EncryptedCorpus;
+
+#[cfg(feature = "verify")]
+pub mod verify;
 
 /// Type alias for a FE ciphertext that contains an encrypted nilsimsa vector.
 type NilsimsaCipherText = CipherText<NILSIMSA_VECTOR_SIZE_BITS>;
 /// Type alias for a FE secret key that can process a nilsimsa vector.
 type NilsimsaSecretKey = SecretKey<NILSIMSA_VECTOR_SIZE_BITS>;
 
+/// Maximum inner product of two Nilsimsa vectors, reached when both fuzzy hashes are
+/// identical (i.e every bit of the hash agrees).
+const NILSIMSA_MAX_INNER_PRODUCT: u16 = (NILSIMSA_VECTOR_SIZE_BITS >> 1) as u16;
+
+/// Bound given to `decrypt` to compare Nilsimsa vectors. It is exclusive, so that the inner
+/// product of identical hashes is recovered.
+pub const NILSIMSA_BOUND: u16 = NILSIMSA_MAX_INNER_PRODUCT + 1;
+
+/// Detailed result of the comparison of two Nilsimsa fuzzy hashes. All the fields
+/// are derived from the single decrypted inner product and the size of the fuzzy hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NilsimsaComparison {
+    /// Number of bits that are the same in both fuzzy hashes
+    pub agree: u16,
+    /// Number of bits that differ between both fuzzy hashes (i.e the hamming distance)
+    pub disagree: u16,
+    /// Nilsimsa similarity score (i.e 128 - disagree)
+    pub score: i16,
+}
+
 impl Comparator<NILSIMSA_VECTOR_SIZE_BITS, i16, NilsimsaCipherText> for NilsimsaSecretKey {
-    fn compare(&self, encrypted_vector: NilsimsaCipherText) -> i16 {
-        let dec = self.decrypt(encrypted_vector, NILSIMSA_VECTOR_SIZE_BITS as u16);
+    fn try_compare(&self, encrypted_vector: NilsimsaCipherText) -> Option<i16> {
+        let comparison = nilsimsa_comparison(self.compare_raw(encrypted_vector)?)?;
+        Some(comparison.score)
+    }
+
+    fn compare_raw(&self, encrypted_vector: NilsimsaCipherText) -> Option<u16> {
+        self.decrypt(encrypted_vector, NILSIMSA_BOUND)
+    }
+}
+
+impl DetailedComparator<NILSIMSA_VECTOR_SIZE_BITS, NilsimsaComparison, NilsimsaCipherText>
+    for NilsimsaSecretKey
+{
+    fn compare_detailed(&self, encrypted_vector: NilsimsaCipherText) -> NilsimsaComparison {
+        let dec = self.compare_raw(encrypted_vector);
 
-        match dec {
+        match dec.and_then(nilsimsa_comparison) {
             None => panic!("Something went wrong, unable to retrieve the hamming distance"),
-            Some(d) => {
-                128 - (((NILSIMSA_VECTOR_SIZE_BITS >> 1 )as i16) - (d as i16))
-            }
+            Some(comparison) => comparison,
         }
     }
 }
 
+impl BoundedComparator<NILSIMSA_VECTOR_SIZE_BITS, NilsimsaCipherText> for NilsimsaSecretKey {
+    /// A ciphertext that isn't decrypted below a reduced bound is reported as scoring at
+    /// least `bound - 128`, a malformed one can only be told apart with NILSIMSA_BOUND.
+    fn compare_bounded(
+        &self,
+        encrypted_vector: NilsimsaCipherText,
+        bound: u16,
+    ) -> Option<BoundedScore> {
+        let bound = bound.min(NILSIMSA_BOUND);
+        match self.decrypt(encrypted_vector, bound) {
+            Some(inner_product) => nilsimsa_score(inner_product).ok().map(BoundedScore::Exact),
+            None if bound < NILSIMSA_BOUND => Some(BoundedScore::AtLeast(bound as i16 - 128)),
+            None => None,
+        }
+    }
+}
+
+/// Nilsimsa score (from -128 to 128) of two fuzzy hashes, given the inner product of their
+/// Nilsimsa vectors. Each vector is the concatenation of the hash and its opposite, so the
+/// inner product is the number of bits that are the same in both hashes, and the score is
+/// `128 - disagree`, i.e `inner_product - 128`. An inner product above the size of a hash
+/// can't be obtained from two Nilsimsa vectors (e.g malformed ciphertext).
+pub fn nilsimsa_score(inner_product: u16) -> Result<i16, ComparatorError> {
+    if inner_product > NILSIMSA_MAX_INNER_PRODUCT {
+        return Err(ComparatorError::InnerProductOutOfRange(inner_product));
+    }
+    Ok(inner_product as i16 - 128)
+}
+
+/// Derive the comparison of two Nilsimsa fuzzy hashes from the decrypted inner product,
+/// None for an out of range inner product (see nilsimsa_score).
+fn nilsimsa_comparison(inner_product: u16) -> Option<NilsimsaComparison> {
+    let score = nilsimsa_score(inner_product).ok()?;
+
+    Some(NilsimsaComparison {
+        agree: inner_product,
+        disagree: NILSIMSA_MAX_INNER_PRODUCT - inner_product,
+        score,
+    })
+}
+
+/// Derive secret keys directly from Nilsimsa digests.
+pub trait NilsimsaInstance {
+    /// Derive the secret key of a Nilsimsa digest, i.e of the digest concatenated with its
+    /// opposite and expanded to bits (the same vector a client encrypts).
+    fn secret_key_from_nilsimsa(&self, digest: [u8; NILSIMSA_FH_SIZE_BYTES]) -> NilsimsaSecretKey;
+}
+
+impl NilsimsaInstance for Instance<NILSIMSA_VECTOR_SIZE_BITS> {
+    fn secret_key_from_nilsimsa(&self, digest: [u8; NILSIMSA_FH_SIZE_BYTES]) -> NilsimsaSecretKey {
+        let vector: [u8; NILSIMSA_VECTOR_SIZE_BITS] = FHVector::from(digest)
+            .to_fe_vector()
+            .expect("A Nilsimsa vector is NILSIMSA_VECTOR_SIZE_BITS bits long");
+        self.secret_key(vector)
+    }
+}
+
+/// Score of a Nilsimsa hash compared to an identical copy, i.e the max score (128 for
+/// any hash), as a baseline to calibrate the thresholds. It is computed in plaintext, no
+/// instance is needed : the FE comparison of identical hashes gives the same score, it
+/// isn't cut by NILSIMSA_BOUND.
+///
+/// None if the hash is not a Nilsimsa one.
+pub fn self_similarity(hash: &FHVector<u8>) -> Option<i16> {
+    let digest = hash.nilsimsa_digest()?;
+    Some(fuzzy_hashes::nilsimsa_compare(&digest, &digest))
+}
+
+/// Exact Nilsimsa score of two hashes computed in plaintext, without any encryption : the
+/// number of bits that are the same in both hashes (XOR/Hamming) minus 128. It is the ground
+/// truth of the FE comparison, e.g for tests and calibration, or when privacy isn't needed.
+///
+/// Panics if one of the hashes is not a Nilsimsa one.
+pub fn plaintext_compare(a: &FHVector<u8>, b: &FHVector<u8>) -> i16 {
+    let (Some(a), Some(b)) = (a.nilsimsa_digest(), b.nilsimsa_digest()) else {
+        panic!("Only Nilsimsa hashes can be compared");
+    };
+    fuzzy_hashes::nilsimsa_compare(&a, &b)
+}
+
+impl NormalizedComparator<NILSIMSA_VECTOR_SIZE_BITS, NilsimsaCipherText> for NilsimsaSecretKey {
+    /// The normalized similarity is the proportion of bits that are the same in both
+    /// fuzzy hashes, i.e `agree / 256` : 1.0 for identical hashes, 0.0 for hashes that
+    /// differ on every bit (and 0.5 for a Nilsimsa score of 0).
+    fn compare_normalized(&self, encrypted_vector: NilsimsaCipherText) -> f32 {
+        let comparison = self.compare_detailed(encrypted_vector);
+        comparison.agree as f32 / NILSIMSA_MAX_INNER_PRODUCT as f32
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use fe::Instance;
-    use fe::traits::{FEInstance, FEPubKey};
+    use fe::traits::FEPubKey;
+    use fuzzy_hashes::parse_digest_hex;
     use proptest::prelude::*;
-    use proptest::test_runner::{TestError, TestRunner};
+    use proptest::test_runner::{Config, TestError, TestRunner};
     use rand::SeedableRng;
     use rand::rngs::{StdRng, SysRng};
     use std::array;
-    use traits::*;
 
     // Size in bit of a nilsimsa hash
     const N: usize = 256;
@@ -99,16 +244,22 @@ mod tests {
 
     #[test]
     fn test_correctness() {
-        let mut runner = TestRunner::default();
+        // The setup of a finite field instance takes tens of seconds
+        let mut runner = if cfg!(feature = "finite-field") {
+            TestRunner::new(Config::with_cases(8))
+        } else {
+            TestRunner::default()
+        };
 
         let result = runner.run(
             &two_random_bitvec(),
             |(secret_vec, secret_client_vec): ([u8; N], [u8; N])| {
+                // 128 minus the hamming distance of both hashes
                 let expected_score = 128
                     - secret_vec
                         .iter()
                         .zip(secret_client_vec)
-                        .map(|(b1, b2)| (*b1 as i16) * (b2 as i16))
+                        .map(|(b1, b2)| (*b1 ^ b2) as i16)
                         .sum::<i16>();
 
                 // Construct ciphertexts : concat hash and not(hash) for both hashes
@@ -130,7 +281,7 @@ mod tests {
 
                 // Generate a fresh instance, pk and sk
                 let instance = Instance::setup();
-                let pk = instance.public_key::<u8>();
+                let pk = instance.public_key();
                 let sk: NilsimsaSecretKey = instance.secret_key::<u8>(secret_vec_to_compare);
 
                 // Encrypt the client vector
@@ -140,15 +291,308 @@ mod tests {
                 // Get the score
                 let score = sk.compare(ct);
 
-                assert_eq!(score, expected_score);
+                prop_assert_eq!(score, expected_score);
+                Ok(())
+            },
+        );
+
+        match result {
+            Ok(()) => (),
+            Err(TestError::Fail(_, value)) => panic!("Found failing case {:?}", value),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_compare_detailed() {
+        // Both hashes differ on the first 10 bits only
+        let h1: [u8; N] = array::from_fn(|i| (i % 3 == 0) as u8);
+        let h2: [u8; N] = array::from_fn(|i| if i < 10 { 1 - h1[i] } else { h1[i] });
+        let not_concat = |h: [u8; N]| -> [u8; NILSIMSA_VECTOR_SIZE_BITS] {
+            fuzzy_hashes::complement_concat(h, 1)
+        };
+
+        let instance = Instance::setup();
+        let pk = instance.public_key();
+        let sk: NilsimsaSecretKey = instance.secret_key::<u8>(not_concat(h1));
+
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let ct: NilsimsaCipherText = pk.encrypt(&mut rng, not_concat(h2));
+
+        let comparison = sk.compare_detailed(ct.clone());
+        assert_eq!(comparison.disagree, 10);
+        assert_eq!(comparison.agree, N as u16 - 10);
+        assert_eq!(comparison.agree + comparison.disagree, N as u16);
+        assert_eq!(comparison.score, 128 - comparison.disagree as i16);
+        assert_eq!(comparison.score, sk.compare(ct.clone()));
+
+        // The score is derived from the raw inner product
+        let raw = sk.compare_raw(ct).unwrap();
+        assert_eq!(raw, comparison.agree);
+        assert_eq!(comparison.score, raw as i16 - 128);
+    }
+
+    #[test]
+    fn test_identical_hashes() {
+        let h: [u8; N] = array::from_fn(|i| (i % 5 < 2) as u8);
+        let v: [u8; NILSIMSA_VECTOR_SIZE_BITS] = fuzzy_hashes::complement_concat(h, 1);
+
+        let instance = Instance::setup();
+        let pk = instance.public_key();
+        let sk: NilsimsaSecretKey = instance.secret_key::<u8>(v);
+
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let comparison = sk.compare_detailed(pk.encrypt(&mut rng, v));
+        assert_eq!(comparison.agree, NILSIMSA_MAX_INNER_PRODUCT);
+        assert_eq!(comparison.disagree, 0);
+        assert_eq!(comparison.score, 128);
+
+        assert_eq!(sk.compare_normalized(pk.encrypt(&mut rng, v)), 1.0);
+    }
+
+    #[test]
+    fn test_try_compare() {
+        let h: [u8; N] = array::from_fn(|i| (i % 3 == 1) as u8);
+        let v: [u8; NILSIMSA_VECTOR_SIZE_BITS] = fuzzy_hashes::complement_concat(h, 1);
+
+        let instance = Instance::setup();
+        let pk = instance.public_key();
+        let sk: NilsimsaSecretKey = instance.secret_key::<u8>(v);
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+
+        // Maximally similar hashes reach the max score, the bound doesn't cut it
+        assert_eq!(sk.try_compare(pk.encrypt(&mut rng, v)), Some(128));
+
+        // Not a Nilsimsa vector : the inner product is out of the range of the scores
+        let malformed = pk.encrypt(&mut rng, [2u8; NILSIMSA_VECTOR_SIZE_BITS]);
+        assert_eq!(sk.try_compare(malformed), None);
+    }
+
+    #[test]
+    fn test_compare_bounded() {
+        // Hashes disagreeing on 100 bits, i.e an inner product of 156
+        let h1: [u8; N] = array::from_fn(|i| (i % 3 == 1) as u8);
+        let h2: [u8; N] = array::from_fn(|i| if i < 100 { 1 - h1[i] } else { h1[i] });
+        let concat = |h: [u8; N]| -> [u8; NILSIMSA_VECTOR_SIZE_BITS] {
+            fuzzy_hashes::complement_concat(h, 1)
+        };
+
+        let instance = Instance::setup();
+        let pk = instance.public_key();
+        let sk: NilsimsaSecretKey = instance.secret_key::<u8>(concat(h1));
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let ct = pk.encrypt(&mut rng, concat(h2));
+
+        // Recovered below the bound
+        for bound in [157, NILSIMSA_BOUND, u16::MAX] {
+            assert_eq!(
+                sk.compare_bounded(ct.clone(), bound),
+                Some(BoundedScore::Exact(28))
+            );
+        }
+        // Cut by the bound, the true score is higher
+        let cut = sk.compare_bounded(ct.clone(), 156).unwrap();
+        assert_eq!(cut, BoundedScore::AtLeast(28));
+        assert_eq!(cut.score(), 28);
+        assert_eq!(
+            sk.compare_bounded(ct, 100),
+            Some(BoundedScore::AtLeast(-28))
+        );
+
+        // Not a Nilsimsa vector : only detected with the full bound
+        let malformed = pk.encrypt(&mut rng, [2u8; NILSIMSA_VECTOR_SIZE_BITS]);
+        assert_eq!(sk.compare_bounded(malformed.clone(), NILSIMSA_BOUND), None);
+        assert_eq!(
+            sk.compare_bounded(malformed, 10),
+            Some(BoundedScore::AtLeast(-118))
+        );
+    }
+
+    #[test]
+    fn test_compare_normalized() {
+        let not_concat = |h: [u8; N]| -> [u8; NILSIMSA_VECTOR_SIZE_BITS] {
+            fuzzy_hashes::complement_concat(h, 1)
+        };
+        let h: [u8; N] = array::from_fn(|i| (i % 5 == 0) as u8);
+        let opposite: [u8; N] = array::from_fn(|i| 1 - h[i]);
+        let half: [u8; N] = array::from_fn(|i| if i < N / 2 { h[i] } else { 1 - h[i] });
+
+        let instance = Instance::setup();
+        let pk = instance.public_key();
+        let sk: NilsimsaSecretKey = instance.secret_key::<u8>(not_concat(h));
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+
+        assert_eq!(
+            sk.compare_normalized(pk.encrypt(&mut rng, not_concat(h))),
+            1.0
+        );
+        assert_eq!(
+            sk.compare_normalized(pk.encrypt(&mut rng, not_concat(opposite))),
+            0.0
+        );
+        assert_eq!(
+            sk.compare_normalized(pk.encrypt(&mut rng, not_concat(half))),
+            0.5
+        );
+    }
+
+    #[derive(serde::Deserialize)]
+    struct TestVectors {
+        vectors: Vec<TestVector>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct TestVector {
+        a: String,
+        b: String,
+        score: i16,
+    }
+
+    #[test]
+    fn test_regression_vectors() {
+        let test_vectors: TestVectors =
+            serde_json::from_str(include_str!("../test_vectors/nilsimsa.json")).unwrap();
+
+        let instance = Instance::setup();
+        let pk = instance.public_key();
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+
+        for vector in test_vectors.vectors {
+            let (a, b) = (
+                parse_digest_hex(&vector.a).unwrap(),
+                parse_digest_hex(&vector.b).unwrap(),
+            );
+            assert_eq!(fuzzy_hashes::nilsimsa_compare(&a, &b), vector.score);
+
+            // Same expansion as the client and the servers
+            let sk: NilsimsaSecretKey =
+                instance.secret_key(FHVector::from(a).to_fe_vector().unwrap());
+            let ct = pk.encrypt(&mut rng, FHVector::from(b).to_fe_vector().unwrap());
+            assert_eq!(sk.compare(ct), vector.score, "{} / {}", vector.a, vector.b);
+        }
+    }
+
+    #[test]
+    fn test_secret_key_from_nilsimsa() {
+        let digest: [u8; NILSIMSA_FH_SIZE_BYTES] = array::from_fn(|i| (i * 37 + 11) as u8);
+
+        // Hash concatenated with its opposite, most significant bit first
+        let bits: [u8; N] = array::from_fn(|i| 1 & (digest[i / 8] >> (7 - i % 8)));
+        let vector: [u8; NILSIMSA_VECTOR_SIZE_BITS] =
+            array::from_fn(|i| if i < N { bits[i] } else { 1 - bits[i % N] });
+
+        let instance = Instance::setup();
+        let sk = instance.secret_key_from_nilsimsa(digest);
+        let expected: NilsimsaSecretKey = instance.secret_key(vector);
+        assert_eq!(sk.to_compact_bytes(), expected.to_compact_bytes());
+
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let ct = instance.public_key().encrypt(&mut rng, vector);
+        assert_eq!(sk.compare(ct), 128);
+    }
+
+    #[test]
+    fn test_self_similarity() {
+        let mut runner = TestRunner::new(Config::with_cases(16));
+
+        let result = runner.run(
+            &(
+                prop::array::uniform32(any::<u8>()),
+                prop::array::uniform32(any::<u8>()),
+            ),
+            |(digest, other)| {
+                let score = self_similarity(&FHVector::from(digest)).unwrap();
+                prop_assert_eq!(score, 128);
+                prop_assert!(score >= fuzzy_hashes::nilsimsa_compare(&digest, &other));
+                Ok(())
+            },
+        );
+
+        match result {
+            Ok(()) => (),
+            Err(TestError::Fail(_, value)) => panic!("Found failing case {:?}", value),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        let features = FHVector::from_features([1, 5, 7]).unwrap();
+        assert_eq!(self_similarity(&features), None);
+    }
+
+    #[test]
+    fn test_plaintext_compare() {
+        let mut runner = if cfg!(feature = "finite-field") {
+            TestRunner::new(Config::with_cases(2))
+        } else {
+            TestRunner::new(Config::with_cases(16))
+        };
+        let instance = Instance::setup();
+
+        let result = runner.run(
+            &(
+                prop::array::uniform32(any::<u8>()),
+                prop::array::uniform32(any::<u8>()),
+            ),
+            |(digest, other)| {
+                let (a, b) = (FHVector::from(digest), FHVector::from(other));
+                let sk = instance.secret_key_from_nilsimsa(digest);
+                prop_assert_eq!(plaintext_compare(&a, &a), 128);
+                for hash in [a, b] {
+                    let ct = instance
+                        .public_key()
+                        .encrypt_with_seed(other, hash.to_fe_vector().unwrap());
+                    prop_assert_eq!(plaintext_compare(&a, &hash), sk.compare(ct));
+                }
+                prop_assert_eq!(
+                    plaintext_compare(&a, &b),
+                    fuzzy_hashes::nilsimsa_compare(&digest, &other)
+                );
                 Ok(())
             },
         );
 
         match result {
             Ok(()) => (),
-            Err(TestError::Fail(_, value)) => println!("Found failing case {:?}", value),
+            Err(TestError::Fail(_, value)) => panic!("Found failing case {:?}", value),
             result => panic!("Unexpected result {:?}", result),
         }
     }
+
+    #[test]
+    fn test_nilsimsa_score() {
+        assert_eq!(nilsimsa_score(0), Ok(-128));
+        assert_eq!(nilsimsa_score(1), Ok(-127));
+        assert_eq!(nilsimsa_score(128), Ok(0));
+        assert_eq!(nilsimsa_score(255), Ok(127));
+        assert_eq!(nilsimsa_score(256), Ok(128));
+        assert_eq!(
+            nilsimsa_score(257),
+            Err(ComparatorError::InnerProductOutOfRange(257))
+        );
+        assert_eq!(
+            nilsimsa_score(u16::MAX),
+            Err(ComparatorError::InnerProductOutOfRange(u16::MAX))
+        );
+
+        // Same score as the plaintext comparison of the digests
+        let a = [0b1010_0110u8; NILSIMSA_FH_SIZE_BYTES];
+        let b = [0b0110_0101u8; NILSIMSA_FH_SIZE_BYTES];
+        let disagree: u32 = a.iter().zip(&b).map(|(x, y)| (x ^ y).count_ones()).sum();
+        assert_eq!(
+            nilsimsa_score(256 - disagree as u16),
+            Ok(fuzzy_hashes::nilsimsa_compare(&a, &b))
+        );
+    }
+
+    #[test]
+    fn test_out_of_range_inner_product() {
+        let comparison = nilsimsa_comparison(N as u16).unwrap();
+        assert_eq!(comparison.score, 128);
+        let comparison = nilsimsa_comparison(0).unwrap();
+        assert_eq!(comparison.score, -128);
+
+        // Inner products that can't be obtained from two Nilsimsa vectors
+        assert_eq!(nilsimsa_comparison(N as u16 + 1), None);
+        assert_eq!(nilsimsa_comparison(NILSIMSA_VECTOR_SIZE_BITS as u16), None);
+        assert_eq!(nilsimsa_comparison(u16::MAX), None);
+    }
 }
@@ -1,4 +1,60 @@
 /// Trait to compute a similarity score from a FE secret key and a FE ciphertext.
 pub trait Comparator<const N: usize, T, E> {
-    fn compare(&self, encrypted_vector: E) -> T;
+    /// Compute the similarity score between the vector of the secret key and the encrypted vector,
+    /// None if it can't be recovered (i.e the ciphertext doesn't encrypt a vector that can be
+    /// compared, e.g a malformed one sent by a client).
+    fn try_compare(&self, encrypted_vector: E) -> Option<T>;
+
+    /// Same as try_compare, for ciphertexts known to be valid (panics otherwise).
+    fn compare(&self, encrypted_vector: E) -> T {
+        match self.try_compare(encrypted_vector) {
+            None => panic!("Something went wrong, unable to recover the similarity score"),
+            Some(score) => score,
+        }
+    }
+
+    /// Return the inner product recovered by the decryption, before it is turned into a
+    /// similarity score (e.g for debugging). None if it can't be recovered.
+    fn compare_raw(&self, encrypted_vector: E) -> Option<u16>;
+}
+
+/// Trait to compute a detailed comparison (i.e not only a similarity score) from a FE
+/// secret key and a FE ciphertext.
+pub trait DetailedComparator<const N: usize, T, E> {
+    /// Compute the detailed comparison between the vector of the secret key and the encrypted vector.
+    fn compare_detailed(&self, encrypted_vector: E) -> T;
+}
+
+/// Trait to compute a similarity normalized in [0.0, 1.0] from a FE secret key and a FE ciphertext.
+pub trait NormalizedComparator<const N: usize, E> {
+    /// Compute the normalized similarity between the vector of the secret key and the encrypted vector.
+    fn compare_normalized(&self, encrypted_vector: E) -> f32;
+}
+
+/// Score recovered by decrypting with a given bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoundedScore {
+    /// The inner product was below the bound, the score is exact
+    Exact(i16),
+    /// The inner product reached the bound, the true score is at least this one
+    AtLeast(i16),
+}
+
+impl BoundedScore {
+    /// Reported score, exact or not.
+    pub fn score(&self) -> i16 {
+        match self {
+            BoundedScore::Exact(score) | BoundedScore::AtLeast(score) => *score,
+        }
+    }
+}
+
+/// Trait to compute a similarity score with a bound on the decrypted inner product (e.g a
+/// low one to quickly tell apart dissimilar vectors), telling whether the score was
+/// cut by the bound.
+pub trait BoundedComparator<const N: usize, E> {
+    /// Compute the similarity score, decrypting with the given bound (exclusive). Bounds
+    /// above the largest possible inner product are lowered to it. None if the score can't
+    /// be recovered even with the largest bound (e.g a malformed ciphertext).
+    fn compare_bounded(&self, encrypted_vector: E, bound: u16) -> Option<BoundedScore>;
 }
@@ -0,0 +1,130 @@
+//! Debugging aid to check the comparisons done over the encrypted domain against the
+//! plaintext vectors. This obviously needs both plaintext vectors, so it must only be used
+//! by an operator on a sample of vectors it already knows.
+use fuzzy_hashes::NILSIMSA_VECTOR_SIZE_BITS;
+use log::warn;
+
+use crate::{Comparator, NilsimsaCipherText, NilsimsaSecretKey, nilsimsa_comparison};
+
+/// Mismatch between the score computed over the encrypted domain and the true score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Discrepancy {
+    /// Score computed from the plaintext vectors
+    pub expected: i16,
+    /// Score computed over the encrypted domain, None if it couldn't be recovered
+    pub computed: Option<i16>,
+}
+
+/// Nilsimsa score of two Nilsimsa vectors (i.e hash concatenated with its opposite),
+/// computed in plaintext.
+pub fn plaintext_nilsimsa_score(
+    a: &[u8; NILSIMSA_VECTOR_SIZE_BITS],
+    b: &[u8; NILSIMSA_VECTOR_SIZE_BITS],
+) -> Option<i16> {
+    let inner_product: u16 = a
+        .iter()
+        .zip(b)
+        .map(|(x, y)| (*x as u16) * (*y as u16))
+        .sum();
+    nilsimsa_comparison(inner_product).map(|comparison| comparison.score)
+}
+
+/// Check a score computed over the encrypted domain against the plaintext vectors,
+/// a discrepancy is logged and returned.
+pub fn verify_nilsimsa_score(
+    a: &[u8; NILSIMSA_VECTOR_SIZE_BITS],
+    b: &[u8; NILSIMSA_VECTOR_SIZE_BITS],
+    computed: i16,
+) -> Result<(), Discrepancy> {
+    // Vectors that are not Nilsimsa vectors can't match any score
+    let expected = plaintext_nilsimsa_score(a, b).unwrap_or(i16::MIN);
+    if expected == computed {
+        return Ok(());
+    }
+
+    warn!(
+        "Comparison mismatch : computed score {}, expected {}",
+        computed, expected
+    );
+    Err(Discrepancy {
+        expected,
+        computed: Some(computed),
+    })
+}
+
+/// Compare the ciphertext of `b` with the secret key of `a`, and check the result
+/// against the plaintext vectors. A score that can't be recovered from the ciphertext is
+/// reported as a discrepancy.
+pub fn compare_verified(
+    sk: &NilsimsaSecretKey,
+    ct: NilsimsaCipherText,
+    a: &[u8; NILSIMSA_VECTOR_SIZE_BITS],
+    b: &[u8; NILSIMSA_VECTOR_SIZE_BITS],
+) -> Result<i16, Discrepancy> {
+    match sk.try_compare(ct) {
+        Some(computed) => verify_nilsimsa_score(a, b, computed).map(|_| computed),
+        None => {
+            let expected = plaintext_nilsimsa_score(a, b).unwrap_or(i16::MIN);
+            warn!(
+                "Comparison mismatch : unable to recover the score, expected {}",
+                expected
+            );
+            Err(Discrepancy {
+                expected,
+                computed: None,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fe::Instance;
+    use fe::traits::{FEInstance, FEPubKey};
+    use rand::SeedableRng;
+    use rand::rngs::{StdRng, SysRng};
+    use std::array;
+
+    const N: usize = NILSIMSA_VECTOR_SIZE_BITS / 2;
+
+    fn not_concat(h: [u8; N]) -> [u8; NILSIMSA_VECTOR_SIZE_BITS] {
+        fuzzy_hashes::complement_concat(h, 1)
+    }
+
+    #[test]
+    fn test_verify() {
+        // Both hashes differ on the first 20 bits only
+        let h1: [u8; N] = array::from_fn(|i| (i % 7 < 3) as u8);
+        let h2: [u8; N] = array::from_fn(|i| if i < 20 { 1 - h1[i] } else { h1[i] });
+        let (a, b) = (not_concat(h1), not_concat(h2));
+        assert_eq!(plaintext_nilsimsa_score(&a, &b), Some(128 - 20));
+
+        let instance = Instance::setup();
+        let sk: NilsimsaSecretKey = instance.secret_key(a);
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let ct = instance.public_key().encrypt(&mut rng, b);
+        assert_eq!(compare_verified(&sk, ct, &a, &b), Ok(128 - 20));
+
+        // Mismatched result
+        assert_eq!(
+            verify_nilsimsa_score(&a, &b, 128 - 21),
+            Err(Discrepancy {
+                expected: 128 - 20,
+                computed: Some(128 - 21)
+            })
+        );
+
+        // A ciphertext of a vector that isn't a Nilsimsa vector
+        let c = [2u8; NILSIMSA_VECTOR_SIZE_BITS];
+        assert_eq!(plaintext_nilsimsa_score(&a, &c), None);
+        let malformed = instance.public_key().encrypt(&mut rng, c);
+        assert_eq!(
+            compare_verified(&sk, malformed, &a, &c),
+            Err(Discrepancy {
+                expected: i16::MIN,
+                computed: None
+            })
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use core::array;
+use fe::traits::{FEInstance, FESecretKey};
+use fe::{CipherText, Instance, SecretKey};
+
+use crate::ComparatorError;
+use crate::traits::Comparator;
+
+/// Comparator for weighted vectors. The secret key is derived from a vector of small
+/// integer weights (instead of bits), so that decrypting a ciphertext of a bit vector
+/// gives the sum of the weights of the positions set in the encrypted vector.
+#[derive(Debug, Clone)]
+pub struct WeightedComparator<const N: usize> {
+    sk: SecretKey<N>,
+    bound: u16,
+}
+
+impl<const N: usize> WeightedComparator<N> {
+    /// Derive the secret key associated to the given weights from the instance.
+    /// Fail if the maximum weighted inner product can't be recovered
+    /// (i.e the sum of the weights does not fit in an u16).
+    pub fn new(instance: &Instance<N>, weights: [u8; N]) -> Result<Self, ComparatorError> {
+        // The encrypted vector is a bit vector, so the inner product is at most the sum
+        // of the weights.
+        Self::with_client_weights(instance, weights, 1)
+    }
+
+    /// Same as `new`, but the client weights its bit vector as well (see `fold_weights`),
+    /// with weights of at most `max_client_weight`. As the inner product is symmetric, the
+    /// decrypted value is `sum(weight_i * client_weight_i * bit_i)`, so the bound is derived
+    /// from the largest possible products. Fail if it does not fit in an u16.
+    pub fn with_client_weights(
+        instance: &Instance<N>,
+        weights: [u8; N],
+        max_client_weight: u8,
+    ) -> Result<Self, ComparatorError> {
+        // The bound is exclusive, hence the + 1.
+        let bound = weights
+            .iter()
+            .try_fold(1u16, |acc, w| {
+                acc.checked_add((*w as u16).checked_mul(max_client_weight as u16)?)
+            })
+            .ok_or(ComparatorError::BoundOverflow)?;
+
+        Ok(Self {
+            sk: instance.secret_key(weights),
+            bound,
+        })
+    }
+
+    /// Bound used to decrypt the weighted inner product.
+    pub fn bound(&self) -> u16 {
+        self.bound
+    }
+}
+
+/// Fold the weights of the client into its bit vector, i.e the weight of each position set in
+/// the vector (and 0 elsewhere). The result is then encrypted in place of the bit vector.
+pub fn fold_weights<const N: usize>(vector: [u8; N], weights: [u8; N]) -> [u8; N] {
+    array::from_fn(|i| if vector[i] != 0 { weights[i] } else { 0 })
+}
+
+impl<const N: usize> Comparator<N, u16, CipherText<N>> for WeightedComparator<N> {
+    fn try_compare(&self, encrypted_vector: CipherText<N>) -> Option<u16> {
+        self.compare_raw(encrypted_vector)
+    }
+
+    fn compare_raw(&self, encrypted_vector: CipherText<N>) -> Option<u16> {
+        self.sk.decrypt(encrypted_vector, self.bound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fe::traits::FEPubKey;
+    use rand::SeedableRng;
+    use rand::rngs::{StdRng, SysRng};
+
+    #[test]
+    fn test_weighted_inner_product() {
+        let weights: [u8; 8] = [3, 1, 0, 2, 5, 1, 1, 4];
+        let client_vec: [u8; 8] = [1, 0, 1, 1, 1, 0, 0, 1];
+
+        let instance = Instance::<8>::setup();
+        let pk = instance.public_key();
+        let comparator = WeightedComparator::new(&instance, weights).unwrap();
+        assert_eq!(comparator.bound(), 18);
+
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let ct = pk.encrypt(&mut rng, client_vec);
+
+        assert_eq!(comparator.compare(ct), 3 + 2 + 5 + 4);
+
+        // Every position set gives the maximum weighted inner product
+        let ct = pk.encrypt(&mut rng, [1u8; 8]);
+        assert_eq!(comparator.compare(ct), 17);
+    }
+
+    #[test]
+    fn test_symmetric_weights() {
+        let weights: [u8; 8] = [3, 1, 0, 2, 5, 1, 1, 4];
+        let client_vec: [u8; 8] = [1, 0, 1, 1, 1, 0, 0, 1];
+        let client_weights: [u8; 8] = [2, 7, 7, 1, 3, 0, 2, 7];
+
+        let instance = Instance::<8>::setup();
+        let pk = instance.public_key();
+        let comparator = WeightedComparator::with_client_weights(&instance, weights, 7).unwrap();
+        assert_eq!(comparator.bound(), 17 * 7 + 1);
+
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let folded = fold_weights(client_vec, client_weights);
+        assert_eq!(folded, [2, 0, 7, 1, 3, 0, 0, 7]);
+
+        // Plaintext weighted dot product
+        let expected: u16 = (0..8)
+            .map(|i| weights[i] as u16 * client_vec[i] as u16 * client_weights[i] as u16)
+            .sum();
+        assert_eq!(comparator.compare(pk.encrypt(&mut rng, folded)), expected);
+
+        // The largest client weights everywhere give the largest product
+        let ct = pk.encrypt(&mut rng, [7u8; 8]);
+        assert_eq!(comparator.compare(ct), comparator.bound() - 1);
+    }
+
+    #[test]
+    fn test_weights_overflow() {
+        let instance = Instance::<512>::setup();
+        assert_eq!(
+            WeightedComparator::new(&instance, [255u8; 512]).unwrap_err(),
+            ComparatorError::BoundOverflow
+        );
+        assert_eq!(
+            WeightedComparator::with_client_weights(&instance, [1u8; 512], 128).unwrap_err(),
+            ComparatorError::BoundOverflow
+        );
+        assert_eq!(
+            ComparatorError::BoundOverflow.to_string(),
+            "the largest weighted inner product can't be decrypted (it exceeds 65534)"
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::Result;
+use fe::SecretKey;
+use sha3::{Digest, Sha3_256};
+use std::collections::{BTreeMap, HashMap};
+
+/// Key of a cached comparison : the SHA3-256 digest of the (serialized ciphertext,
+/// serialized secret key) pair. The ciphertext comes from the client, so the digest must be
+/// collision resistant, otherwise a client could poison the scores of other queries.
+pub type CacheKey = [u8; 32];
+
+/// LRU cache of the scores recovered by the comparisons, keyed by a digest of the
+/// (serialized ciphertext, serialized secret key) pair. This avoids to run the expensive
+/// discrete logarithm recovery again when the same ciphertext is compared twice against
+/// the same secret key (e.g a retried batch).
+#[derive(Debug)]
+pub struct DecryptionCache {
+    capacity: usize,
+    // Map a key to its score and the "time" of its last access
+    entries: HashMap<CacheKey, (i16, u64)>,
+    // Keys by time of last access, to find the least recently used one without a scan
+    accesses: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+impl DecryptionCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::with_capacity(capacity),
+            accesses: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Compute the key associated to a comparison, given the serialized ciphertext.
+    pub fn key<const N: usize>(ct_bytes: &[u8], sk: &SecretKey<N>) -> Result<CacheKey> {
+        let mut hasher = Sha3_256::new();
+        hasher.update(ct_bytes);
+        hasher.update(postcard::to_stdvec(sk)?);
+        Ok(hasher.finalize().into())
+    }
+
+    /// Return the cached score if any, and mark it as the most recently used entry.
+    pub fn get(&mut self, key: &CacheKey) -> Option<i16> {
+        let (score, last_access) = self.entries.get_mut(key)?;
+        self.tick += 1;
+        self.accesses.remove(last_access);
+        self.accesses.insert(self.tick, *key);
+        *last_access = self.tick;
+        Some(*score)
+    }
+
+    /// Cache a score, evicting the least recently used entry if the cache is full.
+    pub fn insert(&mut self, key: CacheKey, score: i16) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        match self.entries.get(&key) {
+            Some((_, last_access)) => {
+                self.accesses.remove(last_access);
+            }
+            None if self.entries.len() >= self.capacity => {
+                if let Some((_, lru)) = self.accesses.pop_first() {
+                    self.entries.remove(&lru);
+                }
+            }
+            None => (),
+        }
+
+        self.tick += 1;
+        self.accesses.insert(self.tick, key);
+        self.entries.insert(key, (score, self.tick));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache = DecryptionCache::new(2);
+        cache.insert([1; 32], 10);
+        cache.insert([2; 32], 20);
+
+        // 1 is now the most recently used, so 2 gets evicted
+        assert_eq!(cache.get(&[1; 32]), Some(10));
+        cache.insert([3; 32], 30);
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&[2; 32]), None);
+        assert_eq!(cache.get(&[1; 32]), Some(10));
+        assert_eq!(cache.get(&[3; 32]), Some(30));
+
+        // Updating an entry makes it the most recently used one
+        cache.insert([1; 32], 11);
+        cache.insert([4; 32], 40);
+        assert_eq!(cache.get(&[3; 32]), None);
+        assert_eq!(cache.get(&[1; 32]), Some(11));
+    }
+}
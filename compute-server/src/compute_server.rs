@@ -1,76 +1,437 @@
 use anyhow::{Error, Result, anyhow};
-use fe::{PublicKey, SecretKey};
-use log::{debug, error, info};
+use fe::traits::FECipherText;
+use fe::{CipherText, SecretKey};
+use log::{debug, error, info, warn};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
 
 use futures::SinkExt;
-use futures::StreamExt;
+use futures::{Stream, StreamExt};
 use fuzzy_hashes::{FHVector, NILSIMSA_VECTOR_SIZE_BITS};
 use messages::{
-    EncryptionRequest, EncryptionResponse, GenerateInstanceResponse, HashComparisonRequest,
+    ClientRequest, EncryptionRequest, EncryptionResponse, HashComparisonRequest, HashParameters,
+    SessionNonce, SupportedHashesResponse, codec, decode_versioned, key_fingerprint, received,
 };
 use rusqlite::Connection;
 use rusqlite::named_params;
-use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
 use comparator::Comparator;
 
+use crate::cache::DecryptionCache;
+use crate::corpus::{Corpus, Hashes};
+use crate::keys::{KeyBatches, PrecomputedKeys};
+use crate::metrics::{Metrics, PhaseTimer};
+use crate::prefilter::PreFilter;
+
+/// What to do with a malformed fuzzy hash in the database (e.g a blob of the wrong length).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MalformedRows {
+    /// Log and ignore the malformed fuzzy hash, the rest of the corpus is still compared
+    Skip,
+    /// Refuse to compare the corpus
+    Fail,
+}
+
+/// Maximum time waited for the authority and for the clients. Generating the keys of a batch
+/// takes a while, whereas a client only has to encrypt its fuzzy hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Time for the authority to answer a request of secret keys
+    pub authority: Duration,
+    /// Time for a client to send each of its frames
+    pub client: Duration,
+    /// Wall-clock budget of a whole comparison, once exceeded the client gets the best score
+    /// of the batches compared so far (the first batch is always compared). None for no limit.
+    pub session: Option<Duration>,
+}
+
+/// Cap on the number of corpus entries compared in a session, so that the work of a session
+/// doesn't grow with the database. The score is then approximate : it is the best one among
+/// the compared entries, a match with any other entry is missed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorpusCap {
+    /// Maximum number of corpus entries compared in a session
+    pub max: usize,
+    /// Compare a random sample of the corpus, drawn for each session, instead of its
+    /// first entries
+    pub sampled: bool,
+}
+
+impl CorpusCap {
+    /// Entries of the corpus compared in a session.
+    fn apply(&self, hashes: &Hashes) -> Hashes {
+        if hashes.len() <= self.max {
+            return hashes.clone();
+        }
+        if !self.sampled {
+            return hashes[..self.max].into();
+        }
+
+        // Kept in the order of the corpus
+        let mut indices =
+            rand::seq::index::sample(&mut rand::rng(), hashes.len(), self.max).into_vec();
+        indices.sort_unstable();
+        indices.into_iter().map(|i| hashes[i]).collect()
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            authority: Duration::from_secs(60),
+            client: Duration::from_secs(30),
+            session: None,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Server {
     listener: TcpListener,
-    db_connection: Connection,
+    corpus: Corpus,
     authority_addr: String,
+    metrics: Arc<Metrics>,
+    cache: Option<Arc<Mutex<DecryptionCache>>>,
+    timeouts: Timeouts,
+    batch_size: usize,
+    precomputed: Option<Arc<PrecomputedKeys<NILSIMSA_VECTOR_SIZE_BITS>>>,
+    bind_sessions: bool,
+    stream_keys: bool,
+    corpus_cap: Option<CorpusCap>,
+}
+
+/// Everything needed to build the batches of keys of the corpus, moved to the task handling
+/// the client so that the corpus is not read by the accept loop.
+struct CorpusSource {
+    corpus: Corpus,
+    authority_addr: String,
+    authority_timeout: Duration,
+    batch_size: usize,
+    precomputed: Option<Arc<PrecomputedKeys<NILSIMSA_VECTOR_SIZE_BITS>>>,
+    stream_keys: bool,
+    corpus_cap: Option<CorpusCap>,
+}
+
+impl CorpusSource {
+    /// Batches of keys of the corpus (or of the fuzzy hashes with the given tag), the keys are
+    /// retrieved from the authority while handling the client unless they were precomputed.
+    async fn batches(
+        self,
+        hash_type: HashComparisonRequest,
+        tag: Option<String>,
+    ) -> Result<KeyBatches<NILSIMSA_VECTOR_SIZE_BITS>> {
+        info!("Loading {:?} fuzzy hashes (tag : {:?})", hash_type, tag);
+
+        if let (Some(keys), None) = (self.precomputed, &tag) {
+            info!("Using the precomputed keys of the corpus");
+            return Ok(KeyBatches::from_precomputed(keys));
+        }
+
+        let timer = PhaseTimer::start("corpus read");
+        let hashes = match hash_type {
+            HashComparisonRequest::NILSIMSA => self.corpus.load(tag).await?,
+        };
+        timer.finish();
+        if hashes.is_empty() {
+            // The client is sent the end of the db (with no score) right away
+            warn!("No fuzzy hash to compare to");
+        }
+        let hashes = match self.corpus_cap {
+            Some(cap) if hashes.len() > cap.max => {
+                info!(
+                    "Comparing to {} of the {} fuzzy hashes of the corpus",
+                    cap.max,
+                    hashes.len()
+                );
+                cap.apply(&hashes)
+            }
+            _ => hashes,
+        };
+
+        let batches = KeyBatches::new(self.authority_addr, hashes, self.authority_timeout)
+            .with_batch_size(self.batch_size)?;
+        Ok(if self.stream_keys {
+            batches.with_streamed_keys()
+        } else {
+            batches
+        })
+    }
+
+    /// Batches of keys of the fuzzy hashes sent by the client instead of the corpus, the
+    /// database (and the pre-filter) is not used.
+    fn inline(
+        self,
+        hash_type: HashComparisonRequest,
+        hashes: Vec<FHVector<u8>>,
+    ) -> Result<KeyBatches<NILSIMSA_VECTOR_SIZE_BITS>> {
+        let max = KeyBatches::<NILSIMSA_VECTOR_SIZE_BITS>::MAX_BATCH_SIZE;
+        if hashes.len() > max {
+            return Err(anyhow!(
+                "{} fuzzy hashes were sent, at most {} can be compared at once",
+                hashes.len(),
+                max
+            ));
+        }
+        let same_type = hashes.iter().all(|hash| match hash_type {
+            HashComparisonRequest::NILSIMSA => matches!(hash, FHVector::NilsimsaVector(_)),
+        });
+        if !same_type {
+            return Err(anyhow!("Expected only {:?} fuzzy hashes", hash_type));
+        }
+        info!("Comparing to {} inline fuzzy hashes", hashes.len());
+
+        let batches = KeyBatches::new(self.authority_addr, hashes.into(), self.authority_timeout)
+            .with_batch_size(self.batch_size)?;
+        Ok(if self.stream_keys {
+            batches.with_streamed_keys()
+        } else {
+            batches
+        })
+    }
+}
+
+/// Everything needed to serve a connection, moved to the task handling it.
+struct ConnectionContext {
+    source: CorpusSource,
+    metrics: Arc<Metrics>,
+    cache: Option<Arc<Mutex<DecryptionCache>>>,
+    client_timeout: Duration,
+    session_budget: Option<Duration>,
+    bind_sessions: bool,
+}
+
+impl ConnectionContext {
+    /// Read the request of the client and answer it.
+    async fn serve(self, stream: impl AsyncRead + AsyncWrite + Unpin) -> Result<()> {
+        info!("Loading client request");
+        // The same framed stream is handed to the client handler, so that the frames
+        // already buffered after the request are not lost
+        let mut framed = Framed::new(stream, codec());
+        let mut frame = read_client_frame(&mut framed, self.client_timeout)
+            .await
+            .map_err(|error| anyhow!("Unable to read client request : {}", error))?;
+
+        let (requested_hash_type, batches) = loop {
+            match decode_versioned(&frame) {
+                Ok(ClientRequest::Compare(hash_type, tag)) => {
+                    let batches = self
+                        .source
+                        .batches(hash_type, tag)
+                        .await
+                        .map_err(|error| anyhow!("Unable to load fuzzy hashes : {}", error))?;
+                    break (hash_type, batches);
+                }
+                Ok(ClientRequest::CompareInline(hash_type, hashes)) => {
+                    let batches = self
+                        .source
+                        .inline(hash_type, hashes)
+                        .map_err(|error| anyhow!("Invalid inline fuzzy hashes : {}", error))?;
+                    break (hash_type, batches);
+                }
+                Ok(ClientRequest::ListSupportedHashes) => {
+                    info!("Sending supported fuzzy hashes to client");
+                    let response = SupportedHashesResponse(SUPPORTED_HASHES.to_vec());
+                    return reply_and_close(&mut framed, postcard::to_stdvec(&response)?).await;
+                }
+                Ok(ClientRequest::DescribeHash(hash_type)) => {
+                    info!("Sending the parameters of {:?} to client", hash_type);
+                    let response = hash_parameters(hash_type);
+                    framed.send(postcard::to_stdvec(&response)?.into()).await?;
+                    // The client then sends its comparison request on the same connection, or
+                    // closes it if it only wanted the parameters
+                    frame = match timeout(self.client_timeout, framed.next()).await {
+                        Ok(None) => return Ok(()),
+                        Ok(frame) => received(frame)?,
+                        Err(_) => {
+                            return Err(anyhow!("The client did not send its request in time"));
+                        }
+                    };
+                }
+                Err(error) => {
+                    return Err(anyhow!("Failed to understand client request : {}", error));
+                }
+            }
+        };
+
+        let mut client_handler = ClientHandler {
+            stream: framed,
+            hash_type: requested_hash_type,
+            batches,
+            metrics: self.metrics,
+            cache: self.cache,
+            client_timeout: self.client_timeout,
+            session_budget: self.session_budget,
+            session: self.bind_sessions.then(rand::random::<SessionNonce>),
+        };
+        client_handler.handle_client().await
+    }
+}
+
+/// The precomputed keys cover the whole corpus, so they can't be used with a corpus cap.
+const CAP_WITH_PRECOMPUTED_KEYS: &str = "The corpus can't be capped with precomputed keys";
+
+/// Fuzzy hashes that can be compared by the server.
+const SUPPORTED_HASHES: [HashComparisonRequest; 1] = [HashComparisonRequest::NILSIMSA];
+
+/// Vectors the clients have to encrypt to compare the given fuzzy hash.
+fn hash_parameters(hash_type: HashComparisonRequest) -> HashParameters {
+    match hash_type {
+        HashComparisonRequest::NILSIMSA => HashParameters {
+            dimension: NILSIMSA_VECTOR_SIZE_BITS as u32,
+            bound: comparator::NILSIMSA_BOUND,
+        },
+    }
+}
+
+/// Send the single frame replying to a request, then close the connection.
+async fn reply_and_close(
+    framed: &mut Framed<impl AsyncRead + AsyncWrite + Unpin, LengthDelimitedCodec>,
+    frame: Vec<u8>,
+) -> Result<()> {
+    framed.send(frame.into()).await?;
+    framed.close().await?;
+    Ok(())
 }
 
 const FH_SQL_QUERY: &str = "SELECT fh FROM fuzzy_hashes WHERE type == :hash_type";
+// Databases created before tags were introduced have no `tag` column, it is
+// only queried when the client asks for a tag.
+const FH_TAG_SQL_QUERY: &str =
+    "SELECT fh FROM fuzzy_hashes WHERE type == :hash_type AND tag == :tag";
 
 impl Server {
-    pub fn new(listener: TcpListener, db_connection: Connection, authority_addr: String) -> Self {
+    pub fn new(
+        listener: TcpListener,
+        db_connection: Connection,
+        authority_addr: String,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         Self {
             listener,
-            db_connection,
+            corpus: Corpus::new(db_connection),
             authority_addr,
+            metrics,
+            cache: None,
+            timeouts: Timeouts::default(),
+            batch_size: KeyBatches::<NILSIMSA_VECTOR_SIZE_BITS>::MAX_BATCH_SIZE,
+            precomputed: None,
+            bind_sessions: false,
+            stream_keys: false,
+            corpus_cap: None,
         }
     }
 
-    fn get_nilsimsa_hashes(&self) -> Result<Vec<FHVector<u8>>> {
-        let mut nilsimsa_statement = self.db_connection.prepare(FH_SQL_QUERY)?;
+    /// Only compare the clients to the fuzzy hashes of the database that pass the pre-filter
+    /// (see PreFilter).
+    pub fn with_prefilter(mut self, prefilter: PreFilter) -> Self {
+        self.corpus = self.corpus.with_prefilter(prefilter);
+        self
+    }
 
-        let vectors = nilsimsa_statement
-            .query_map(named_params! {":hash_type": "nilsimsa"}, |row| {
-                let r: [u8; 32] = row.get("fh").expect("Malformed database");
-                Ok(FHVector::from(r))
-            })?
-            .map(|vector| vector.expect("Malformed fuzzy hash in database"))
-            .collect();
+    /// Share a cache of the decrypted scores between the clients (see DecryptionCache).
+    pub fn with_cache(mut self, cache: Arc<Mutex<DecryptionCache>>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// What to do with the malformed fuzzy hashes of the database, they fail the comparison
+    /// by default.
+    pub fn with_malformed_rows(mut self, malformed_rows: MalformedRows) -> Self {
+        self.corpus = self.corpus.with_malformed_rows(malformed_rows);
+        self
+    }
 
-        Ok(vectors)
+    /// Require the clients to bind their ciphertexts to a random nonce of the session, so
+    /// that a ciphertext recorded from a session can't be replayed in another one (e.g to
+    /// learn the score of someone else's fuzzy hash when the keys are precomputed).
+    pub fn with_session_binding(mut self) -> Self {
+        self.bind_sessions = true;
+        self
     }
 
-    async fn retrieve_secret_keys<const N: usize>(
-        &self,
-        vectors: &[FHVector<u8>],
-    ) -> Result<GenerateInstanceResponse<N>> {
-        let mut authority_stream = TcpStream::connect(&self.authority_addr).await?;
-        info!("Connection opened with authority");
+    /// Replace the default timeouts of the server.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
 
-        let mut writer = FramedWrite::new(&mut authority_stream, LengthDelimitedCodec::new());
-        let serialized = postcard::to_stdvec(vectors)?;
-        writer.send(serialized.into()).await.unwrap();
-        info!("Sended vectors to authority");
+    /// Ask the authority to stream the secret keys of each batch (see
+    /// KeyBatches::with_streamed_keys).
+    pub fn with_streamed_keys(mut self) -> Self {
+        self.stream_keys = true;
+        self
+    }
 
-        let mut reader = FramedRead::new(&mut authority_stream, LengthDelimitedCodec::new());
-        let frame = reader.next().await.unwrap().unwrap();
+    /// Compare each session to at most cap.max entries of the corpus (all of them by
+    /// default), the scores are then approximate (see CorpusCap).
+    pub fn with_corpus_cap(mut self, cap: CorpusCap) -> Result<Self> {
+        if self.precomputed.is_some() {
+            return Err(anyhow!(CAP_WITH_PRECOMPUTED_KEYS));
+        }
+        self.corpus_cap = Some(cap);
+        Ok(self)
+    }
 
-        let resp: GenerateInstanceResponse<N> = postcard::from_bytes(&frame)?;
+    /// Number of fuzzy hashes whose keys are requested at once from the authority,
+    /// the maximum allowed by the vector size by default.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Result<Self> {
+        KeyBatches::<NILSIMSA_VECTOR_SIZE_BITS>::check_batch_size(batch_size)?;
+        self.batch_size = batch_size;
+        Ok(self)
+    }
 
-        Ok(resp)
+    /// Serve the comparisons to the whole corpus (i.e without tag) with keys loaded from
+    /// disk instead of querying the authority, the keys must have been exported for the
+    /// same corpus (see export_keys).
+    pub fn with_precomputed_keys(
+        mut self,
+        keys: PrecomputedKeys<NILSIMSA_VECTOR_SIZE_BITS>,
+    ) -> Result<Self> {
+        if self.corpus_cap.is_some() {
+            return Err(anyhow!(CAP_WITH_PRECOMPUTED_KEYS));
+        }
+        keys.check_corpus(&self.corpus.read(None)?)?;
+        self.precomputed = Some(Arc::new(keys));
+        Ok(self)
+    }
+
+    /// Retrieve the keys of the whole corpus from the authority, and write them to the
+    /// given file to be loaded with with_precomputed_keys.
+    pub async fn export_keys(&self, path: &Path) -> Result<()> {
+        let batches = KeyBatches::<NILSIMSA_VECTOR_SIZE_BITS>::new(
+            self.authority_addr.clone(),
+            self.corpus.load(None).await?,
+            self.timeouts.authority,
+        )
+        .with_batch_size(self.batch_size)?;
+        let keys = PrecomputedKeys::fetch(batches).await?;
+        keys.save(path)?;
+        info!("Exported the keys of {} fuzzy hashes", keys.corpus_size());
+        Ok(())
+    }
+
+    /// What a client handler needs to build the batches of keys of the corpus.
+    fn corpus_source(&self) -> CorpusSource {
+        CorpusSource {
+            corpus: self.corpus.clone(),
+            authority_addr: self.authority_addr.clone(),
+            authority_timeout: self.timeouts.authority,
+            batch_size: self.batch_size,
+            precomputed: self.precomputed.clone(),
+            stream_keys: self.stream_keys,
+            corpus_cap: self.corpus_cap,
+        }
     }
 
     pub async fn run(&mut self) -> Result<()> {
         loop {
-            let mut s = match self.accept_conn().await {
+            let s = match self.accept_conn().await {
                 Ok(stream) => stream,
                 Err(e) => {
                     error!("{}", e);
@@ -78,59 +439,31 @@ impl Server {
                 }
             };
 
-            info!("Loading client request");
-            let mut reader = FramedRead::new(&mut s, LengthDelimitedCodec::new());
-            let frame = reader.next().await.unwrap().unwrap();
-
-            let requested_hash_type: HashComparisonRequest =
-                postcard::from_bytes(&frame).expect("Failed to understand client request");
-
-            info!("Loading {:?} fuzzy hashes", requested_hash_type);
-
-            let hashes = match requested_hash_type {
-                HashComparisonRequest::NILSIMSA => match self.get_nilsimsa_hashes() {
-                    Err(error) => return Err(error),
-                    Ok(v) => v,
-                },
-            };
-
-            info!("Loaded {} fuzzy hashes", hashes.len());
-            info!("Query authority server for secret keys");
-            let keys = match requested_hash_type {
-                HashComparisonRequest::NILSIMSA => {
-                    let mut batches = vec![];
-                    for hashes_batch in hashes.chunks(NILSIMSA_VECTOR_SIZE_BITS - 1) {
-                        let compressed_response = self
-                            .retrieve_secret_keys::<NILSIMSA_VECTOR_SIZE_BITS>(hashes_batch)
-                            .await?;
-                        match compressed_response.decompress() {
-                            Ok(decompressed) => batches.push(decompressed),
-                            _ => return Err(anyhow!("Unable to retrieve vectors from authority")),
-                        }
-                    }
-                    batches
-                }
-            };
-
-            info!("Received pk/sk from authority");
-
+            // The request is read by the task, so that a client slow to send it doesn't
+            // hold the others back
+            let connection = self.connection_context();
             tokio::spawn(async move {
-                let mut client_handler = ClientHandler {
-                    stream: s,
-                    hash_type: requested_hash_type,
-                    keys,
-                };
-
-                match client_handler.handle_client().await {
-                    Ok(_) => {}
-                    Err(error) => {
-                        error!("Error while handling client : {}", error)
-                    }
+                if let Err(error) = connection.serve(s).await {
+                    error!("Error while handling client : {}", error)
                 }
             });
         }
     }
 
+    /// What the task handling a connection needs from the server.
+    fn connection_context(&self) -> ConnectionContext {
+        ConnectionContext {
+            source: self.corpus_source(),
+            metrics: self.metrics.clone(),
+            // Ciphertexts bound to a session can't be replayed, so they are never compared
+            // twice and caching their scores would only be overhead
+            cache: self.cache.clone().filter(|_| !self.bind_sessions),
+            client_timeout: self.timeouts.client,
+            session_budget: self.timeouts.session,
+            bind_sessions: self.bind_sessions,
+        }
+    }
+
     async fn accept_conn(&mut self) -> Result<TcpStream> {
         match self.listener.accept().await {
             Ok((sock, _)) => Ok(sock),
@@ -139,83 +472,1394 @@ impl Server {
     }
 }
 
-struct ClientHandler<const N: usize> {
-    stream: TcpStream,
-    hash_type: HashComparisonRequest,
-    keys: Vec<(PublicKey<N>, Vec<SecretKey<N>>)>,
-}
+/// Load the Nilsimsa fuzzy hashes of the corpus, or only those with the given tag.
+pub(crate) fn get_nilsimsa_hashes(
+    db_connection: &Connection,
+    tag: Option<&str>,
+    malformed_rows: MalformedRows,
+) -> Result<Vec<FHVector<u8>>> {
+    let mut nilsimsa_statement = match tag {
+        Some(_) => db_connection.prepare(FH_TAG_SQL_QUERY)?,
+        None => db_connection.prepare(FH_SQL_QUERY)?,
+    };
 
-impl ClientHandler<NILSIMSA_VECTOR_SIZE_BITS> {
-    /// The protocol is using framed content, encoded by prefixing the length of the payload
-    /// This reads an entire frame and returns what the readed frame.
-    async fn read_frame(&mut self) -> Result<Vec<u8>> {
-        let mut reader = FramedRead::new(&mut self.stream, LengthDelimitedCodec::new());
-        let frame = reader.next().await.unwrap().unwrap().to_vec();
-        Ok(frame)
-    }
+    let map_row = |row: &rusqlite::Row<'_>| {
+        let r: [u8; 32] = row.get("fh")?;
+        Ok(FHVector::from(r))
+    };
+    let rows = match tag {
+        Some(tag) => nilsimsa_statement.query_map(
+            named_params! {":hash_type": "nilsimsa", ":tag": tag},
+            map_row,
+        )?,
+        None => nilsimsa_statement.query_map(named_params! {":hash_type": "nilsimsa"}, map_row)?,
+    };
 
-    /// The protocol is using framed content, encoded by prefixing the length of the payload
-    /// This write an entire frame made of the given bytes.
-    async fn write_frame(&mut self, bytes: Vec<u8>) -> Result<()> {
-        let mut writer = FramedWrite::new(&mut self.stream, LengthDelimitedCodec::new());
-        writer.send(bytes.into()).await?;
-        Ok(())
+    let mut vectors = vec![];
+    for row in rows {
+        match (row, malformed_rows) {
+            (Ok(vector), _) => vectors.push(vector),
+            (Err(error), MalformedRows::Skip) => {
+                warn!("Skipping malformed fuzzy hash in database : {}", error)
+            }
+            (Err(error), MalformedRows::Fail) => {
+                return Err(anyhow!("Malformed fuzzy hash in database : {}", error));
+            }
+        }
     }
 
-    pub async fn handle_client(&mut self) -> Result<()> {
-        // Split between read and write
-        let (mut rx, mut tx) = self.stream.split();
+    Ok(vectors)
+}
 
-        // Init framed read/write
-        let mut writer = FramedWrite::new(&mut tx, LengthDelimitedCodec::new());
-        let mut reader = FramedRead::new(&mut rx, LengthDelimitedCodec::new());
+/// Comparison of a client to the corpus, over any stream (a TcpStream, or an in-memory
+/// pipe in the tests).
+struct ClientHandler<S, const N: usize> {
+    // Held for the whole comparison, so that a frame sent by the client right after the
+    // previous one is never lost between two reads
+    stream: Framed<S, LengthDelimitedCodec>,
+    hash_type: HashComparisonRequest,
+    batches: KeyBatches<N>,
+    metrics: Arc<Metrics>,
+    cache: Option<Arc<Mutex<DecryptionCache>>>,
+    client_timeout: Duration,
+    session_budget: Option<Duration>,
+    // Nonce the ciphertexts must be bound to, if any
+    session: Option<SessionNonce>,
+}
 
+impl<S: AsyncRead + AsyncWrite + Unpin> ClientHandler<S, NILSIMSA_VECTOR_SIZE_BITS> {
+    pub async fn handle_client(&mut self) -> Result<()> {
+        // Best score of the batches compared so far
         let mut score: i16 = i16::MIN;
+        // Comparison of the previous batch, it runs while the keys of the next batch are
+        // retrieved from the authority and the client encrypts its vector under them.
+        let mut comparison: Option<JoinHandle<Result<i16>>> = None;
+        let started = Instant::now();
 
-        for (pk, sks) in &self.keys {
-            let message = match self.hash_type {
-                HashComparisonRequest::NILSIMSA => {
-                    EncryptionRequest::<NILSIMSA_VECTOR_SIZE_BITS, i16> {
-                        pk: Some(pk.clone()),
-                        similarity_score: Some(score),
+        info!("Query authority server for secret keys");
+        loop {
+            // Do not start another batch once the session is over budget
+            if let Some(budget) = self.session_budget
+                && comparison.is_some()
+                && started.elapsed() >= budget
+            {
+                warn!(
+                    "The comparison exceeded its budget of {:?}, sending the best score so far",
+                    budget
+                );
+                break;
+            }
+
+            let (message, fingerprint, pk, sks) = match self.batches.next_batch().await? {
+                Some((pk, sks)) => {
+                    debug!("Received pk/sk from authority");
+                    let fingerprint = key_fingerprint(&pk)?;
+                    let mut request = match self.hash_type {
+                        HashComparisonRequest::NILSIMSA => {
+                            EncryptionRequest::<NILSIMSA_VECTOR_SIZE_BITS, i16>::with_key(pk, score)
+                        }
+                    };
+                    if let Some(session) = self.session {
+                        request = request.with_session(session);
                     }
+                    let message = postcard::to_stdvec(&request)?;
+                    // Kept to check the proof of the ciphertext
+                    let pk = request.pk.take().expect("Request without a public key");
+                    (message, fingerprint, pk, sks)
                 }
+                None => break,
             };
 
             debug!("Sending PK to client");
-            writer.send(postcard::to_stdvec(&message)?.into()).await?;
+            self.stream.send(message.into()).await?;
 
             let encrypted_vector = match self.hash_type {
-                HashComparisonRequest::NILSIMSA => postcard::from_bytes::<EncryptionResponse<NILSIMSA_VECTOR_SIZE_BITS>>(reader.next().await.unwrap().unwrap().to_vec().as_slice())?,
+                HashComparisonRequest::NILSIMSA => {
+                    // e.g a truncated frame, only this client is dropped
+                    postcard::from_bytes::<EncryptionResponse<NILSIMSA_VECTOR_SIZE_BITS>>(
+                        &read_client_frame(&mut self.stream, self.client_timeout).await?,
+                    )
+                    .map_err(|error| {
+                        anyhow!("Malformed encryption response from the client : {}", error)
+                    })?
+                }
             };
 
-            let ct = match encrypted_vector {
-                EncryptionResponse::<_>::EncryptedVector(ct) => ct,
-                EncryptionResponse::<_>::EndOfComparison => break,
+            let (ct, ct_fingerprint) = match (encrypted_vector, self.session) {
+                (EncryptionResponse::<_>::EncryptedVector(ct, ct_fingerprint), None) => {
+                    (ct, ct_fingerprint)
+                }
+                (
+                    EncryptionResponse::<_>::BoundVector(ct, ct_fingerprint, proof),
+                    Some(session),
+                ) => {
+                    // A ciphertext replayed from another session has no valid proof for
+                    // this one
+                    if pk.verify_bound(&ct, &proof, &session).is_err() {
+                        return Err(anyhow!(
+                            "The ciphertext was not computed for this session (e.g replayed), abort"
+                        ));
+                    }
+                    (ct, ct_fingerprint)
+                }
+                (EncryptionResponse::<_>::EndOfComparison, _) => break,
+                (_, Some(_)) => {
+                    return Err(anyhow!("The ciphertext is not bound to the session, abort"));
+                }
+                (_, None) => {
+                    return Err(anyhow!("Unexpected session proof, abort"));
+                }
             };
+            // Decrypting a ciphertext encrypted under another key gives garbage
+            if ct_fingerprint != fingerprint {
+                return Err(anyhow!(
+                    "The ciphertext was encrypted under another public key (e.g a stale one), abort"
+                ));
+            }
 
-            
-            score = i16::MIN;
-            for sk in sks {
-                let tmp_score = sk.compare(ct.clone());
-                if tmp_score > score {
-                    score = tmp_score;
-                }
+            // Ensure that the ciphertext has the expected dimension before comparing
+            let expected_len = match self.hash_type {
+                HashComparisonRequest::NILSIMSA => NILSIMSA_VECTOR_SIZE_BITS,
+            };
+            check_ciphertext(&ct, expected_len)?;
+
+            if let Some(previous) = comparison.take() {
+                score = score.max(previous.await??);
             }
+            comparison = Some(tokio::spawn(compare_batch(
+                sks,
+                ct,
+                self.metrics.clone(),
+                self.cache.clone(),
+            )));
+        }
+
+        if let Some(previous) = comparison {
+            score = score.max(previous.await??);
         }
 
         // Send to client the "end of the db"
         let message = match self.hash_type {
-            HashComparisonRequest::NILSIMSA => {
-                EncryptionRequest::<NILSIMSA_VECTOR_SIZE_BITS, i16> {
-                    pk: None,
-                    similarity_score: Some(score),
-                }
-            }
+            HashComparisonRequest::NILSIMSA => postcard::to_stdvec(&EncryptionRequest::<
+                NILSIMSA_VECTOR_SIZE_BITS,
+                i16,
+            >::end(score))?,
         };
-        writer.send(postcard::to_stdvec(&message)?.into()).await?;
-        
+        self.stream.send(message.into()).await?;
+        // Shut down the write half, so that the client reads the end of the db followed by
+        // a clean end of stream rather than a reset when the connection is dropped
+        self.stream.close().await?;
+
         info!("Handling client");
+        info!("Metrics : {}", self.metrics);
+        if let Some(cache) = &self.cache {
+            info!("{} comparison results cached", cache.lock().unwrap().len());
+        }
         Ok(())
     }
 }
+
+/// Read the next frame sent by a client, a client not sending it in time is dropped
+/// instead of holding the server.
+async fn read_client_frame<T>(
+    reader: &mut (impl Stream<Item = std::io::Result<T>> + Unpin),
+    client_timeout: Duration,
+) -> Result<T> {
+    match timeout(client_timeout, reader.next()).await {
+        Ok(frame) => received(frame),
+        Err(_) => Err(anyhow!(
+            "The client did not send anything within {:?}",
+            client_timeout
+        )),
+    }
+}
+
+/// Compare the ciphertext against all the secret keys of a batch and return the best score.
+/// Comparisons are CPU intensive, so they run on the blocking thread pool to keep the
+/// async runtime responsive for the other connections.
+async fn compare_batch(
+    sks: Vec<SecretKey<NILSIMSA_VECTOR_SIZE_BITS>>,
+    ct: CipherText<NILSIMSA_VECTOR_SIZE_BITS>,
+    metrics: Arc<Metrics>,
+    cache: Option<Arc<Mutex<DecryptionCache>>>,
+) -> Result<i16> {
+    tokio::task::spawn_blocking(move || {
+        // The ciphertext is only serialized if the cache is enabled
+        let ct_bytes = match cache {
+            Some(_) => postcard::to_stdvec(&ct)?,
+            None => vec![],
+        };
+
+        let timer = PhaseTimer::start("comparison of a batch");
+        let mut score = i16::MIN;
+        for sk in &sks {
+            let tmp_score = match &cache {
+                Some(cache) => compare_cached(sk, &ct, &ct_bytes, cache)?,
+                None => compare(sk, &ct)?,
+            };
+            metrics.record_comparison(tmp_score);
+            score = score.max(tmp_score);
+        }
+        metrics.record_batch();
+        timer.finish();
+
+        Ok(score)
+    })
+    .await?
+}
+
+/// Compare the ciphertext against the secret key, unless the score is already in the cache.
+fn compare_cached(
+    sk: &SecretKey<NILSIMSA_VECTOR_SIZE_BITS>,
+    ct: &CipherText<NILSIMSA_VECTOR_SIZE_BITS>,
+    ct_bytes: &[u8],
+    cache: &Mutex<DecryptionCache>,
+) -> Result<i16> {
+    let key = DecryptionCache::key(ct_bytes, sk)?;
+    if let Some(score) = cache.lock().unwrap().get(&key) {
+        return Ok(score);
+    }
+
+    // The lock is not held while comparing, to not block the other clients
+    let score = compare(sk, ct)?;
+    cache.lock().unwrap().insert(key, score);
+
+    Ok(score)
+}
+
+/// Compare the ciphertext against the secret key. The ciphertext comes from the client,
+/// so an out of range inner product (i.e not from a Nilsimsa vector) is an error.
+fn compare(
+    sk: &SecretKey<NILSIMSA_VECTOR_SIZE_BITS>,
+    ct: &CipherText<NILSIMSA_VECTOR_SIZE_BITS>,
+) -> Result<i16> {
+    sk.try_compare(ct.clone()).ok_or(anyhow!(
+        "The ciphertext doesn't encrypt a Nilsimsa vector, abort"
+    ))
+}
+
+/// Helper function, this function ensures that a ciphertext received from a client
+/// encrypts a vector of the expected dimension.
+fn check_ciphertext<const N: usize>(ct: &CipherText<N>, expected_len: usize) -> Result<()> {
+    if ct.len() != expected_len {
+        return Err(anyhow!(
+            "Received a ciphertext of dimension {} instead of {}, abort",
+            ct.len(),
+            expected_len
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::tests::{Requests, keying_authority, mock_authority, precomputed_keys};
+    use fe::Instance;
+    use fe::traits::{FEInstance, FEPubKey};
+    use rand::SeedableRng;
+    use rand::rngs::{StdRng, SysRng};
+    use std::array;
+    use tokio::sync::oneshot;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    /// In-memory database holding the given Nilsimsa digests.
+    fn test_db(hashes: impl IntoIterator<Item = [u8; 32]>) -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute(
+            "CREATE TABLE fuzzy_hashes(fh BLOB PRIMARY KEY, type TEXT)",
+            (),
+        )
+        .unwrap();
+        for hash in hashes {
+            db.execute("INSERT INTO fuzzy_hashes VALUES (?1, 'nilsimsa')", (hash,))
+                .unwrap();
+        }
+        db
+    }
+
+    /// Start a mock authority (see mock_authority) and return its address.
+    async fn spawn_mock_authority(requests: Requests) -> String {
+        let authority = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let authority_addr = authority.local_addr().unwrap().to_string();
+        tokio::spawn(mock_authority(authority, requests, 0));
+        authority_addr
+    }
+
+    /// Server listening on a random port.
+    async fn test_server(db: Connection, authority_addr: String, metrics: Arc<Metrics>) -> Server {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Server::new(listener, db, authority_addr, metrics)
+    }
+
+    /// Run the server in the background and return its address.
+    fn spawn_server(mut server: Server) -> std::net::SocketAddr {
+        let addr = server.listener.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+        addr
+    }
+
+    #[test]
+    fn test_get_nilsimsa_hashes_by_tag() {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute(
+            "CREATE TABLE fuzzy_hashes(fh BLOB PRIMARY KEY, type TEXT, tag TEXT)",
+            (),
+        )
+        .unwrap();
+        for (i, tag) in ["a", "b", "a", "c", "a"].iter().enumerate() {
+            db.execute(
+                "INSERT INTO fuzzy_hashes VALUES (?1, 'nilsimsa', ?2)",
+                ([i as u8; 32], tag),
+            )
+            .unwrap();
+        }
+
+        assert_eq!(
+            get_nilsimsa_hashes(&db, None, MalformedRows::Fail)
+                .unwrap()
+                .len(),
+            5
+        );
+        assert_eq!(
+            get_nilsimsa_hashes(&db, Some("a"), MalformedRows::Fail)
+                .unwrap()
+                .len(),
+            3
+        );
+        assert_eq!(
+            get_nilsimsa_hashes(&db, Some("c"), MalformedRows::Fail)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(
+            get_nilsimsa_hashes(&db, Some("d"), MalformedRows::Fail)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_malformed_rows() {
+        let db = test_db([[0u8; 32], [2u8; 32]]);
+        db.execute(
+            "INSERT INTO fuzzy_hashes VALUES (?1, 'nilsimsa')",
+            (&[1u8; 31][..],),
+        )
+        .unwrap();
+
+        let hashes = get_nilsimsa_hashes(&db, None, MalformedRows::Skip).unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert!(get_nilsimsa_hashes(&db, None, MalformedRows::Fail).is_err());
+    }
+
+    #[test]
+    fn test_get_nilsimsa_hashes_without_tag_column() {
+        // Database created before tags were introduced
+        let db = test_db([[0u8; 32]]);
+
+        assert_eq!(
+            get_nilsimsa_hashes(&db, None, MalformedRows::Fail)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(get_nilsimsa_hashes(&db, Some("a"), MalformedRows::Fail).is_err());
+    }
+
+    #[test]
+    fn test_check_ciphertext() {
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+
+        let pk = Instance::<NILSIMSA_VECTOR_SIZE_BITS>::setup().public_key();
+        let ct = pk.encrypt(&mut rng, [1u8; NILSIMSA_VECTOR_SIZE_BITS]);
+        assert!(check_ciphertext(&ct, NILSIMSA_VECTOR_SIZE_BITS).is_ok());
+
+        // Ciphertext with a mismatched dimension
+        let pk = Instance::<256>::setup().public_key();
+        let ct = pk.encrypt(&mut rng, [1u8; 256]);
+        assert!(check_ciphertext(&ct, NILSIMSA_VECTOR_SIZE_BITS).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compare_batch_does_not_block_runtime() {
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let instance = Instance::<NILSIMSA_VECTOR_SIZE_BITS>::setup();
+        let pk = instance.public_key();
+
+        // Hash made only of 0, compared against hashes having their first i bits set
+        let vector = |i: usize| -> [u8; NILSIMSA_VECTOR_SIZE_BITS] {
+            let half = NILSIMSA_VECTOR_SIZE_BITS / 2;
+            array::from_fn(|j| (((j % half) < i) ^ (j >= half)) as u8)
+        };
+        let sks = (10..200).map(|i| instance.secret_key(vector(i))).collect();
+        let ct = pk.encrypt(&mut rng, vector(0));
+
+        // The test runtime is single threaded, so the other task can only complete
+        // while the comparison is in progress if the comparison does not block it.
+        let metrics = Arc::new(Metrics::new(100));
+        let comparison = tokio::spawn(compare_batch(sks, ct, metrics.clone(), None));
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move { tx.send(()).unwrap() });
+        rx.await.unwrap();
+        assert!(!comparison.is_finished());
+
+        assert_eq!(comparison.await.unwrap().unwrap(), 128 - 10);
+
+        // Scores of the batch range from 128 - 199 to 128 - 10
+        assert_eq!(metrics.comparisons(), 190);
+        assert_eq!(metrics.matches(), 19);
+        assert_eq!(metrics.batches(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_compare_batch_cache_hit() {
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let instance = Instance::<NILSIMSA_VECTOR_SIZE_BITS>::setup();
+        let pk = instance.public_key();
+
+        let vector: [u8; NILSIMSA_VECTOR_SIZE_BITS] =
+            array::from_fn(|i| (i >= NILSIMSA_VECTOR_SIZE_BITS / 2) as u8);
+        let sks = vec![instance.secret_key(vector)];
+        let ct = pk.encrypt(&mut rng, vector);
+
+        let metrics = Arc::new(Metrics::new(0));
+        let cache = Arc::new(Mutex::new(DecryptionCache::new(16)));
+
+        // First comparison fills the cache, and the repeated one hits it
+        let score = compare_batch(
+            sks.clone(),
+            ct.clone(),
+            metrics.clone(),
+            Some(cache.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(score, 128);
+        assert_eq!(cache.lock().unwrap().len(), 1);
+
+        let score = compare_batch(
+            sks.clone(),
+            ct.clone(),
+            metrics.clone(),
+            Some(cache.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(score, 128);
+        assert_eq!(cache.lock().unwrap().len(), 1);
+
+        // Tamper the cached value to make sure it is the one returned
+        let key = DecryptionCache::key(&postcard::to_stdvec(&ct).unwrap(), &sks[0]).unwrap();
+        cache.lock().unwrap().insert(key, 42);
+        let score = compare_batch(sks, ct, metrics, Some(cache)).await.unwrap();
+        assert_eq!(score, 42);
+    }
+
+    #[tokio::test]
+    async fn test_compare_batch_malformed_ciphertext() {
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let instance = Instance::<NILSIMSA_VECTOR_SIZE_BITS>::setup();
+        let pk = instance.public_key();
+        let vector = FHVector::from([0x5au8; 32]).to_fe_vector().unwrap();
+        let sks = vec![instance.secret_key(vector)];
+        let metrics = Arc::new(Metrics::new(0));
+
+        // Identical hashes are reported with the max score
+        let ct = pk.encrypt(&mut rng, vector);
+        let score = compare_batch(sks.clone(), ct, metrics.clone(), None).await;
+        assert_eq!(score.unwrap(), 128);
+
+        // A vector that is not a Nilsimsa vector is rejected, with or without the cache
+        let cache = Arc::new(Mutex::new(DecryptionCache::new(16)));
+        let ct = pk.encrypt(&mut rng, [2u8; NILSIMSA_VECTOR_SIZE_BITS]);
+        for cache in [None, Some(cache.clone())] {
+            let error = compare_batch(sks.clone(), ct.clone(), metrics.clone(), cache)
+                .await
+                .unwrap_err();
+            assert!(
+                error
+                    .to_string()
+                    .contains("doesn't encrypt a Nilsimsa vector")
+            );
+        }
+        assert_eq!(cache.lock().unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_list_supported_hashes() {
+        let server = test_server(test_db([]), String::new(), Arc::new(Metrics::new(0))).await;
+        let addr = spawn_server(server);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut writer = FramedWrite::new(&mut stream, codec());
+        let request = messages::encode_versioned(&ClientRequest::ListSupportedHashes).unwrap();
+        writer.send(request.into()).await.unwrap();
+
+        let mut reader = FramedRead::new(&mut stream, codec());
+        let frame = reader.next().await.unwrap().unwrap();
+        let response: SupportedHashesResponse = postcard::from_bytes(&frame).unwrap();
+
+        assert_eq!(response.0, vec![HashComparisonRequest::NILSIMSA]);
+    }
+
+    #[tokio::test]
+    async fn test_describe_hash() {
+        let server = test_server(test_db([]), String::new(), Arc::new(Metrics::new(0))).await;
+        let addr = spawn_server(server);
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut framed = Framed::new(stream, codec());
+        let request = ClientRequest::DescribeHash(HashComparisonRequest::NILSIMSA);
+        let request = messages::encode_versioned(&request).unwrap();
+        framed.send(request.into()).await.unwrap();
+
+        let frame = framed.next().await.unwrap().unwrap();
+        let parameters: HashParameters = postcard::from_bytes(&frame).unwrap();
+
+        // The connection is kept open for the next request
+        let request = messages::encode_versioned(&ClientRequest::ListSupportedHashes).unwrap();
+        framed.send(request.into()).await.unwrap();
+        let frame = framed.next().await.unwrap().unwrap();
+        let response: SupportedHashesResponse = postcard::from_bytes(&frame).unwrap();
+        assert_eq!(response.0, vec![HashComparisonRequest::NILSIMSA]);
+
+        assert_eq!(parameters.dimension as usize, NILSIMSA_VECTOR_SIZE_BITS);
+        // The score of identical hashes can be recovered
+        assert_eq!(
+            SecretKey::<NILSIMSA_VECTOR_SIZE_BITS>::max_recoverable(parameters.bound),
+            Some(NILSIMSA_VECTOR_SIZE_BITS as u16 / 2)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_timeout() {
+        let server = test_server(test_db([]), String::new(), Arc::new(Metrics::new(0))).await;
+        let addr = spawn_server(server.with_timeouts(Timeouts {
+            client: Duration::from_millis(100),
+            ..Timeouts::default()
+        }));
+
+        // Silent client, the server gives up on it and closes the connection
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        let mut reader = FramedRead::new(&mut silent, codec());
+        assert!(reader.next().await.is_none());
+
+        // The server is still serving the other clients
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut writer = FramedWrite::new(&mut stream, codec());
+        let request = messages::encode_versioned(&ClientRequest::ListSupportedHashes).unwrap();
+        writer.send(request.into()).await.unwrap();
+
+        let mut reader = FramedRead::new(&mut stream, codec());
+        assert!(reader.next().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_does_not_block_others() {
+        let server = test_server(test_db([]), String::new(), Arc::new(Metrics::new(0))).await;
+        let addr = spawn_server(server);
+
+        // Still within the client timeout while the other client is served
+        let _silent = TcpStream::connect(addr).await.unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut writer = FramedWrite::new(&mut stream, codec());
+        let request = messages::encode_versioned(&ClientRequest::ListSupportedHashes).unwrap();
+        writer.send(request.into()).await.unwrap();
+
+        let mut reader = FramedRead::new(&mut stream, codec());
+        let frame = timeout(Duration::from_secs(5), reader.next())
+            .await
+            .unwrap();
+        assert!(frame.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_corpus_read_once() {
+        let requests = Requests::default();
+        let authority_addr = spawn_mock_authority(requests.clone()).await;
+        let db = test_db((0..3u8).map(|i| [i; 32]));
+        let server = test_server(db, authority_addr, Arc::new(Metrics::new(0))).await;
+        let corpus = server.corpus.clone();
+        let addr = spawn_server(server);
+
+        // Keys of the mock authority are made of 1, so the score is always 128
+        let vector = array::from_fn(|i| (i % 2 == 0) as u8);
+        let clients: Vec<_> = (0..5)
+            .map(|_| {
+                let request = ClientRequest::Compare(HashComparisonRequest::NILSIMSA, None);
+                tokio::spawn(compare_as_client(addr, request, vector))
+            })
+            .collect();
+        for client in clients {
+            assert_eq!(client.await.unwrap(), Some(128));
+        }
+
+        // Every client was compared to the corpus, which was read from the database once
+        assert_eq!(requests.lock().unwrap().len(), 5);
+        assert_eq!(corpus.reads(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_corpus_cap() {
+        let corpus: Vec<FHVector<u8>> = (0..10u8).map(|i| FHVector::from([i; 32])).collect();
+        for sampled in [false, true] {
+            let requests = Requests::default();
+            let authority_addr = spawn_mock_authority(requests.clone()).await;
+            let db = test_db((0..10u8).map(|i| [i; 32]));
+            let metrics = Arc::new(Metrics::new(0));
+            let server = test_server(db, authority_addr, metrics.clone()).await;
+            let addr = spawn_server(
+                server
+                    .with_corpus_cap(CorpusCap { max: 4, sampled })
+                    .unwrap(),
+            );
+
+            let vector = array::from_fn(|i| (i % 2 == 0) as u8);
+            let request = ClientRequest::Compare(HashComparisonRequest::NILSIMSA, None);
+            assert_eq!(compare_as_client(addr, request, vector).await, Some(128));
+
+            // Only 4 of the 10 fuzzy hashes of the corpus were compared
+            assert_eq!(metrics.comparisons(), 4);
+            let requested: Vec<FHVector<u8>> =
+                requests.lock().unwrap().iter().flatten().copied().collect();
+            assert_eq!(requested.len(), 4);
+            if sampled {
+                assert!(requested.iter().all(|hash| corpus.contains(hash)));
+            } else {
+                assert_eq!(requested, corpus[..4]);
+            }
+        }
+
+        // Corpora smaller than the cap are compared entirely, without copying them
+        let cap = CorpusCap {
+            max: 20,
+            sampled: true,
+        };
+        let corpus: Hashes = corpus.into();
+        assert!(Arc::ptr_eq(&cap.apply(&corpus), &corpus));
+    }
+
+    #[tokio::test]
+    async fn test_back_to_back_frames() {
+        use tokio::io::AsyncWriteExt;
+        use tokio_util::bytes::BytesMut;
+        use tokio_util::codec::Encoder;
+
+        let authority_addr = spawn_mock_authority(Requests::default()).await;
+        let metrics = Arc::new(Metrics::new(0));
+        let server = test_server(test_db([[0u8; 32]]), authority_addr, metrics.clone()).await;
+        let addr = spawn_server(server);
+
+        // The request and the end of the comparison, sent in a single write
+        let request = ClientRequest::Compare(HashComparisonRequest::NILSIMSA, None);
+        let end = EncryptionResponse::<NILSIMSA_VECTOR_SIZE_BITS>::EndOfComparison;
+        let mut bytes = BytesMut::new();
+        let mut encoder = codec();
+        encoder
+            .encode(
+                messages::encode_versioned(&request).unwrap().into(),
+                &mut bytes,
+            )
+            .unwrap();
+        encoder
+            .encode(postcard::to_stdvec(&end).unwrap().into(), &mut bytes)
+            .unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&bytes).await.unwrap();
+
+        // The end of the comparison is read right after the request, not lost with the
+        // buffer of the request. The timeout leaves time for a debug build to retrieve the
+        // keys of the batch.
+        let mut reader = FramedRead::new(&mut stream, codec());
+        let mut next_request = async || {
+            let frame = timeout(Duration::from_secs(120), reader.next())
+                .await
+                .expect("The end of the comparison was lost")
+                .unwrap()
+                .unwrap();
+            postcard::from_bytes::<EncryptionRequest<NILSIMSA_VECTOR_SIZE_BITS, i16>>(&frame)
+                .unwrap()
+        };
+        assert!(next_request().await.pk.is_some());
+        let end = next_request().await;
+        assert!(end.is_end());
+        assert_eq!(end.similarity_score, Some(i16::MIN));
+        assert_eq!(metrics.comparisons(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_client_ends_early() {
+        let requests = Requests::default();
+        let authority_addr = spawn_mock_authority(requests.clone()).await;
+
+        // Corpus of three batches
+        let db = test_db((0..3u8).map(|i| [i; 32]));
+        let metrics = Arc::new(Metrics::new(0));
+        let server = test_server(db, authority_addr, metrics.clone()).await;
+        let addr = spawn_server(server.with_batch_size(1).unwrap());
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut framed = tokio_util::codec::Framed::new(stream, codec());
+        let request = ClientRequest::Compare(HashComparisonRequest::NILSIMSA, None);
+        framed
+            .send(messages::encode_versioned(&request).unwrap().into())
+            .await
+            .unwrap();
+
+        // Encrypt under the key of the first batch, then end without encrypting under the
+        // key of the second one
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let vector = array::from_fn(|i| (i % 2 == 0) as u8);
+        let mut scores = vec![];
+        loop {
+            let frame = framed.next().await.unwrap().unwrap();
+            let request: EncryptionRequest<NILSIMSA_VECTOR_SIZE_BITS, i16> =
+                postcard::from_bytes(&frame).unwrap();
+            scores.push(request.similarity_score);
+            let response = match request.pk {
+                None => break,
+                Some(_) if scores.len() > 1 => EncryptionResponse::EndOfComparison,
+                Some(pk) => EncryptionResponse::EncryptedVector(
+                    pk.encrypt(&mut rng, vector),
+                    key_fingerprint(&pk).unwrap(),
+                ),
+            };
+            framed
+                .send(postcard::to_stdvec(&response).unwrap().into())
+                .await
+                .unwrap();
+        }
+
+        // The first batch is compared while the second key is sent, its score comes with
+        // the end of the comparison (keys of the mock authority are made of 1, so the
+        // score is always 128)
+        assert_eq!(scores, [Some(i16::MIN), Some(i16::MIN), Some(128)]);
+        assert!(framed.next().await.is_none());
+        assert_eq!(metrics.batches(), 1);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_response() {
+        let authority_addr = spawn_mock_authority(Requests::default()).await;
+        let server = test_server(test_db([]), authority_addr, Arc::new(Metrics::new(0))).await;
+        let addr = spawn_server(server);
+
+        let vector = array::from_fn(|i| (i % 2 == 0) as u8);
+        let hashes = vec![FHVector::from([3u8; 32])];
+        let request = ClientRequest::CompareInline(HashComparisonRequest::NILSIMSA, hashes);
+
+        // A session whose client sends a truncated ciphertext
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut framed = tokio_util::codec::Framed::new(stream, codec());
+        framed
+            .send(messages::encode_versioned(&request).unwrap().into())
+            .await
+            .unwrap();
+        let frame = framed.next().await.unwrap().unwrap();
+        let key_request: EncryptionRequest<NILSIMSA_VECTOR_SIZE_BITS, i16> =
+            postcard::from_bytes(&frame).unwrap();
+
+        // Another session in progress at the same time
+        let other = tokio::spawn(compare_as_client(addr, request, vector));
+
+        let pk = key_request.pk.unwrap();
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let response = EncryptionResponse::EncryptedVector(
+            pk.encrypt(&mut rng, vector),
+            key_fingerprint(&pk).unwrap(),
+        );
+        let bytes = postcard::to_stdvec(&response).unwrap();
+        framed
+            .send(bytes[..bytes.len() / 2].to_vec().into())
+            .await
+            .unwrap();
+        // Closed without a score
+        assert!(matches!(framed.next().await, None | Some(Err(_))));
+
+        // The other session and the new ones are served
+        assert_eq!(other.await.unwrap(), Some(128));
+        let request = ClientRequest::CompareInline(
+            HashComparisonRequest::NILSIMSA,
+            vec![FHVector::from([3u8; 32])],
+        );
+        assert_eq!(compare_as_client(addr, request, vector).await, Some(128));
+    }
+
+    #[tokio::test]
+    async fn test_precomputed_keys() {
+        let corpus = |size: u8| test_db((0..size).map(|i| [i; 32]));
+        let server =
+            |db, authority_addr| test_server(db, authority_addr, Arc::new(Metrics::new(0)));
+        let authority_addr = spawn_mock_authority(Requests::default()).await;
+
+        let path = std::env::temp_dir().join(format!("keys_{}", std::process::id()));
+        server(corpus(5), authority_addr)
+            .await
+            .export_keys(&path)
+            .await
+            .unwrap();
+        let keys = || PrecomputedKeys::load(&path).unwrap();
+        assert_eq!(keys().corpus_size(), 5);
+
+        // The keys don't match another corpus
+        let error = server(corpus(6), String::new())
+            .await
+            .with_precomputed_keys(keys())
+            .unwrap_err();
+        assert!(error.to_string().contains("exported for 5 fuzzy hashes"));
+
+        // Nor another corpus of the same size
+        let db = corpus(5);
+        db.execute(
+            "UPDATE fuzzy_hashes SET fh = ?1 WHERE fh = ?2",
+            ([9u8; 32], [4u8; 32]),
+        )
+        .unwrap();
+        let error = server(db, String::new())
+            .await
+            .with_precomputed_keys(keys())
+            .unwrap_err();
+        assert!(error.to_string().contains("other fuzzy hashes"));
+
+        // The keys cover the whole corpus, whichever is set first
+        let cap = CorpusCap {
+            max: 2,
+            sampled: false,
+        };
+        let server_with_cap = server(corpus(5), String::new()).await;
+        let error = server_with_cap
+            .with_corpus_cap(cap)
+            .unwrap()
+            .with_precomputed_keys(keys())
+            .unwrap_err();
+        assert!(error.to_string().contains("capped"));
+        let server_with_keys = server(corpus(5), String::new()).await;
+        let error = server_with_keys
+            .with_precomputed_keys(keys())
+            .unwrap()
+            .with_corpus_cap(cap)
+            .unwrap_err();
+        assert!(error.to_string().contains("capped"));
+
+        // No authority to query, the keys loaded from disk are used
+        let server = server(corpus(5), String::new())
+            .await
+            .with_precomputed_keys(keys())
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let addr = spawn_server(server);
+
+        // Keys of the mock authority are made of 1, so the score is always 128
+        let vector = array::from_fn(|i| (i % 2 == 0) as u8);
+        let request = ClientRequest::Compare(HashComparisonRequest::NILSIMSA, None);
+        assert_eq!(compare_as_client(addr, request, vector).await, Some(128));
+    }
+
+    /// Compare the vector as a client would, and return the score sent with the end of the
+    /// db (None if the server closed the connection instead).
+    async fn compare_as_client(
+        addr: std::net::SocketAddr,
+        request: ClientRequest,
+        vector: [u8; NILSIMSA_VECTOR_SIZE_BITS],
+    ) -> Option<i16> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = messages::encode_versioned(&request).unwrap();
+        let mut writer = FramedWrite::new(&mut stream, codec());
+        writer.send(request.into()).await.unwrap();
+        encrypt_as_client(stream, vector).await
+    }
+
+    /// Encrypt the vector under every public key sent by the compute server, and return
+    /// the final score (once the request of the client was sent).
+    async fn encrypt_as_client(
+        mut stream: impl AsyncRead + AsyncWrite + Unpin,
+        vector: [u8; NILSIMSA_VECTOR_SIZE_BITS],
+    ) -> Option<i16> {
+        let (rx, tx) = tokio::io::split(&mut stream);
+        let mut reader = FramedRead::new(rx, codec());
+        let mut writer = FramedWrite::new(tx, codec());
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        loop {
+            // Generous, several clients may be served at once by a debug build
+            let frame = timeout(Duration::from_secs(300), reader.next())
+                .await
+                .expect("The server stalled")?
+                .unwrap();
+            let request: EncryptionRequest<NILSIMSA_VECTOR_SIZE_BITS, i16> =
+                postcard::from_bytes(&frame).unwrap();
+            let Some(pk) = request.pk else {
+                return request.similarity_score;
+            };
+            let fingerprint = key_fingerprint(&pk).unwrap();
+            let response = match request.session {
+                Some(session) => {
+                    let (ct, proof) = pk.encrypt_bound(&mut rng, vector, &session);
+                    EncryptionResponse::BoundVector(ct, fingerprint, proof)
+                }
+                None => {
+                    EncryptionResponse::EncryptedVector(pk.encrypt(&mut rng, vector), fingerprint)
+                }
+            };
+            writer
+                .send(postcard::to_stdvec(&response).unwrap().into())
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_exchange() {
+        // The whole comparison goes through an in-memory pipe between the client and the
+        // task serving its connection, with precomputed keys so that the authority isn't
+        // queried either
+        let corpus: Vec<FHVector<u8>> = (0..5u8).map(|i| FHVector::from([i; 32])).collect();
+        let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+        let metrics = Arc::new(Metrics::new(128));
+        let connection = ConnectionContext {
+            source: CorpusSource {
+                corpus: Corpus::new(Connection::open_in_memory().unwrap()),
+                authority_addr: String::new(),
+                authority_timeout: Timeouts::default().authority,
+                batch_size: KeyBatches::<NILSIMSA_VECTOR_SIZE_BITS>::MAX_BATCH_SIZE,
+                precomputed: Some(precomputed_keys(&corpus)),
+                stream_keys: false,
+                corpus_cap: None,
+            },
+            metrics: metrics.clone(),
+            cache: None,
+            client_timeout: Timeouts::default().client,
+            session_budget: None,
+            bind_sessions: false,
+        };
+        let server = tokio::spawn(connection.serve(server_stream));
+
+        let mut client = client::Client::new(client_stream, corpus[3], None);
+        client.negotiate().await.unwrap();
+        assert_eq!(client.start().await.unwrap(), 128);
+        server.await.unwrap().unwrap();
+        assert_eq!(metrics.comparisons(), 5);
+        assert_eq!(metrics.matches(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_replayed_ciphertext_rejected() {
+        let authority_addr = spawn_mock_authority(Requests::default()).await;
+
+        // The cache is bypassed, as the ciphertexts of a session are never compared twice
+        let cache = Arc::new(Mutex::new(DecryptionCache::new(16)));
+        let server = |authority_addr| {
+            let cache = cache.clone();
+            async move {
+                test_server(
+                    test_db([[7u8; 32]]),
+                    authority_addr,
+                    Arc::new(Metrics::new(0)),
+                )
+                .await
+                .with_cache(cache)
+            }
+        };
+
+        // With precomputed keys, every session uses the same public key
+        let path = std::env::temp_dir().join(format!("replay_keys_{}", std::process::id()));
+        server(authority_addr)
+            .await
+            .export_keys(&path)
+            .await
+            .unwrap();
+        let server = server(String::new())
+            .await
+            .with_precomputed_keys(PrecomputedKeys::load(&path).unwrap())
+            .unwrap()
+            .with_session_binding();
+        std::fs::remove_file(&path).unwrap();
+        let addr = spawn_server(server);
+
+        let request = ClientRequest::Compare(HashComparisonRequest::NILSIMSA, None);
+        let vector = array::from_fn(|i| (i % 2 == 0) as u8);
+
+        // Record the ciphertext of a session
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let (rx, tx) = stream.split();
+        let mut reader = FramedRead::new(rx, codec());
+        let mut writer = FramedWrite::new(tx, codec());
+        let first = messages::encode_versioned(&request).unwrap();
+        writer.send(first.into()).await.unwrap();
+        let frame = reader.next().await.unwrap().unwrap();
+        let key_request: EncryptionRequest<NILSIMSA_VECTOR_SIZE_BITS, i16> =
+            postcard::from_bytes(&frame).unwrap();
+        let session = key_request.session.unwrap();
+        let pk = key_request.pk.unwrap();
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let (ct, proof) = pk.encrypt_bound(&mut rng, vector, &session);
+        let recorded = postcard::to_stdvec(&EncryptionResponse::BoundVector(
+            ct,
+            key_fingerprint(&pk).unwrap(),
+            proof,
+        ))
+        .unwrap();
+        writer.send(recorded.clone().into()).await.unwrap();
+        let end: EncryptionRequest<NILSIMSA_VECTOR_SIZE_BITS, i16> =
+            postcard::from_bytes(&reader.next().await.unwrap().unwrap()).unwrap();
+        assert_eq!(end.similarity_score, Some(128));
+        assert_eq!(cache.lock().unwrap().len(), 0);
+
+        // Replayed in another session, under the same public key
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let (rx, tx) = stream.split();
+        let mut reader = FramedRead::new(rx, codec());
+        let mut writer = FramedWrite::new(tx, codec());
+        let first = messages::encode_versioned(&request).unwrap();
+        writer.send(first.into()).await.unwrap();
+        let frame = reader.next().await.unwrap().unwrap();
+        let key_request: EncryptionRequest<NILSIMSA_VECTOR_SIZE_BITS, i16> =
+            postcard::from_bytes(&frame).unwrap();
+        assert_ne!(key_request.session, Some(session));
+        assert_eq!(
+            key_fingerprint(&key_request.pk.unwrap()).unwrap(),
+            key_fingerprint(&pk).unwrap()
+        );
+        writer.send(recorded.into()).await.unwrap();
+        // The server closes the connection without a score
+        assert!(matches!(reader.next().await, None | Some(Err(_))));
+
+        // A ciphertext that is not bound to the session is rejected as well
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut framed = tokio_util::codec::Framed::new(stream, codec());
+        framed
+            .send(messages::encode_versioned(&request).unwrap().into())
+            .await
+            .unwrap();
+        let frame = framed.next().await.unwrap().unwrap();
+        let key_request: EncryptionRequest<NILSIMSA_VECTOR_SIZE_BITS, i16> =
+            postcard::from_bytes(&frame).unwrap();
+        let pk = key_request.pk.unwrap();
+        let response = EncryptionResponse::EncryptedVector(
+            pk.encrypt(&mut rng, vector),
+            key_fingerprint(&pk).unwrap(),
+        );
+        framed
+            .send(postcard::to_stdvec(&response).unwrap().into())
+            .await
+            .unwrap();
+        assert!(matches!(framed.next().await, None | Some(Err(_))));
+
+        // Honest clients are still served
+        assert_eq!(compare_as_client(addr, request, vector).await, Some(128));
+    }
+
+    #[tokio::test]
+    async fn test_inline_corpus() {
+        let authority = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let authority_addr = authority.local_addr().unwrap().to_string();
+        let requests = Requests::default();
+        tokio::spawn(keying_authority(authority, requests.clone()));
+
+        // The database is not used
+        let server = test_server(test_db([]), authority_addr, Arc::new(Metrics::new(0))).await;
+        let addr = spawn_server(server);
+
+        let client: [u8; 32] = array::from_fn(|i| (i * 29 + 3) as u8);
+        let mut corpus: Vec<[u8; 32]> = vec![[0; 32], [0xff; 32], client];
+        // Differs from the client hash on 6 bits
+        corpus[2][4] ^= 0b111;
+        corpus[2][9] ^= 0b1011_0000;
+        let hashes: Vec<FHVector<u8>> = corpus.iter().map(|h| FHVector::from(*h)).collect();
+        let expected = corpus
+            .iter()
+            .map(|h| fuzzy_hashes::nilsimsa_compare(h, &client))
+            .max()
+            .unwrap();
+        assert_eq!(expected, 128 - 6);
+
+        let vector = FHVector::from(client).to_fe_vector().unwrap();
+        let request = ClientRequest::CompareInline(HashComparisonRequest::NILSIMSA, hashes.clone());
+        assert_eq!(
+            compare_as_client(addr, request, vector).await,
+            Some(expected)
+        );
+        // The authority only derived the keys of the inline fuzzy hashes
+        let received = requests.lock().unwrap().clone();
+        assert_eq!(received, vec![hashes]);
+
+        // More fuzzy hashes than an instance can derive keys for, or another kind of vector
+        let too_many = vec![FHVector::from(client); NILSIMSA_VECTOR_SIZE_BITS];
+        let other_kind = vec![FHVector::FeatureVector(
+            [0; fuzzy_hashes::FEATURE_VECTOR_SIZE_BYTES],
+        )];
+        for hashes in [too_many, other_kind] {
+            let request = ClientRequest::CompareInline(HashComparisonRequest::NILSIMSA, hashes);
+            assert_eq!(compare_as_client(addr, request, vector).await, None);
+        }
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_empty_corpus() {
+        // The authority must not be queried for an empty corpus
+        let requests = Requests::default();
+        let authority_addr = spawn_mock_authority(requests.clone()).await;
+        let server = test_server(test_db([]), authority_addr, Arc::new(Metrics::new(0))).await;
+        let addr = spawn_server(server);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut writer = FramedWrite::new(&mut stream, codec());
+        let request = messages::encode_versioned(&ClientRequest::Compare(
+            HashComparisonRequest::NILSIMSA,
+            None,
+        ))
+        .unwrap();
+        writer.send(request.into()).await.unwrap();
+
+        let mut reader = FramedRead::new(&mut stream, codec());
+        let frame = timeout(Duration::from_secs(30), reader.next())
+            .await
+            .expect("The server stalled")
+            .unwrap()
+            .unwrap();
+        let end: EncryptionRequest<NILSIMSA_VECTOR_SIZE_BITS, i16> =
+            postcard::from_bytes(&frame).unwrap();
+        assert!(end.is_end());
+        assert_eq!(end.similarity_score, Some(i16::MIN));
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_end_of_db_then_close() {
+        let server = test_server(test_db([]), String::new(), Arc::new(Metrics::new(0))).await;
+        let addr = spawn_server(server);
+
+        // The client never closes its side, the last frame must still be received
+        // followed by a clean end of stream (not a reset)
+        for _ in 0..20 {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let (rx, tx) = stream.split();
+            let mut reader = FramedRead::new(rx, codec());
+            let mut writer = FramedWrite::new(tx, codec());
+            let request = messages::encode_versioned(&ClientRequest::Compare(
+                HashComparisonRequest::NILSIMSA,
+                None,
+            ))
+            .unwrap();
+            writer.send(request.into()).await.unwrap();
+
+            let frame = timeout(Duration::from_secs(30), reader.next())
+                .await
+                .expect("The server stalled")
+                .unwrap()
+                .unwrap();
+            let end: EncryptionRequest<NILSIMSA_VECTOR_SIZE_BITS, i16> =
+                postcard::from_bytes(&frame).unwrap();
+            assert!(end.is_end());
+            assert!(reader.next().await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    // The cache is locked on purpose while the server is running, to block the comparisons
+    #[allow(clippy::await_holding_lock)]
+    async fn test_next_key_sent_while_comparing() {
+        let authority_addr = spawn_mock_authority(Requests::default()).await;
+
+        // Corpus of two batches
+        let hashes = (0..NILSIMSA_VECTOR_SIZE_BITS + 5)
+            .map(|i| FHVector::from([i as u8; 32]))
+            .collect();
+        let timeouts = Timeouts::default();
+        let batches = KeyBatches::new(authority_addr, hashes, timeouts.authority);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let metrics = Arc::new(Metrics::new(0));
+        let cache = Arc::new(Mutex::new(DecryptionCache::new(16)));
+        let mut client_handler = ClientHandler {
+            stream: Framed::new(stream, codec()),
+            hash_type: HashComparisonRequest::NILSIMSA,
+            batches,
+            metrics: metrics.clone(),
+            cache: Some(cache.clone()),
+            client_timeout: timeouts.client,
+            session_budget: timeouts.session,
+            session: None,
+        };
+        let server = tokio::spawn(async move { client_handler.handle_client().await });
+
+        let (rx, tx) = client.split();
+        let mut reader = FramedRead::new(rx, codec());
+        let mut writer = FramedWrite::new(tx, codec());
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        // Keys of the mock authority are made of 1, so the score is always 128
+        let vector: [u8; NILSIMSA_VECTOR_SIZE_BITS] = array::from_fn(|i| (i % 2 == 0) as u8);
+
+        let mut read_request = async || {
+            let frame = timeout(Duration::from_secs(30), reader.next())
+                .await
+                .expect("The server stalled")
+                .unwrap()
+                .unwrap();
+            postcard::from_bytes::<EncryptionRequest<NILSIMSA_VECTOR_SIZE_BITS, i16>>(&frame)
+                .unwrap()
+        };
+
+        // No comparison can complete while the cache is locked
+        let guard = cache.lock().unwrap();
+        for _ in 0..2 {
+            let pk = read_request().await.pk.unwrap();
+            let fingerprint = key_fingerprint(&pk).unwrap();
+            let response =
+                EncryptionResponse::EncryptedVector(pk.encrypt(&mut rng, vector), fingerprint);
+            writer
+                .send(postcard::to_stdvec(&response).unwrap().into())
+                .await
+                .unwrap();
+        }
+
+        // The second key was received while the first batch was still being compared
+        assert_eq!(metrics.batches(), 0);
+        drop(guard);
+
+        let end = read_request().await;
+        assert!(end.is_end());
+        assert_eq!(end.similarity_score, Some(128));
+        assert_eq!(metrics.batches(), 2);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_budget() {
+        let authority = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let authority_addr = authority.local_addr().unwrap().to_string();
+        let requests = Requests::default();
+        tokio::spawn(keying_authority(authority, requests.clone()));
+
+        // Three batches of two hashes, the client hash is only in the last one
+        let client: [u8; 32] = array::from_fn(|i| (i * 13 + 5) as u8);
+        let mut corpus = [[0u8; 32], client, client, [0xff; 32], [0x0f; 32], client];
+        corpus[1][0] ^= 0xff;
+        corpus[2][7] ^= 0b11;
+        let hashes = corpus.iter().map(|h| FHVector::from(*h)).collect();
+        let timeouts = Timeouts {
+            session: Some(Duration::ZERO),
+            ..Timeouts::default()
+        };
+        let batches = KeyBatches::new(authority_addr, hashes, timeouts.authority)
+            .with_batch_size(2)
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::new(0));
+        let handler_metrics = metrics.clone();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut client_handler = ClientHandler {
+                stream: Framed::new(stream, codec()),
+                hash_type: HashComparisonRequest::NILSIMSA,
+                batches,
+                metrics: handler_metrics,
+                cache: None,
+                client_timeout: timeouts.client,
+                session_budget: timeouts.session,
+                session: None,
+            };
+            client_handler.handle_client().await
+        });
+
+        // The comparison is finalized after the first batch, with its best score
+        let vector = FHVector::from(client).to_fe_vector().unwrap();
+        let expected = fuzzy_hashes::nilsimsa_compare(&corpus[1], &client)
+            .max(fuzzy_hashes::nilsimsa_compare(&corpus[0], &client));
+        assert!(expected < 128);
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let (rx, tx) = stream.split();
+        let mut reader = FramedRead::new(rx, codec());
+        let mut writer = FramedWrite::new(tx, codec());
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let mut score = None;
+        while let Some(frame) = reader.next().await {
+            let request: EncryptionRequest<NILSIMSA_VECTOR_SIZE_BITS, i16> =
+                postcard::from_bytes(&frame.unwrap()).unwrap();
+            let Some(pk) = request.pk else {
+                score = request.similarity_score;
+                break;
+            };
+            let fingerprint = key_fingerprint(&pk).unwrap();
+            let response =
+                EncryptionResponse::EncryptedVector(pk.encrypt(&mut rng, vector), fingerprint);
+            writer
+                .send(postcard::to_stdvec(&response).unwrap().into())
+                .await
+                .unwrap();
+        }
+        server.await.unwrap().unwrap();
+
+        assert_eq!(score, Some(expected));
+        assert_eq!(metrics.batches(), 1);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_key_rejected() {
+        let authority_addr = spawn_mock_authority(Requests::default()).await;
+
+        // Corpus of two batches of a single key
+        let hashes = (0..2u8).map(|i| FHVector::from([i; 32])).collect();
+        let timeouts = Timeouts::default();
+        let batches = KeyBatches::new(authority_addr, hashes, timeouts.authority)
+            .with_batch_size(1)
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let mut client_handler = ClientHandler {
+            stream: Framed::new(stream, codec()),
+            hash_type: HashComparisonRequest::NILSIMSA,
+            batches,
+            metrics: Arc::new(Metrics::new(0)),
+            cache: None,
+            client_timeout: timeouts.client,
+            session_budget: timeouts.session,
+            session: None,
+        };
+        let server = tokio::spawn(async move { client_handler.handle_client().await });
+
+        let (rx, tx) = client.split();
+        let mut reader = FramedRead::new(rx, codec());
+        let mut writer = FramedWrite::new(tx, codec());
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let vector: [u8; NILSIMSA_VECTOR_SIZE_BITS] = array::from_fn(|i| (i % 2 == 0) as u8);
+
+        // Answer every batch with a ciphertext under the first key
+        let mut stale_pk = None;
+        for _ in 0..2 {
+            let frame = reader.next().await.unwrap().unwrap();
+            let request =
+                postcard::from_bytes::<EncryptionRequest<NILSIMSA_VECTOR_SIZE_BITS, i16>>(&frame)
+                    .unwrap();
+            let pk = stale_pk.get_or_insert(request.pk.unwrap());
+            let fingerprint = key_fingerprint(pk).unwrap();
+            let response =
+                EncryptionResponse::EncryptedVector(pk.encrypt(&mut rng, vector), fingerprint);
+            writer
+                .send(postcard::to_stdvec(&response).unwrap().into())
+                .await
+                .unwrap();
+        }
+
+        let err = server.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("another public key"));
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::Result;
+use fuzzy_hashes::FHVector;
+use log::info;
+use rusqlite::Connection;
+use std::sync::{Arc, Mutex};
+
+use crate::compute_server::{MalformedRows, get_nilsimsa_hashes};
+use crate::prefilter::PreFilter;
+
+/// Version of the database, it changes on any commit (of this connection or of another one).
+type DbVersion = (i64, u64);
+
+/// Fuzzy hashes read from the database, shared by the clients.
+pub type Hashes = Arc<[FHVector<u8>]>;
+
+/// Fuzzy hashes of the corpus, read from the database on the blocking thread pool. The
+/// whole corpus is shared by the clients until the database changes, while the fuzzy hashes
+/// of a tag are read for each client. The reads are serialized, so that clients asking for
+/// the corpus at the same time share a single read.
+#[derive(Debug, Clone)]
+pub struct Corpus(Arc<Mutex<CorpusState>>);
+
+#[derive(Debug)]
+struct CorpusState {
+    db_connection: Connection,
+    prefilter: Option<PreFilter>,
+    malformed_rows: MalformedRows,
+    // Fuzzy hashes (after the pre-filter) of the whole corpus, and the version of the
+    // database they were read from
+    cached: Option<(DbVersion, Hashes)>,
+    reads: usize,
+}
+
+impl Corpus {
+    /// Corpus of the whole database, a malformed fuzzy hash fails the read by default.
+    pub fn new(db_connection: Connection) -> Self {
+        Self(Arc::new(Mutex::new(CorpusState {
+            db_connection,
+            prefilter: None,
+            malformed_rows: MalformedRows::Fail,
+            cached: None,
+            reads: 0,
+        })))
+    }
+
+    /// Only keep the fuzzy hashes of the database that pass the pre-filter.
+    pub fn with_prefilter(self, prefilter: PreFilter) -> Self {
+        let mut state = self.0.lock().unwrap();
+        state.prefilter = Some(prefilter);
+        state.cached = None;
+        drop(state);
+        self
+    }
+
+    /// What to do with the malformed fuzzy hashes of the database.
+    pub fn with_malformed_rows(self, malformed_rows: MalformedRows) -> Self {
+        let mut state = self.0.lock().unwrap();
+        state.malformed_rows = malformed_rows;
+        state.cached = None;
+        drop(state);
+        self
+    }
+
+    /// Fuzzy hashes of the corpus (or those with the given tag) to compare to, without
+    /// blocking the runtime.
+    pub async fn load(&self, tag: Option<String>) -> Result<Hashes> {
+        let corpus = self.clone();
+        tokio::task::spawn_blocking(move || corpus.read(tag.as_deref())).await?
+    }
+
+    /// Same as load, blocking until the fuzzy hashes are read (e.g at startup).
+    pub fn read(&self, tag: Option<&str>) -> Result<Hashes> {
+        self.0.lock().unwrap().read(tag)
+    }
+
+    /// Number of times the database was actually read.
+    #[cfg(test)]
+    pub fn reads(&self) -> usize {
+        self.0.lock().unwrap().reads
+    }
+}
+
+impl CorpusState {
+    fn read(&mut self, tag: Option<&str>) -> Result<Hashes> {
+        // The tags come from the clients, caching the fuzzy hashes of each of them would let
+        // any client grow the memory of the server
+        if tag.is_some() {
+            return self.read_db(tag);
+        }
+
+        let version = (
+            self.db_connection
+                .query_row("PRAGMA data_version", [], |row| row.get(0))?,
+            self.db_connection.total_changes(),
+        );
+        if let Some((cached_version, hashes)) = &self.cached
+            && *cached_version == version
+        {
+            return Ok(hashes.clone());
+        }
+
+        let hashes = self.read_db(None)?;
+        self.cached = Some((version, hashes.clone()));
+        Ok(hashes)
+    }
+
+    fn read_db(&mut self, tag: Option<&str>) -> Result<Hashes> {
+        let mut hashes = get_nilsimsa_hashes(&self.db_connection, tag, self.malformed_rows)?;
+        self.reads += 1;
+        info!("Loaded {} fuzzy hashes", hashes.len());
+
+        if let Some(prefilter) = &self.prefilter {
+            prefilter.apply(&mut hashes);
+            info!("{} fuzzy hashes left after pre-filtering", hashes.len());
+        }
+
+        Ok(hashes.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(db: &Connection, hash: [u8; 32]) {
+        db.execute(
+            "INSERT INTO fuzzy_hashes(fh, type) VALUES (?1, 'nilsimsa')",
+            (hash,),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_invalidated_on_change() {
+        let path = std::env::temp_dir().join(format!("corpus_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let writer = Connection::open(&path).unwrap();
+        writer
+            .execute(
+                "CREATE TABLE fuzzy_hashes(fh BLOB PRIMARY KEY, type TEXT)",
+                (),
+            )
+            .unwrap();
+        insert(&writer, [1; 32]);
+
+        let corpus = Corpus::new(Connection::open(&path).unwrap());
+        assert_eq!(corpus.read(None).unwrap().len(), 1);
+        assert_eq!(corpus.read(None).unwrap().len(), 1);
+        assert_eq!(corpus.reads(), 1);
+
+        // Committed by another connection (e.g a script populating the database)
+        insert(&writer, [2; 32]);
+        assert_eq!(corpus.read(None).unwrap().len(), 2);
+        assert_eq!(corpus.reads(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tags_not_cached() {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute(
+            "CREATE TABLE fuzzy_hashes(fh BLOB PRIMARY KEY, type TEXT, tag TEXT)",
+            (),
+        )
+        .unwrap();
+        db.execute(
+            "INSERT INTO fuzzy_hashes VALUES (?1, 'nilsimsa', 'a')",
+            ([1u8; 32],),
+        )
+        .unwrap();
+
+        let corpus = Corpus::new(db);
+        assert_eq!(corpus.read(Some("a")).unwrap().len(), 1);
+        assert_eq!(corpus.read(Some("a")).unwrap().len(), 1);
+        assert!(corpus.read(Some("b")).unwrap().is_empty());
+        assert_eq!(corpus.reads(), 3);
+        assert!(corpus.0.lock().unwrap().cached.is_none());
+    }
+}
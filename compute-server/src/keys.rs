@@ -0,0 +1,608 @@
+use anyhow::{Result, anyhow};
+use fe::{CompressedSecretKey, PublicKey, SecretKey};
+use futures::FutureExt;
+use futures::SinkExt;
+use futures::StreamExt;
+use fuzzy_hashes::FHVector;
+use log::info;
+use messages::{
+    AuthorityRequest, AuthorityResponse, GenerateInstanceResponse, codec, decode_versioned,
+    encode_versioned, received,
+};
+use sha3::{Digest, Sha3_256};
+use std::future::Future;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+
+use crate::corpus::Hashes;
+use crate::metrics::PhaseTimer;
+
+/// Lazily retrieve the keys associated to the fuzzy hashes of the corpus from the authority,
+/// one batch at a time. This way, only the keys of the batch being compared are kept in memory
+/// instead of the keys of the whole corpus.
+///
+/// Each batch comes from a fresh instance, so the client has to encrypt its vector once per
+/// batch. A ciphertext must not be reused across batches : compared to N keys (or more) of
+/// the same instance, the inner products would reveal the client vector.
+pub struct KeyBatches<const N: usize> {
+    authority_addr: String,
+    hashes: Hashes,
+    batch_size: usize,
+    next: usize,
+    authority_timeout: Duration,
+    // Keys loaded from disk, used instead of querying the authority
+    precomputed: Option<Arc<PrecomputedKeys<N>>>,
+    // Whether the authority is asked to stream the secret keys
+    streamed: bool,
+}
+
+impl<const N: usize> KeyBatches<N>
+where
+    GenerateInstanceResponse<N>: TryFrom<AuthorityResponse, Error = anyhow::Error>,
+{
+    /// An instance can't derive secret keys for more than N - 1 vectors
+    pub const MAX_BATCH_SIZE: usize = N - 1;
+
+    pub fn new(authority_addr: String, hashes: Hashes, authority_timeout: Duration) -> Self {
+        Self {
+            authority_addr,
+            hashes,
+            batch_size: Self::MAX_BATCH_SIZE,
+            next: 0,
+            authority_timeout,
+            precomputed: None,
+            streamed: false,
+        }
+    }
+
+    /// Serve the batches of keys loaded from disk instead of querying the authority.
+    pub fn from_precomputed(keys: Arc<PrecomputedKeys<N>>) -> Self {
+        Self {
+            authority_addr: String::new(),
+            hashes: Arc::new([]),
+            batch_size: Self::MAX_BATCH_SIZE,
+            next: 0,
+            authority_timeout: Duration::ZERO,
+            precomputed: Some(keys),
+            streamed: false,
+        }
+    }
+
+    /// Check that a batch size can be used with vectors of size N.
+    pub fn check_batch_size(batch_size: usize) -> Result<()> {
+        if batch_size == 0 || batch_size > Self::MAX_BATCH_SIZE {
+            return Err(anyhow!(
+                "The batch size must be between 1 and {}, got {}",
+                Self::MAX_BATCH_SIZE,
+                batch_size
+            ));
+        }
+        Ok(())
+    }
+
+    /// Request the keys of at most `batch_size` fuzzy hashes at once from the authority,
+    /// instead of the maximum allowed by the vector size.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Result<Self> {
+        Self::check_batch_size(batch_size)?;
+        self.batch_size = batch_size;
+        Ok(self)
+    }
+
+    /// Ask the authority to stream the secret keys of each batch, so that they are
+    /// decompressed as they arrive instead of once the whole batch is derived.
+    pub fn with_streamed_keys(mut self) -> Self {
+        self.streamed = true;
+        self
+    }
+
+    /// Query the authority for the keys of the next batch of fuzzy hashes,
+    /// return None once the whole corpus has been processed.
+    pub async fn next_batch(&mut self) -> Result<Option<(PublicKey<N>, Vec<SecretKey<N>>)>> {
+        if let Some(keys) = &self.precomputed {
+            let Some(response) = keys.batches.get(self.next) else {
+                return Ok(None);
+            };
+            self.next += 1;
+            return Ok(Some(decompress(response)?));
+        }
+
+        if self.streamed {
+            // The keys are decompressed while they are received
+            let timer = PhaseTimer::start("authority round-trip (streamed keys)");
+            let batch = self.next_streamed_batch().await?;
+            timer.finish();
+            return Ok(batch);
+        }
+
+        let timer = PhaseTimer::start("authority round-trip");
+        let response = self.next_compressed_batch().await?;
+        timer.finish();
+        match response {
+            Some(response) => Ok(Some(decompress(&response)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Same as next_batch, but the keys are returned as sent by the authority.
+    async fn next_compressed_batch(&mut self) -> Result<Option<GenerateInstanceResponse<N>>> {
+        let Some(range) = self.next_range() else {
+            return Ok(None);
+        };
+        let requested = range.len();
+        let compressed_response = self
+            .with_timeout(retrieve_secret_keys::<N>(
+                &self.authority_addr,
+                &self.hashes[range],
+            ))
+            .await?;
+
+        // Each secret key must match a fuzzy hash of the batch, otherwise the
+        // comparisons would be misaligned with the corpus
+        if compressed_response.1.len() != requested {
+            return Err(anyhow!(
+                "The authority returned {} secret keys for {} vectors, abort",
+                compressed_response.1.len(),
+                requested
+            ));
+        }
+
+        Ok(Some(compressed_response))
+    }
+
+    /// Same as next_batch, but the authority streams the secret keys.
+    async fn next_streamed_batch(&mut self) -> Result<Option<(PublicKey<N>, Vec<SecretKey<N>>)>> {
+        let Some(range) = self.next_range() else {
+            return Ok(None);
+        };
+        let keys = self
+            .with_timeout(stream_secret_keys::<N>(
+                &self.authority_addr,
+                &self.hashes[range],
+            ))
+            .await?;
+        Ok(Some(keys))
+    }
+
+    /// Fuzzy hashes of the next batch, None once the whole corpus has been processed.
+    fn next_range(&mut self) -> Option<Range<usize>> {
+        if self.next >= self.hashes.len() {
+            return None;
+        }
+
+        let end = usize::min(self.next + self.batch_size, self.hashes.len());
+        let range = self.next..end;
+        self.next = end;
+        Some(range)
+    }
+
+    /// Give up on the authority if it doesn't answer in time.
+    fn with_timeout<T>(
+        &self,
+        request: impl Future<Output = Result<T>>,
+    ) -> impl Future<Output = Result<T>> {
+        let limit = self.authority_timeout;
+        timeout(limit, request).map(move |response| match response {
+            Ok(response) => response,
+            Err(_) => Err(anyhow!(
+                "The authority did not answer within {:?}, abort",
+                limit
+            )),
+        })
+    }
+}
+
+fn decompress<const N: usize>(
+    response: &GenerateInstanceResponse<N>,
+) -> Result<(PublicKey<N>, Vec<SecretKey<N>>)> {
+    let timer = PhaseTimer::start("decompression of a batch");
+    let decompressed = match response.decompress() {
+        Ok(keys) => keys,
+        _ => return Err(anyhow!("Unable to retrieve vectors from authority")),
+    };
+    timer.finish();
+    Ok(decompressed)
+}
+
+/// Digest of the fuzzy hashes of a corpus, in the order of the corpus.
+pub type CorpusDigest = [u8; 32];
+
+/// SHA3-256 digest of the serialized fuzzy hashes.
+fn corpus_digest(hashes: &[FHVector<u8>]) -> Result<CorpusDigest> {
+    Ok(Sha3_256::digest(postcard::to_stdvec(hashes)?).into())
+}
+
+/// Keys of every batch of a corpus, retrieved once from the authority and stored on disk,
+/// so that a fixed corpus can be served without querying the authority for each client.
+#[derive(Debug)]
+pub struct PrecomputedKeys<const N: usize> {
+    // Digest of the corpus the keys were derived for
+    corpus: CorpusDigest,
+    batches: Vec<GenerateInstanceResponse<N>>,
+}
+
+impl<const N: usize> PrecomputedKeys<N>
+where
+    GenerateInstanceResponse<N>: TryFrom<AuthorityResponse, Error = anyhow::Error>,
+{
+    /// Retrieve the keys of all the batches from the authority.
+    pub async fn fetch(mut batches: KeyBatches<N>) -> Result<Self> {
+        let corpus = corpus_digest(&batches.hashes)?;
+        let mut responses = vec![];
+        while let Some(response) = batches.next_compressed_batch().await? {
+            responses.push(response);
+        }
+        Ok(Self {
+            corpus,
+            batches: responses,
+        })
+    }
+
+    /// Number of fuzzy hashes the keys were derived for.
+    pub fn corpus_size(&self) -> usize {
+        self.batches.iter().map(|response| response.1.len()).sum()
+    }
+
+    /// Check that the keys were derived for these fuzzy hashes, in this order (the keys are
+    /// matched with the fuzzy hashes by position).
+    pub fn check_corpus(&self, hashes: &[FHVector<u8>]) -> Result<()> {
+        if self.corpus_size() != hashes.len() {
+            return Err(anyhow!(
+                "The keys were exported for {} fuzzy hashes, but the corpus has {}",
+                self.corpus_size(),
+                hashes.len()
+            ));
+        }
+        if self.corpus != corpus_digest(hashes)? {
+            return Err(anyhow!(
+                "The keys were exported for other fuzzy hashes than those of the corpus"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Write the keys to a file, tagged with the protocol version.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, encode_versioned(&(self.corpus, &self.batches))?)?;
+        Ok(())
+    }
+
+    /// Load keys written by save, every key is checked so that a corrupted file is
+    /// rejected at startup rather than while serving a client.
+    pub fn load(path: &Path) -> Result<Self> {
+        let (corpus, batches): (CorpusDigest, Vec<GenerateInstanceResponse<N>>) =
+            decode_versioned(&std::fs::read(path)?)?;
+        for response in &batches {
+            // A single instance can't be used for N vectors (or more)
+            if response.1.len() > KeyBatches::<N>::MAX_BATCH_SIZE {
+                return Err(anyhow!("Too many secret keys for a single instance, abort"));
+            }
+            decompress(response)?;
+        }
+        Ok(Self { corpus, batches })
+    }
+}
+
+async fn retrieve_secret_keys<const N: usize>(
+    authority_addr: &str,
+    vectors: &[FHVector<u8>],
+) -> Result<GenerateInstanceResponse<N>>
+where
+    GenerateInstanceResponse<N>: TryFrom<AuthorityResponse, Error = anyhow::Error>,
+{
+    let mut authority_stream = Framed::new(TcpStream::connect(authority_addr).await?, codec());
+    info!("Connection opened with authority");
+
+    let serialized = encode_versioned(&AuthorityRequest::Generate(vectors.to_vec()))?;
+    authority_stream.send(serialized.into()).await?;
+    info!("Sended vectors to authority");
+
+    let frame = received(authority_stream.next().await)?;
+
+    let resp: AuthorityResponse = postcard::from_bytes(&frame)?;
+
+    GenerateInstanceResponse::<N>::try_from(resp)
+}
+
+/// Same as retrieve_secret_keys, with the secret keys streamed by the authority : each one
+/// is decompressed as soon as it is received, while the authority derives the next ones.
+async fn stream_secret_keys<const N: usize>(
+    authority_addr: &str,
+    vectors: &[FHVector<u8>],
+) -> Result<(PublicKey<N>, Vec<SecretKey<N>>)>
+where
+    GenerateInstanceResponse<N>: TryFrom<AuthorityResponse, Error = anyhow::Error>,
+{
+    let mut authority_stream = Framed::new(TcpStream::connect(authority_addr).await?, codec());
+    info!("Connection opened with authority");
+
+    let serialized = encode_versioned(&AuthorityRequest::Stream(vectors.to_vec()))?;
+    authority_stream.send(serialized.into()).await?;
+    info!("Sended vectors to authority");
+
+    // The public key comes first, without any secret key
+    let frame = received(authority_stream.next().await)?;
+    let resp: AuthorityResponse = postcard::from_bytes(&frame)?;
+    let header = GenerateInstanceResponse::<N>::try_from(resp)?;
+    if !header.1.is_empty() {
+        return Err(anyhow!(
+            "The authority sent secret keys along the public key, abort"
+        ));
+    }
+
+    // Each secret key must match a fuzzy hash of the batch, see next_compressed_batch
+    let mut sks = Vec::with_capacity(vectors.len());
+    while sks.len() < vectors.len() {
+        let Some(frame) = authority_stream.next().await else {
+            return Err(anyhow!(
+                "The authority returned {} secret keys for {} vectors, abort",
+                sks.len(),
+                vectors.len()
+            ));
+        };
+        let compressed: CompressedSecretKey = postcard::from_bytes(&frame?)?;
+        match SecretKey::<N>::try_from(&compressed) {
+            Ok(sk) => sks.push(sk),
+            Err(_) => return Err(anyhow!("Unable to retrieve vectors from authority")),
+        }
+    }
+
+    Ok((header.0, sks))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use comparator::Comparator;
+    use fe::Instance;
+    use fe::traits::{FEInstance, FEPubKey};
+    use fuzzy_hashes::NILSIMSA_VECTOR_SIZE_BITS as N;
+    use rand::SeedableRng;
+    use rand::rngs::{StdRng, SysRng};
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Vectors received by the mock authority, one entry per request.
+    pub(crate) type Requests = Arc<Mutex<Vec<Vec<FHVector<u8>>>>>;
+
+    /// Keys derived like the authority does, without querying it : a single batch for the
+    /// given fuzzy hashes.
+    pub(crate) fn precomputed_keys(hashes: &[FHVector<u8>]) -> Arc<PrecomputedKeys<N>> {
+        let instance = Instance::<N>::setup();
+        let sks = hashes
+            .iter()
+            .map(|hash| instance.secret_key(hash.to_fe_vector::<N>().unwrap()))
+            .collect();
+        let response = GenerateInstanceResponse::from((instance.public_key(), sks));
+        Arc::new(PrecomputedKeys {
+            corpus: corpus_digest(hashes).unwrap(),
+            batches: vec![response],
+        })
+    }
+
+    /// Mock authority that answers any request with fresh keys (omitting the given number
+    /// of keys), and records the received requests.
+    pub(crate) async fn mock_authority(listener: TcpListener, requests: Requests, missing: usize) {
+        authority(listener, requests, missing, |instance, _| {
+            instance.secret_key([1u8; N])
+        })
+        .await
+    }
+
+    /// Mock authority that derives the keys of the received vectors, like the real one.
+    pub(crate) async fn keying_authority(listener: TcpListener, requests: Requests) {
+        authority(listener, requests, 0, |instance, vector| {
+            instance.secret_key(vector.to_fe_vector::<N>().unwrap())
+        })
+        .await
+    }
+
+    async fn authority(
+        listener: TcpListener,
+        requests: Requests,
+        missing: usize,
+        secret_key: fn(&Instance<N>, &FHVector<u8>) -> SecretKey<N>,
+    ) {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+
+            let mut stream = Framed::new(stream, codec());
+            let frame = stream.next().await.unwrap().unwrap();
+            let request: AuthorityRequest = messages::decode_versioned(&frame).unwrap();
+            let vectors = request.vectors().clone();
+            requests.lock().unwrap().push(vectors.clone());
+
+            let frames = {
+                let instance = Instance::<N>::setup();
+                let pk = instance.public_key();
+                let sks: Vec<SecretKey<N>> = vectors
+                    .iter()
+                    .skip(missing)
+                    .map(|vector| secret_key(&instance, vector))
+                    .collect();
+                match request {
+                    AuthorityRequest::Generate(_) => {
+                        let response =
+                            AuthorityResponse::from(GenerateInstanceResponse::from((pk, sks)));
+                        vec![postcard::to_stdvec(&response).unwrap()]
+                    }
+                    AuthorityRequest::Stream(_) => {
+                        let header = AuthorityResponse::from(GenerateInstanceResponse(pk, vec![]));
+                        let mut frames = vec![postcard::to_stdvec(&header).unwrap()];
+                        frames.extend(sks.iter().map(|sk| {
+                            postcard::to_stdvec(&CompressedSecretKey::from(sk)).unwrap()
+                        }));
+                        frames
+                    }
+                }
+            };
+
+            for frame in frames {
+                stream.send(frame.into()).await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batches_are_retrieved_lazily() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let requests = Requests::default();
+        tokio::spawn(mock_authority(listener, requests.clone(), 0));
+
+        // Simulated corpus of 3 full batches and a partial one
+        let corpus_size = 3 * (N - 1) + 7;
+        let hashes = (0..corpus_size)
+            .map(|i| FHVector::from([i as u8; 32]))
+            .collect();
+        let mut batches = KeyBatches::<N>::new(addr, hashes, TIMEOUT);
+
+        let mut n_batches = 0;
+        let mut n_keys = 0;
+        while let Some((_, sks)) = batches.next_batch().await.unwrap() {
+            n_batches += 1;
+            n_keys += sks.len();
+
+            // Only the keys of the current batch are in memory, and the authority
+            // has not been queried for the next batches yet
+            assert!(sks.len() < N);
+            assert_eq!(requests.lock().unwrap().len(), n_batches);
+        }
+
+        assert_eq!(n_batches, 4);
+        assert_eq!(n_keys, corpus_size);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_keys() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let requests = Requests::default();
+        tokio::spawn(keying_authority(listener, requests.clone()));
+
+        // A full batch and a partial one
+        let corpus_size = N + 2;
+        let hashes: Vec<_> = (0..corpus_size)
+            .map(|i| {
+                let mut hash = [0u8; 32];
+                hash[..2].copy_from_slice(&(i as u16).to_le_bytes());
+                FHVector::from(hash)
+            })
+            .collect();
+        let mut batches =
+            KeyBatches::<N>::new(addr, hashes.clone().into(), TIMEOUT).with_streamed_keys();
+
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let mut n_keys = 0;
+        while let Some((pk, sks)) = batches.next_batch().await.unwrap() {
+            // The keys are in the order of the corpus : only the key of the encrypted
+            // hash (the second of the batch) gives the max score
+            let hash = &hashes[n_keys + 1];
+            let ct = pk.encrypt(&mut rng, hash.to_fe_vector::<N>().unwrap());
+            let scores: Vec<i16> = sks.iter().map(|sk| sk.compare(ct.clone())).collect();
+            assert_eq!(scores[1], 128);
+            assert_eq!(scores.iter().filter(|score| **score == 128).count(), 1);
+            n_keys += sks.len();
+        }
+        assert_eq!(n_keys, corpus_size);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_keys_missing() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(mock_authority(listener, Requests::default(), 2));
+
+        let hashes = (0..5).map(|i| FHVector::from([i as u8; 32])).collect();
+        let mut batches = KeyBatches::<N>::new(addr, hashes, TIMEOUT).with_streamed_keys();
+        let error = batches.next_batch().await.unwrap_err();
+        assert!(
+            error.to_string().contains("3 secret keys for 5 vectors"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn test_smaller_batch_size() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let requests = Requests::default();
+        tokio::spawn(mock_authority(listener, requests.clone(), 0));
+
+        let corpus_size = 2 * (N - 1) + 7;
+        let hashes: Vec<_> = (0..corpus_size)
+            .map(|i| FHVector::from(std::array::from_fn(|j| (i + j) as u8)))
+            .collect();
+        let mut batches = KeyBatches::<N>::new(addr, hashes.clone().into(), TIMEOUT)
+            .with_batch_size(100)
+            .unwrap();
+
+        let mut sizes = vec![];
+        while let Some((_, sks)) = batches.next_batch().await.unwrap() {
+            sizes.push(sks.len());
+        }
+        assert_eq!(sizes.len(), corpus_size.div_ceil(100));
+        assert!(sizes.iter().all(|&size| size <= 100));
+
+        // The batches sent to the authority put back together are the corpus
+        let received: Vec<_> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .flatten()
+            .map(|vector| vector.to_fe_vector::<N>().unwrap())
+            .collect();
+        let expected: Vec<_> = hashes
+            .iter()
+            .map(|vector| vector.to_fe_vector::<N>().unwrap())
+            .collect();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_batch_size_bounds() {
+        assert!(KeyBatches::<N>::check_batch_size(0).is_err());
+        assert!(KeyBatches::<N>::check_batch_size(N).is_err());
+        assert!(KeyBatches::<N>::check_batch_size(N - 1).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_missing_keys_are_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(mock_authority(listener, Requests::default(), 1));
+
+        let hashes = (0..5).map(|i| FHVector::from([i as u8; 32])).collect();
+        let mut batches = KeyBatches::<N>::new(addr, hashes, TIMEOUT);
+
+        let error = batches.next_batch().await.unwrap_err();
+        assert!(error.to_string().contains("4 secret keys for 5 vectors"));
+    }
+
+    #[tokio::test]
+    async fn test_authority_timeout() {
+        // Authority accepting the connection but never answering
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut streams = vec![];
+            loop {
+                streams.push(listener.accept().await.unwrap());
+            }
+        });
+
+        let hashes = (0..5).map(|i| FHVector::from([i as u8; 32])).collect();
+        let mut batches = KeyBatches::<N>::new(addr, hashes, Duration::from_millis(100));
+
+        let error = batches.next_batch().await.unwrap_err();
+        assert!(error.to_string().contains("did not answer"));
+    }
+}
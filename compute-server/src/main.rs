@@ -1,10 +1,21 @@
+mod cache;
 mod compute_server;
-use crate::compute_server::Server;
+mod corpus;
+mod keys;
+mod metrics;
+mod prefilter;
+use crate::cache::DecryptionCache;
+use crate::compute_server::{CorpusCap, MalformedRows, Server, Timeouts};
+use crate::keys::PrecomputedKeys;
+use crate::metrics::Metrics;
+use crate::prefilter::PreFilter;
 
 use anyhow::Result;
 use clap::Parser;
 use log::info;
 use rusqlite::Connection;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::TcpListener;
 
 #[derive(Parser)]
@@ -14,6 +25,59 @@ struct Cli {
     db_path: std::path::PathBuf,
     #[clap(long, short, action)]
     populate_db: bool,
+    /// Similarity score above which a comparison is counted as a match in the metrics
+    #[clap(long, default_value_t = 64, allow_negative_numbers = true)]
+    match_threshold: i16,
+    /// Opt-in plaintext pre-filter : hex encoded Nilsimsa digest used as a coarse reference.
+    /// Corpus entries too dissimilar from it are not compared. This weakens privacy.
+    #[clap(long, value_parser = fuzzy_hashes::parse_digest_hex, requires = "prefilter_cutoff")]
+    prefilter_reference: Option<[u8; 32]>,
+    /// Minimum Nilsimsa score with the pre-filter reference for a corpus entry to be compared
+    #[clap(long, allow_negative_numbers = true, requires = "prefilter_reference")]
+    prefilter_cutoff: Option<i16>,
+    /// Capacity of the cache of the comparison results (disabled by default). It is bypassed
+    /// with --bind-sessions, as the ciphertexts of a session can't be replayed
+    #[clap(long)]
+    decryption_cache: Option<usize>,
+    /// What to do with malformed fuzzy hashes in the database
+    #[clap(long, value_enum, default_value_t = MalformedRows::Skip)]
+    malformed_rows: MalformedRows,
+    /// Seconds to wait for the authority to answer a request of secret keys
+    #[clap(long, default_value_t = Timeouts::default().authority.as_secs())]
+    authority_timeout: u64,
+    /// Seconds to wait for each frame sent by a client
+    #[clap(long, default_value_t = Timeouts::default().client.as_secs())]
+    client_timeout: u64,
+    /// Seconds a client may spend comparing its fuzzy hash, it then gets the best score
+    /// found so far (no limit by default)
+    #[clap(long)]
+    session_budget: Option<u64>,
+    /// Require the ciphertexts of the clients to be bound to their session, so that a
+    /// recorded ciphertext can't be replayed in another session
+    #[clap(long)]
+    bind_sessions: bool,
+    /// Ask the authority to stream the secret keys, one frame per key
+    #[clap(long)]
+    stream_keys: bool,
+    /// Compare each client to at most this number of fuzzy hashes of the corpus (no limit by
+    /// default). The scores are approximate : a match with the other hashes is missed
+    #[clap(long, conflicts_with = "keys")]
+    max_corpus: Option<usize>,
+    /// Compare each client to a random sample of the corpus instead of its first hashes
+    #[clap(long, requires = "max_corpus")]
+    sample_corpus: bool,
+    /// Number of fuzzy hashes whose keys are requested at once from the authority
+    /// (at most the vector size minus one, which is the default)
+    #[clap(long)]
+    batch_size: Option<usize>,
+    /// Serve the comparisons to the whole corpus with the keys exported to this file,
+    /// instead of querying the authority for each client
+    #[clap(long)]
+    keys: Option<std::path::PathBuf>,
+    /// Retrieve the keys of the whole corpus from the authority, write them to this file
+    /// (to be used with --keys) and exit
+    #[clap(long, conflicts_with = "keys")]
+    export_keys: Option<std::path::PathBuf>,
 }
 
 #[tokio::main]
@@ -39,7 +103,41 @@ async fn main() -> Result<()> {
         Err(e) => panic!("Unable to bind {} : {}", &args.bind, e),
     };
 
-    let mut server = Server::new(socket, ct_connection, args.authority_addr);
+    let metrics = Arc::new(Metrics::new(args.match_threshold));
+    let mut server = Server::new(socket, ct_connection, args.authority_addr, metrics)
+        .with_malformed_rows(args.malformed_rows)
+        .with_timeouts(Timeouts {
+            authority: Duration::from_secs(args.authority_timeout),
+            client: Duration::from_secs(args.client_timeout),
+            session: args.session_budget.map(Duration::from_secs),
+        });
+    if let Some((reference, cutoff)) = args.prefilter_reference.zip(args.prefilter_cutoff) {
+        server = server.with_prefilter(PreFilter::new(reference, cutoff));
+    }
+    if let Some(capacity) = args.decryption_cache {
+        server = server.with_cache(Arc::new(Mutex::new(DecryptionCache::new(capacity))));
+    }
+    if args.stream_keys {
+        server = server.with_streamed_keys();
+    }
+    if let Some(max) = args.max_corpus {
+        server = server.with_corpus_cap(CorpusCap {
+            max,
+            sampled: args.sample_corpus,
+        })?;
+    }
+    if args.bind_sessions {
+        server = server.with_session_binding();
+    }
+    if let Some(batch_size) = args.batch_size {
+        server = server.with_batch_size(batch_size)?;
+    }
+    if let Some(path) = args.export_keys {
+        return server.export_keys(&path).await;
+    }
+    if let Some(path) = args.keys {
+        server = server.with_precomputed_keys(PrecomputedKeys::load(&path)?)?;
+    }
     server.run().await?;
     Ok(())
 }
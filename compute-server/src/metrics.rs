@@ -0,0 +1,139 @@
+use log::{Level, debug, log_enabled};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Counters about the work done by the compute server, shared by all the client handlers.
+/// Relaxed atomics are enough since the counters are only used for monitoring.
+#[derive(Debug)]
+pub struct Metrics {
+    comparisons: AtomicU64,
+    matches: AtomicU64,
+    batches: AtomicU64,
+    match_threshold: i16,
+}
+
+impl Metrics {
+    /// Comparisons with a score greater or equal to `match_threshold` are counted as matches.
+    pub fn new(match_threshold: i16) -> Self {
+        Self {
+            comparisons: AtomicU64::new(0),
+            matches: AtomicU64::new(0),
+            batches: AtomicU64::new(0),
+            match_threshold,
+        }
+    }
+
+    /// Record a single comparison and its similarity score.
+    pub fn record_comparison(&self, score: i16) {
+        self.comparisons.fetch_add(1, Ordering::Relaxed);
+        if score >= self.match_threshold {
+            self.matches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Record that a whole batch of the corpus has been processed.
+    pub fn record_batch(&self) {
+        self.batches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Total number of comparisons performed.
+    pub fn comparisons(&self) -> u64 {
+        self.comparisons.load(Ordering::Relaxed)
+    }
+
+    /// Number of comparisons with a score above the match threshold.
+    pub fn matches(&self) -> u64 {
+        self.matches.load(Ordering::Relaxed)
+    }
+
+    /// Number of batches processed.
+    pub fn batches(&self) -> u64 {
+        self.batches.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} comparisons, {} matches (score >= {}), {} batches",
+            self.comparisons(),
+            self.matches(),
+            self.match_threshold,
+            self.batches()
+        )
+    }
+}
+
+/// Duration of a phase of the handling of a client (e.g the comparison of a batch), logged
+/// at debug level once the phase is finished. The clock is not even read when debug logs
+/// are disabled.
+pub struct PhaseTimer<F = fn(&'static str, Duration)> {
+    phase: &'static str,
+    start: Option<Instant>,
+    // Where the duration is reported
+    sink: F,
+}
+
+impl PhaseTimer {
+    pub fn start(phase: &'static str) -> Self {
+        Self::reporting_to(phase, log_enabled!(Level::Debug), |phase, elapsed| {
+            debug!("Timing : {} took {:?}", phase, elapsed)
+        })
+    }
+}
+
+impl<F: FnOnce(&'static str, Duration)> PhaseTimer<F> {
+    /// Time the phase, its duration is given to the sink once finished (only if enabled).
+    pub fn reporting_to(phase: &'static str, enabled: bool, sink: F) -> Self {
+        Self {
+            phase,
+            start: enabled.then(Instant::now),
+            sink,
+        }
+    }
+
+    pub fn finish(self) {
+        if let Some(start) = self.start {
+            (self.sink)(self.phase, start.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        let metrics = Metrics::new(50);
+
+        for score in [-128, 0, 49, 50, 128] {
+            metrics.record_comparison(score);
+        }
+        metrics.record_batch();
+
+        assert_eq!(metrics.comparisons(), 5);
+        assert_eq!(metrics.matches(), 2);
+        assert_eq!(metrics.batches(), 1);
+    }
+
+    #[test]
+    fn test_phase_timer() {
+        let mut reported = None;
+        let timer = PhaseTimer::reporting_to("comparison of a batch", true, |phase, elapsed| {
+            reported = Some((phase, elapsed))
+        });
+        std::thread::sleep(Duration::from_millis(10));
+        timer.finish();
+
+        let (phase, elapsed) = reported.unwrap();
+        assert_eq!(phase, "comparison of a batch");
+        assert!(elapsed >= Duration::from_millis(10));
+
+        // Nothing is reported by a disabled timer
+        let timer = PhaseTimer::reporting_to("corpus read", false, |_, _| panic!("Reported"));
+        timer.finish();
+    }
+}
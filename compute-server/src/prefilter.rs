@@ -0,0 +1,57 @@
+use fuzzy_hashes::{FHVector, NILSIMSA_FH_SIZE_BYTES, nilsimsa_compare};
+
+/// Optional plaintext pre-filter of the corpus. Corpus entries whose Nilsimsa similarity
+/// with a coarse reference hash is below the cutoff are skipped, so that no secret key
+/// is requested to the authority for them.
+///
+/// **Warning** : this weakens privacy, since the set of compared fuzzy hashes (and thus
+/// the number of batches seen by the client) now depends on the reference, and the
+/// corpus entries are compared in plaintext. This is why it is disabled by default.
+#[derive(Debug, Clone)]
+pub struct PreFilter {
+    reference: [u8; NILSIMSA_FH_SIZE_BYTES],
+    cutoff: i16,
+}
+
+impl PreFilter {
+    pub fn new(reference: [u8; NILSIMSA_FH_SIZE_BYTES], cutoff: i16) -> Self {
+        Self { reference, cutoff }
+    }
+
+    /// Return true if the fuzzy hash is similar enough to the reference to be compared.
+    pub fn keep(&self, hash: &FHVector<u8>) -> bool {
+        match hash.nilsimsa_digest() {
+            Some(digest) => nilsimsa_compare(&self.reference, &digest) >= self.cutoff,
+            // The reference is a Nilsimsa digest, other vectors are left untouched
+            None => true,
+        }
+    }
+
+    /// Remove the fuzzy hashes that are too dissimilar from the reference.
+    pub fn apply(&self, hashes: &mut Vec<FHVector<u8>>) {
+        hashes.retain(|hash| self.keep(hash));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefilter() {
+        let reference = [0u8; NILSIMSA_FH_SIZE_BYTES];
+        let mut far = [0u8; NILSIMSA_FH_SIZE_BYTES];
+        far[..10].fill(0xff);
+        let mut close = [0u8; NILSIMSA_FH_SIZE_BYTES];
+        close[0] = 0x0f;
+
+        // Score of far is 128 - 80, score of close is 128 - 4
+        let mut hashes = vec![FHVector::from(far), FHVector::from(close)];
+        PreFilter::new(reference, 100).apply(&mut hashes);
+
+        assert_eq!(hashes.len(), 1);
+        assert!(PreFilter::new(reference, 100).keep(&FHVector::from(close)));
+        assert!(!PreFilter::new(reference, 100).keep(&FHVector::from(far)));
+        assert!(PreFilter::new(reference, 48).keep(&FHVector::from(far)));
+    }
+}
@@ -0,0 +1,80 @@
+//! Big integers of the finite-field backend : malachite (feature `malachite`) or num-bigint
+//! (feature `num-bigint`). Only the few operations needed by the scheme are wrapped, so that
+//! ff_fe.rs is the same for both libraries.
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "num-bigint")] {
+        use rand::{Rng, SeedableRng, rngs::StdRng};
+
+        /// Big unsigned integer used for the group elements and the scalars.
+        pub type Natural = num_bigint::BigUint;
+
+        /// Build an integer from its 64 bits limbs, most significant first.
+        pub(crate) fn from_limbs_desc(limbs: &[u64]) -> Natural {
+            let bytes: Vec<u8> = limbs.iter().flat_map(|limb| limb.to_be_bytes()).collect();
+            Natural::from_bytes_be(&bytes)
+        }
+
+        /// base^exp mod m
+        pub(crate) fn mod_pow(base: &Natural, exp: &Natural, m: &Natural) -> Natural {
+            base.modpow(exp, m)
+        }
+
+        /// a * b mod m
+        pub(crate) fn mod_mul(a: &Natural, b: &Natural, m: &Natural) -> Natural {
+            (a * b) % m
+        }
+
+        /// Stream of integers uniformly drawn from [low, high), derived from the given seed.
+        pub(crate) fn uniform_range(
+            seed: [u8; 32],
+            low: Natural,
+            high: Natural,
+        ) -> impl Iterator<Item = Natural> {
+            let mut rng = StdRng::from_seed(seed);
+            let range = high - &low;
+            let bits = range.bits();
+            let mut bytes = vec![0u8; bits.div_ceil(8) as usize];
+            // Rejection sampling of bits-long integers, less than half of them are rejected
+            std::iter::repeat_with(move || loop {
+                rng.fill_bytes(&mut bytes);
+                bytes[0] &= 0xff >> (bytes.len() as u64 * 8 - bits);
+                let value = Natural::from_bytes_be(&bytes);
+                if value < range {
+                    return &low + value;
+                }
+            })
+        }
+    } else if #[cfg(feature = "malachite")] {
+        use malachite::base::num::arithmetic::traits::{ModMul, ModPow};
+        use malachite::base::random::Seed;
+        use malachite::natural::random;
+
+        /// Big unsigned integer used for the group elements and the scalars.
+        pub type Natural = malachite::natural::Natural;
+
+        /// Build an integer from its 64 bits limbs, most significant first.
+        pub(crate) fn from_limbs_desc(limbs: &[u64]) -> Natural {
+            Natural::from_limbs_desc(limbs)
+        }
+
+        /// base^exp mod m
+        pub(crate) fn mod_pow(base: &Natural, exp: &Natural, m: &Natural) -> Natural {
+            base.mod_pow(exp, m)
+        }
+
+        /// a * b mod m
+        pub(crate) fn mod_mul(a: &Natural, b: &Natural, m: &Natural) -> Natural {
+            a.mod_mul(b, m)
+        }
+
+        /// Stream of integers uniformly drawn from [low, high), derived from the given seed.
+        pub(crate) fn uniform_range(
+            seed: [u8; 32],
+            low: Natural,
+            high: Natural,
+        ) -> impl Iterator<Item = Natural> {
+            random::uniform_random_natural_range(Seed::from_bytes(seed), low, high)
+        }
+    }
+}
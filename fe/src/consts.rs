@@ -1,5 +1,3 @@
-use malachite::natural::Natural;
-
 //https://www.ietf.org/rfc/rfc3526.txt
 pub(crate) const DH15_PRIME_LIMBS: [u64; 48] = [
     0xFFFFFFFFFFFFFFFF,
@@ -51,5 +49,3 @@ pub(crate) const DH15_PRIME_LIMBS: [u64; 48] = [
     0x4B82D120A93AD2CA,
     0xFFFFFFFFFFFFFFFF,
 ];
-
-pub(crate) const CST2: Natural = Natural::const_from(2);
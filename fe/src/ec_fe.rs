@@ -1,7 +1,8 @@
 #![allow(dead_code)]
 use core::array;
+use std::collections::HashMap;
+use std::sync::Arc;
 
-use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
 use curve25519_dalek::scalar::Scalar;
 use curve25519_dalek::traits::{Identity, MultiscalarMul};
 use rand::{
@@ -9,52 +10,107 @@ use rand::{
     rngs::{StdRng, SysRng},
 };
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_512};
+
+use crate::error::FeError;
 use crate::generic::{
     CompressedDdhFeSecretKey, DdhFeCiphertext, DdhFeInstance, DdhFePublicKey, DdhFeSecretKey,
-    MskItem,
+    MskItem, boxed_array,
 };
 use crate::traits::{FECipherText, FEInstance, FEPubKey, FESecretKey};
 
+// Group used by the backend : Ristretto255 by default, or the Edwards form of Curve25519
+// (feature `edwards`, e.g for interoperability with Ed25519 based systems).
+cfg_if::cfg_if! {
+    if #[cfg(feature = "edwards")] {
+        use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+        use curve25519_dalek::edwards::CompressedEdwardsY as CompressedPoint;
+        /// Point of the elliptic curve group used by the FE scheme.
+        pub type Point = curve25519_dalek::edwards::EdwardsPoint;
+
+        /// Random point of the prime order subgroup.
+        fn random_point<R: CryptoRng + ?Sized>(rng: &mut R) -> Point {
+            Scalar::random(rng) * ED25519_BASEPOINT_POINT
+        }
+
+        /// Points of a public key must be in the prime order subgroup, otherwise the
+        /// small order component would leak information about the encrypted vector.
+        fn is_valid_point(p: &Point) -> bool {
+            *p != Point::identity() && p.is_torsion_free()
+        }
+    } else {
+        use curve25519_dalek::ristretto::CompressedRistretto as CompressedPoint;
+        /// Point of the elliptic curve group used by the FE scheme.
+        pub type Point = curve25519_dalek::ristretto::RistrettoPoint;
+
+        fn random_point<R: CryptoRng + ?Sized>(rng: &mut R) -> Point {
+            Point::random(rng)
+        }
+
+        fn is_valid_point(p: &Point) -> bool {
+            *p != Point::identity()
+        }
+    }
+}
+
 /*
     Type aliases (shared by both ec_fe.rs and ff_fe.rs)
 */
-/// FE instance over Ristretto255 curve for arbitrary vector size.
-pub type Instance<const N: usize> = DdhFeInstance<N, Scalar, RistrettoPoint>;
-/// FE public key over Ristretto255 curve for arbitrary vector size.
-pub type PublicKey<const N: usize> = DdhFePublicKey<N, RistrettoPoint>;
-/// FE secret key over Ristretto255 curve for arbitrary vector size.
-pub type SecretKey<const N: usize> = DdhFeSecretKey<N, Scalar, RistrettoPoint>;
-/// FE compressed secret key over Ristretto255 curve for arbitrary vector size. This is done to
+/// FE instance over the elliptic curve group for arbitrary vector size.
+pub type Instance<const N: usize> = DdhFeInstance<N, Scalar, Point>;
+/// FE public key over the elliptic curve group for arbitrary vector size.
+pub type PublicKey<const N: usize> = DdhFePublicKey<N, Point>;
+/// FE secret key over the elliptic curve group for arbitrary vector size.
+pub type SecretKey<const N: usize> = DdhFeSecretKey<N, Scalar, Point>;
+/// FE compressed secret key over the elliptic curve group for arbitrary vector size. This is done to
 /// (greatly) improve the efficiency of the network transmission of the secret key structure.
-pub type CompressedSecretKey = CompressedDdhFeSecretKey<Scalar, CompressedRistretto, u8>;
-/// FE ciphertext over Ristretto255 curve for arbitrary vector size.
-pub type CipherText<const N: usize> = DdhFeCiphertext<N, RistrettoPoint>;
+pub type CompressedSecretKey = CompressedDdhFeSecretKey<Scalar, CompressedPoint, CompressedVector>;
+/// FE ciphertext over the elliptic curve group for arbitrary vector size.
+pub type CipherText<const N: usize> = DdhFeCiphertext<N, Point>;
+
+/// Vector of a compressed secret key. Binary vectors (e.g fuzzy hashes) are packed
+/// as bits, while any other vector (e.g weights) is kept as scalars.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum CompressedVector {
+    /// Bits of the vector, most significant bit first
+    Binary(Vec<u8>),
+    /// Scalars of the vector
+    Scalars(Vec<Scalar>),
+}
 
 /// Implementation of From and TryFrom to allow easy compression/decompression
 /// between a CompressedSecretKey and a SecretKey
 impl<const N: usize> From<&SecretKey<N>> for CompressedSecretKey {
     fn from(value: &SecretKey<N>) -> CompressedSecretKey {
         let g = value.g.compress();
-        let x_bytes: Vec<u8> = value
+        let is_binary = value
             .x
-            .map(|b| {
-                if b.eq(&Scalar::ZERO) {
-                    0
-                } else if b.eq(&Scalar::ONE) {
-                    1
-                } else {
-                    panic!("Cannot serialize vector");
-                }
-            })
-            .chunks_exact(8)
-            .map(|b| (0..8).map(|i| b[i] * (1 << (7 - i))).sum())
-            .collect();
+            .iter()
+            .all(|b| *b == Scalar::ZERO || *b == Scalar::ONE);
+
+        let x = if is_binary {
+            CompressedVector::Binary(
+                value
+                    .x
+                    .map(|b| (b == Scalar::ONE) as u8)
+                    // The last chunk may be shorter than 8 bits when N is not a multiple of 8,
+                    // the missing bits are then left to 0 (i.e padding).
+                    .chunks(8)
+                    .map(|b| b.iter().enumerate().map(|(i, bit)| bit << (7 - i)).sum())
+                    .collect(),
+            )
+        } else {
+            CompressedVector::Scalars(value.x.to_vec())
+        };
 
         CompressedSecretKey {
             g,
             sx: value.sx,
             tx: value.tx,
-            x: x_bytes,
+            x,
         }
     }
 }
@@ -65,18 +121,38 @@ impl<const N: usize> TryFrom<&CompressedSecretKey> for SecretKey<N> {
     type Error = ();
 
     fn try_from(value: &CompressedSecretKey) -> Result<Self, Self::Error> {
-        if value.x.len() != N / 8 {
-            return Err(());
-        }
+        let x: [Scalar; N] = match &value.x {
+            CompressedVector::Binary(bits) => {
+                // The last byte holds the remaining N % 8 bits (if any), e.g a truncated
+                // vector or one of another size is rejected
+                if bits.len() != N.div_ceil(8) {
+                    return Err(());
+                }
+
+                let mut expanded = bits
+                    .iter()
+                    .flat_map(|byte| (0..8).map(move |i| 1 & (byte >> (7 - i))));
+
+                let mut x = [Scalar::ZERO; N];
+                for scalar in x.iter_mut() {
+                    *scalar = Scalar::from(expanded.next().ok_or(())?);
+                }
+
+                // The padding bits of the last byte must be unset
+                if expanded.any(|bit| bit != 0) {
+                    return Err(());
+                }
+
+                x
+            }
+            CompressedVector::Scalars(scalars) => scalars.as_slice().try_into().map_err(|_| ())?,
+        };
 
         let g = match value.g.decompress() {
             Some(p) => p,
             None => return Err(()),
         };
 
-        let x: [Scalar; N] =
-            array::from_fn(|i| Scalar::from(1 & (value.x[i / 8] >> (7 - (i % 8)))));
-
         Ok(SecretKey {
             g,
             sx: value.sx,
@@ -86,6 +162,159 @@ impl<const N: usize> TryFrom<&CompressedSecretKey> for SecretKey<N> {
     }
 }
 
+impl<const N: usize> SecretKey<N> {
+    /// Decrypt the given ciphertext to a signed inner product (e.g when the vectors have
+    /// negative entries), see `DecryptContext::decrypt_signed`.
+    pub fn decrypt_signed(&self, ct: impl FECipherText<Point>, bound: i16) -> Option<i16> {
+        self.decrypt_context().decrypt_signed(ct, bound)
+    }
+
+    /// Prepare the decryption of several ciphertexts with this secret key.
+    pub fn decrypt_context(&self) -> DecryptContext<N> {
+        DecryptContext {
+            g: self.g,
+            scalars: self
+                .x
+                .iter()
+                .chain(&[-self.sx, -self.tx])
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Serialize the secret key to a compact binary blob (e.g to store it on disk), using the
+    /// CompressedSecretKey encoding.
+    #[cfg(feature = "serde")]
+    pub fn to_compact_bytes(&self) -> Vec<u8> {
+        postcard::to_stdvec(&CompressedSecretKey::from(self))
+            .expect("Unable to serialize the secret key")
+    }
+
+    /// Load a secret key from a blob returned by to_compact_bytes, the blob is rejected
+    /// if it is corrupted or if it doesn't match the vector size.
+    #[cfg(feature = "serde")]
+    pub fn from_compact_bytes(bytes: &[u8]) -> Result<Self, FeError> {
+        match postcard::take_from_bytes::<CompressedSecretKey>(bytes) {
+            Ok((compressed, [])) => {
+                SecretKey::try_from(&compressed).map_err(|_| FeError::InvalidSecretKey)
+            }
+            _ => Err(FeError::InvalidSecretKey),
+        }
+    }
+}
+
+impl<const N: usize> PublicKey<N> {
+    /// Ensure that the public key (e.g received from an untrusted source) does not contain
+    /// the identity point (or a point out of the prime order subgroup with the `edwards`
+    /// feature), which would weaken the encryption. Non canonical encodings of the points
+    /// are already rejected when deserializing the key.
+    pub fn validate(&self) -> Result<(), FeError> {
+        if !self
+            .mpk
+            .iter()
+            .chain([&self.g, &self.h])
+            .all(is_valid_point)
+        {
+            return Err(FeError::InvalidPublicKey);
+        }
+
+        Ok(())
+    }
+}
+
+impl<const N: usize> PublicKey<N> {
+    /// Encrypt the given vector, each element being mapped to a scalar using the given closure
+    /// (e.g for signed or scaled encodings).
+    pub fn encrypt_with<R: CryptoRng + ?Sized, T, F: Fn(T) -> Scalar>(
+        &self,
+        rng: &mut R,
+        vector: [T; N],
+        f: F,
+    ) -> CipherText<N> {
+        self.encrypt_scalars(rng, vector.map(f))
+    }
+
+    /// Encrypt the given vector like `FEPubKey::encrypt`, along with a proof that the
+    /// ciphertext was computed for the given context (e.g a session nonce). The proof can't
+    /// be computed for another context without the randomness of the encryption, so a
+    /// recorded ciphertext can't be replayed in another context.
+    pub fn encrypt_bound<R: CryptoRng + ?Sized, T>(
+        &self,
+        rng: &mut R,
+        vector: [T; N],
+        context: &[u8],
+    ) -> (CipherText<N>, EncryptionProof)
+    where
+        Scalar: From<T>,
+    {
+        let r = Scalar::random(rng);
+        let ct = self.encrypt_with_randomness(r, vector.map(Scalar::from));
+
+        // Schnorr proof of knowledge of r such that c = r * g
+        let k = Scalar::random(rng);
+        let commitment = k * self.g;
+        let challenge = self.proof_challenge(&ct, &commitment, context);
+        let proof = EncryptionProof {
+            commitment,
+            response: k + challenge * r,
+        };
+
+        (ct, proof)
+    }
+
+    /// Check that the proof was computed along the ciphertext for the given context.
+    pub fn verify_bound(
+        &self,
+        ct: &CipherText<N>,
+        proof: &EncryptionProof,
+        context: &[u8],
+    ) -> Result<(), FeError> {
+        let challenge = self.proof_challenge(ct, &proof.commitment, context);
+        if proof.response * self.g != proof.commitment + challenge * ct.c {
+            return Err(FeError::InvalidProof);
+        }
+
+        Ok(())
+    }
+
+    /// Fiat-Shamir challenge of the proof, it covers the whole ciphertext so that the proof
+    /// can't be attached to another one.
+    fn proof_challenge(&self, ct: &CipherText<N>, commitment: &Point, context: &[u8]) -> Scalar {
+        let mut hasher = Sha3_512::new();
+        hasher.update(b"fe-encryption-proof");
+        hasher.update((context.len() as u64).to_le_bytes());
+        hasher.update(context);
+        for p in [&self.g, &self.h, commitment, &ct.c, &ct.d]
+            .into_iter()
+            .chain(ct.e.iter())
+        {
+            hasher.update(p.compress().as_bytes());
+        }
+        Scalar::from_bytes_mod_order_wide(&hasher.finalize().into())
+    }
+
+    fn encrypt_scalars<R: CryptoRng + ?Sized>(&self, rng: &mut R, x: [Scalar; N]) -> CipherText<N> {
+        self.encrypt_with_randomness(Scalar::random(rng), x)
+    }
+
+    fn encrypt_with_randomness(&self, r: Scalar, x: [Scalar; N]) -> CipherText<N> {
+        let c = r * self.g;
+        let d = r * self.h;
+        let e = boxed_array(|i| x[i] * self.g + r * self.mpk[i]);
+
+        DdhFeCiphertext { c, d, e }
+    }
+}
+
+/// Proof sent along a ciphertext that it was computed for a given context, see
+/// `PublicKey::encrypt_bound`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EncryptionProof {
+    commitment: Point,
+    response: Scalar,
+}
+
 // Useful to get a random master secret key element
 impl MskItem<Scalar> {
     pub(crate) fn get_rand<R: CryptoRng + ?Sized>(rng: &mut R) -> Self {
@@ -99,109 +328,109 @@ impl MskItem<Scalar> {
 /*
     Implements traits defined in traits.rs
 */
-impl<const N: usize> FEInstance<N, RistrettoPoint, Scalar> for Instance<N> {
+impl<const N: usize> FEInstance<N, Point, Scalar> for Instance<N> {
     fn setup() -> Self {
         // CS-PRNG
         let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
 
         // Init parameters
-        let g = RistrettoPoint::random(&mut rng);
-        let h = RistrettoPoint::random(&mut rng);
+        let g = random_point(&mut rng);
+        let h = random_point(&mut rng);
 
         // Init MSK/MPK
         let msk: [MskItem<Scalar>; N] = array::from_fn(|_i| MskItem::get_rand(&mut rng));
-        let mpk: [RistrettoPoint; N] = array::from_fn(|i| msk[i].s * g + msk[i].t * h);
+        let mpk = boxed_array(|i| msk[i].s * g + msk[i].t * h);
 
-        DdhFeInstance { g, h, msk, mpk }
+        DdhFeInstance {
+            msk,
+            pk: Arc::new(DdhFePublicKey { g, h, mpk }),
+        }
     }
 
-    fn secret_key<T: Copy>(&self, vector: [T; N]) -> SecretKey<N>
-    where
-        Scalar: From<T>,
-    {
-        let scal = self
-            .msk
-            .iter()
-            .zip(vector)
-            .map(|(e_i, v_i)| {
-                (
-                    e_i.s * <Scalar as From<T>>::from(v_i),
-                    e_i.t * <Scalar as From<T>>::from(v_i),
-                )
-            })
-            .reduce(|acc, e| (acc.0 + e.0, acc.1 + e.1))
-            .unwrap();
+    fn secret_key_with<T, F: Fn(T) -> Scalar>(&self, vector: [T; N], f: F) -> SecretKey<N> {
+        let x = vector.map(f);
+        let is_binary = x.iter().all(|b| *b == Scalar::ZERO || *b == Scalar::ONE);
+        let scal = if is_binary {
+            // Bit vectors (e.g fuzzy hashes) : the products are either 0 or the MSK element,
+            // so they are skipped or added as is
+            self.msk
+                .iter()
+                .zip(&x)
+                .filter(|(_, x_i)| **x_i == Scalar::ONE)
+                .fold((Scalar::ZERO, Scalar::ZERO), |acc, (e_i, _)| {
+                    (acc.0 + e_i.s, acc.1 + e_i.t)
+                })
+        } else {
+            self.msk
+                .iter()
+                .zip(&x)
+                .map(|(e_i, x_i)| (e_i.s * x_i, e_i.t * x_i))
+                .reduce(|acc, e| (acc.0 + e.0, acc.1 + e.1))
+                .unwrap()
+        };
 
         DdhFeSecretKey {
-            g: self.g,
+            g: self.pk.g,
             sx: scal.0,
             tx: scal.1,
-            x: array::from_fn(|i| Scalar::from(vector[i])),
+            x,
         }
     }
 
-    fn public_key<T: Copy>(&self) -> PublicKey<N>
-    where
-        Scalar: From<T>,
-    {
-        DdhFePublicKey {
-            g: self.g,
-            h: self.h,
-            mpk: self.mpk,
-        }
+    fn public_key(&self) -> PublicKey<N> {
+        self.pk.as_ref().clone()
     }
 }
 
-impl<const N: usize, T> FEPubKey<N, T, RistrettoPoint> for PublicKey<N>
+impl<const N: usize, T> FEPubKey<N, T, Point> for PublicKey<N>
 where
     Scalar: std::convert::From<T>,
     T: Copy,
 {
-    fn encrypt<R: CryptoRng + ?Sized>(&self, rng: &mut R, vector: [T; N]) -> CipherText<N> {
-        let r = Scalar::random(rng);
-
-        let c = r * self.g;
-        let d = r * self.h;
-        let e: [RistrettoPoint; N] =
-            array::from_fn(|i| Scalar::from(vector[i]) * self.g + r * self.mpk[i]);
+    fn encrypt_ref<R: CryptoRng + ?Sized>(&self, rng: &mut R, vector: &[T; N]) -> CipherText<N> {
+        self.encrypt_scalars(rng, array::from_fn(|i| Scalar::from(vector[i])))
+    }
 
-        DdhFeCiphertext { c, d, e }
+    fn encrypt_with_seed(&self, seed: [u8; 32], vector: [T; N]) -> CipherText<N> {
+        self.encrypt(&mut StdRng::from_seed(seed), vector)
     }
 }
 
-impl<const N: usize> FECipherText<RistrettoPoint> for CipherText<N> {
-    fn get_c(&self) -> RistrettoPoint {
+impl<const N: usize> FECipherText<Point> for CipherText<N> {
+    fn get_c(&self) -> Point {
         self.c
     }
-    fn get_d(&self) -> RistrettoPoint {
+    fn get_d(&self) -> Point {
         self.d
     }
-    fn get_e(&self) -> &[RistrettoPoint] {
-        &self.e
+    fn get_e(&self) -> &[Point] {
+        self.e.as_slice()
     }
 }
 
-impl<const N: usize> FESecretKey<N, RistrettoPoint, u16> for SecretKey<N> {
-    fn decrypt(&self, ct: impl FECipherText<RistrettoPoint>, bound: u16) -> Option<u16> {
-        let scalars: Vec<_> = self
-            .x
-            .iter()
-            .chain(&[-self.sx, -self.tx])
-            .cloned()
-            .collect();
-        let points: Vec<_> = ct
-            .get_e()
-            .iter()
-            .chain(&[ct.get_c(), ct.get_d()])
-            .cloned()
-            .collect();
+impl<const N: usize> FESecretKey<N, Point, u16> for SecretKey<N> {
+    fn decrypt(&self, ct: impl FECipherText<Point>, bound: u16) -> Option<u16> {
+        self.decrypt_context().decrypt(ct, bound)
+    }
+}
+
+/// Scalars of a secret key prepared once, so that a stream of ciphertexts can be
+/// decrypted using the same secret key with no allocation for each ciphertext.
+#[derive(Debug, Clone)]
+pub struct DecryptContext<const N: usize> {
+    g: Point,
+    // x || -sx || -tx
+    scalars: Vec<Scalar>,
+}
 
-        // Compute sum(E * xi) - C * sx - D * tx
-        let ex = RistrettoPoint::multiscalar_mul(scalars, points);
+impl<const N: usize> DecryptContext<N> {
+    /// Decrypt the given ciphertext (i.e compute an inner product), same as `FESecretKey::decrypt`.
+    pub fn decrypt(&self, ct: impl FECipherText<Point>, bound: u16) -> Option<u16> {
+        let ex = self.inner_product_point(&ct);
 
         // BF to retrieve scalar product value
         let mut i = 0;
-        let mut p = RistrettoPoint::identity();
+        let mut p = Point::identity();
         while i != bound && p != ex {
             i += 1;
             p += self.g
@@ -209,4 +438,164 @@ impl<const N: usize> FESecretKey<N, RistrettoPoint, u16> for SecretKey<N> {
 
         if i == bound { None } else { Some(i) }
     }
+
+    /// Decrypt the given ciphertext to a signed inner product (e.g when the vectors have
+    /// negative entries), same as `SecretKey::decrypt_signed`. The absolute value of the
+    /// inner product has to be less than the bound.
+    pub fn decrypt_signed(&self, ct: impl FECipherText<Point>, bound: i16) -> Option<i16> {
+        let ex = self.inner_product_point(&ct);
+        let neg_ex = -ex;
+
+        // BF on both sides of 0
+        let mut i = 0;
+        let mut p = Point::identity();
+        while i < bound {
+            if p == ex {
+                return Some(i);
+            }
+            if p == neg_ex {
+                return Some(-i);
+            }
+            i += 1;
+            p += self.g
+        }
+
+        None
+    }
+
+    /// Decrypt the given ciphertext like `decrypt`, but use a precomputed table (e.g for
+    /// large bounds) instead of brute forcing the inner product. Return None if the table
+    /// was built for another generator, or if the inner product exceeds its bound.
+    pub fn decrypt_with_table(
+        &self,
+        ct: impl FECipherText<Point>,
+        table: &DlogTable,
+    ) -> Option<u16> {
+        if table.g != self.g {
+            return None;
+        }
+        table.dlog(&self.inner_product_point(&ct))
+    }
+
+    /// Compute sum(E * xi) - C * sx - D * tx, i.e g^<x, y>
+    fn inner_product_point(&self, ct: &impl FECipherText<Point>) -> Point {
+        Point::multiscalar_mul(
+            &self.scalars,
+            ct.get_e().iter().chain(&[ct.get_c(), ct.get_d()]),
+        )
+    }
+}
+
+/// Baby-step giant-step table computing discrete logarithms in base g up to a bound, i.e
+/// decrypting inner products in O(sqrt(bound)) instead of O(bound). Building it costs
+/// sqrt(bound) point compressions, it can be serialized to be reloaded instead of rebuilt
+/// as long as the generator (i.e the public key) doesn't change.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DlogTable {
+    g: Point,
+    bound: u16,
+    // Compressed j * g -> j, for 0 <= j < number of baby steps
+    baby_steps: HashMap<[u8; 32], u16>,
+}
+
+impl DlogTable {
+    /// Precompute the table for the generator of the given public key, the discrete
+    /// logarithms must be less than the bound.
+    pub fn new<const N: usize>(pk: &PublicKey<N>, bound: u16) -> Self {
+        Self::with_table_size(pk, bound, Self::default_size(bound))
+    }
+
+    /// Same as new, with the given number of baby steps instead of ceil(sqrt(bound)). A larger
+    /// table takes more memory but needs less giant steps for each discrete logarithm, which
+    /// pays off when the table is reused for many decryptions. The hint is clamped between 1
+    /// and the bound.
+    pub fn with_table_size<const N: usize>(
+        pk: &PublicKey<N>,
+        bound: u16,
+        table_size_hint: u16,
+    ) -> Self {
+        let mut baby_steps = HashMap::new();
+        let mut p = Point::identity();
+        for j in 0..table_size_hint.min(bound).max(1) {
+            baby_steps.insert(p.compress().to_bytes(), j);
+            p += pk.g;
+        }
+
+        DlogTable {
+            g: pk.g,
+            bound,
+            baby_steps,
+        }
+    }
+
+    /// Upper bound (exclusive) of the discrete logarithms computed by the table.
+    pub fn bound(&self) -> u16 {
+        self.bound
+    }
+
+    /// Serialize the table (e.g to store it on disk).
+    #[cfg(feature = "serde")]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, postcard::Error> {
+        postcard::to_stdvec(self)
+    }
+
+    /// Load a table serialized with to_bytes. The table is rejected if it is corrupted, or
+    /// if it was not built for the generator of the given public key. Recomputing every baby
+    /// step would cost as much as building the table, so the points of the baby steps are
+    /// only checked by dlog, against the logarithm they give.
+    #[cfg(feature = "serde")]
+    pub fn from_bytes<const N: usize>(bytes: &[u8], pk: &PublicKey<N>) -> Result<Self, FeError> {
+        match postcard::take_from_bytes::<DlogTable>(bytes) {
+            Ok((table, []))
+                if table.g == pk.g
+                    && !table.baby_steps.is_empty()
+                    && table.baby_steps.len() <= table.bound.max(1) as usize
+                    && table.is_consistent() =>
+            {
+                Ok(table)
+            }
+            _ => Err(FeError::InvalidDlogTable),
+        }
+    }
+
+    /// Check that each j appears exactly once for 0 <= j < number of baby steps.
+    #[cfg(feature = "serde")]
+    fn is_consistent(&self) -> bool {
+        let mut seen = vec![false; self.baby_steps.len()];
+        for &j in self.baby_steps.values() {
+            match seen.get_mut(j as usize) {
+                Some(seen) if !*seen => *seen = true,
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Compute the discrete logarithm of p in base g, if it is less than the bound. The
+    /// logarithm is checked against p, a corrupted baby step is skipped rather than trusted.
+    pub fn dlog(&self, p: &Point) -> Option<u16> {
+        let m = self.baby_steps.len() as u32;
+        let giant_step = Scalar::from(m) * self.g;
+
+        // p - i * m * g for each giant step i, until it is a baby step j
+        let mut q = *p;
+        for i in 0..(self.bound as u32).div_ceil(m) {
+            if let Some(j) = self.baby_steps.get(&q.compress().to_bytes()) {
+                let dlog = i * m + *j as u32;
+                if dlog < self.bound as u32 && Scalar::from(dlog) * self.g == *p {
+                    return Some(dlog as u16);
+                }
+            }
+            q -= giant_step;
+        }
+
+        None
+    }
+
+    /// ceil(sqrt(bound)), so that there are as many baby steps as giant steps
+    fn default_size(bound: u16) -> u16 {
+        let root = bound.isqrt();
+        root + (root * root < bound) as u16
+    }
 }
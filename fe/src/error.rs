@@ -0,0 +1,59 @@
+use std::fmt;
+
+/// Errors returned by the functionnal encryption scheme.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FeError {
+    /// The public key contains an invalid group element (e.g the identity element).
+    InvalidPublicKey,
+    /// The serialized secret key is corrupted or doesn't match the vector size.
+    InvalidSecretKey,
+    /// The serialized discrete logarithm table is corrupted or built for another generator.
+    InvalidDlogTable,
+    /// The proof does not match the ciphertext or the context (e.g a replayed ciphertext).
+    InvalidProof,
+}
+
+impl fmt::Display for FeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeError::InvalidPublicKey => {
+                write!(f, "the public key contains an invalid group element")
+            }
+            FeError::InvalidSecretKey => write!(f, "the secret key is invalid"),
+            FeError::InvalidDlogTable => {
+                write!(
+                    f,
+                    "the discrete logarithm table is invalid for this public key"
+                )
+            }
+            FeError::InvalidProof => write!(f, "the encryption proof is invalid"),
+        }
+    }
+}
+
+impl std::error::Error for FeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            FeError::InvalidPublicKey.to_string(),
+            "the public key contains an invalid group element"
+        );
+        assert_eq!(
+            FeError::InvalidSecretKey.to_string(),
+            "the secret key is invalid"
+        );
+        assert_eq!(
+            FeError::InvalidDlogTable.to_string(),
+            "the discrete logarithm table is invalid for this public key"
+        );
+        assert_eq!(
+            FeError::InvalidProof.to_string(),
+            "the encryption proof is invalid"
+        );
+    }
+}
@@ -1,30 +1,31 @@
 #![allow(dead_code)]
 use core::array;
 use std::clone::Clone;
+use std::sync::Arc;
 
-use malachite::base::num::arithmetic::traits::{ModMul, ModMulAssign, ModPow};
-use malachite::base::random::Seed;
-use malachite::natural::Natural;
-use malachite::natural::random::{self, UniformRandomNaturalRange};
 use rand::{
     CryptoRng, RngExt, SeedableRng,
     rngs::{StdRng, SysRng},
 };
 
+pub use crate::bigint::Natural;
+use crate::bigint::{from_limbs_desc, mod_mul, mod_pow, uniform_range};
 use crate::consts;
+use crate::error::FeError;
 use crate::generic::{
     CompressedDdhFeSecretKey, DdhFeCiphertext, DdhFeInstance, DdhFePublicKey, DdhFeSecretKey,
-    MskItem,
+    MskItem, boxed_array,
 };
 use crate::traits::{FECipherText, FEInstance, FEPubKey, FESecretKey};
 
 lazy_static::lazy_static! {
-    static ref DH15_PRIME: Natural = Natural::from_limbs_desc(&consts::DH15_PRIME_LIMBS);
+    static ref DH15_PRIME: Natural = from_limbs_desc(&consts::DH15_PRIME_LIMBS);
+    static ref CST2: Natural = Natural::from(2u8);
 }
 
 // Useful to get a random master secret key element
 impl MskItem<Natural> {
-    pub(crate) fn get_rand(rng: &mut UniformRandomNaturalRange) -> Self {
+    pub(crate) fn get_rand(rng: &mut impl Iterator<Item = Natural>) -> Self {
         MskItem {
             s: rng
                 .next()
@@ -48,7 +49,7 @@ pub type SecretKey<const N: usize> = DdhFeSecretKey<N, Natural, Natural>;
 /// FE compressed secret key over Diffie Hellman group n°15 for arbitrary vector size.
 /// This is just the secret key when working over finite field, but it is implemented
 /// to allow transparent usage when swaping to the elliptic curve based-fe of the crate.
-pub type CompressedSecretKey = CompressedDdhFeSecretKey<Natural, Natural, Natural>;
+pub type CompressedSecretKey = CompressedDdhFeSecretKey<Natural, Natural, Vec<Natural>>;
 /// FE ciphertext over Diffie Hellman group n°15 for arbitrary vector size.
 pub type CipherText<const N: usize> = DdhFeCiphertext<N, Natural>;
 
@@ -84,6 +85,80 @@ impl<const N: usize> TryFrom<&CompressedSecretKey> for SecretKey<N> {
     }
 }
 
+impl<const N: usize> SecretKey<N> {
+    /// Serialize the secret key to a compact binary blob (e.g to store it on disk), using the
+    /// CompressedSecretKey encoding.
+    #[cfg(feature = "serde")]
+    pub fn to_compact_bytes(&self) -> Vec<u8> {
+        postcard::to_stdvec(&CompressedSecretKey::from(self))
+            .expect("Unable to serialize the secret key")
+    }
+
+    /// Load a secret key from a blob returned by to_compact_bytes, the blob is rejected
+    /// if it is corrupted or if it doesn't match the vector size.
+    #[cfg(feature = "serde")]
+    pub fn from_compact_bytes(bytes: &[u8]) -> Result<Self, FeError> {
+        match postcard::take_from_bytes::<CompressedSecretKey>(bytes) {
+            Ok((compressed, [])) => {
+                SecretKey::try_from(&compressed).map_err(|_| FeError::InvalidSecretKey)
+            }
+            _ => Err(FeError::InvalidSecretKey),
+        }
+    }
+}
+
+impl<const N: usize> PublicKey<N> {
+    /// Ensure that the public key (e.g received from an untrusted source) only contains
+    /// elements of the group that are not the identity element, which would weaken the encryption.
+    pub fn validate(&self) -> Result<(), FeError> {
+        if self
+            .mpk
+            .iter()
+            .chain([&self.g, &self.h])
+            .any(|p| *p < *CST2 || *p >= *DH15_PRIME)
+        {
+            return Err(FeError::InvalidPublicKey);
+        }
+
+        Ok(())
+    }
+}
+
+impl<const N: usize> PublicKey<N> {
+    /// Encrypt the given vector, each element being mapped to a scalar using the given closure
+    /// (e.g for signed or scaled encodings).
+    pub fn encrypt_with<R: CryptoRng + ?Sized, T, F: Fn(T) -> Natural>(
+        &self,
+        seeder: &mut R,
+        vector: [T; N],
+        f: F,
+    ) -> CipherText<N> {
+        self.encrypt_natural(array::from_fn(|_| seeder.random::<u8>()), vector.map(f))
+    }
+
+    fn encrypt_natural(&self, seed: [u8; 32], x: [Natural; N]) -> CipherText<N> {
+        let r = random_exponents(seed)
+            .next()
+            .expect("Unable to generate a random value for encryption");
+
+        self.encrypt_with_exponent(&r, x)
+    }
+
+    fn encrypt_with_exponent(&self, r: &Natural, x: [Natural; N]) -> CipherText<N> {
+        let c = mod_pow(&self.g, r, &DH15_PRIME);
+        let d = mod_pow(&self.h, r, &DH15_PRIME);
+        let e = boxed_array(|i| {
+            mod_mul(
+                &mod_pow(&self.g, &x[i], &DH15_PRIME),
+                &mod_pow(&self.mpk[i], r, &DH15_PRIME),
+                &DH15_PRIME,
+            )
+        });
+
+        DdhFeCiphertext { c, d, e }
+    }
+}
+
 /*
     Implements traits defined in traits.rs
 */
@@ -91,8 +166,8 @@ impl<const N: usize> FEInstance<N, Natural, Natural> for Instance<N> {
     fn setup() -> Self {
         // PRNG
         let mut seeder = StdRng::try_from_rng(&mut SysRng).unwrap();
-        let seed = Seed::from_bytes(array::from_fn(|_| seeder.random::<u8>()));
-        let mut rng = random::uniform_random_natural_range(seed, consts::CST2, DH15_PRIME.clone());
+        let seed = array::from_fn(|_| seeder.random::<u8>());
+        let mut rng = uniform_range(seed, CST2.clone(), DH15_PRIME.clone());
 
         // Init parameters
         let g = rng.next().expect("Unable to generate a random generator");
@@ -100,44 +175,39 @@ impl<const N: usize> FEInstance<N, Natural, Natural> for Instance<N> {
 
         // Init MSK/MPK
         let msk: [MskItem<Natural>; N] = array::from_fn(|_i| MskItem::get_rand(&mut rng));
-        let mpk: [Natural; N] = array::from_fn(|i| {
-            g.clone()
-                .mod_pow(&msk[i].s, &*DH15_PRIME)
-                .mod_mul(h.clone().mod_pow(&msk[i].t, &*DH15_PRIME), &*DH15_PRIME)
+        let mpk = boxed_array(|i| {
+            mod_mul(
+                &mod_pow(&g, &msk[i].s, &DH15_PRIME),
+                &mod_pow(&h, &msk[i].t, &DH15_PRIME),
+                &DH15_PRIME,
+            )
         });
 
-        DdhFeInstance { g, h, msk, mpk }
+        DdhFeInstance {
+            msk,
+            pk: Arc::new(DdhFePublicKey { g, h, mpk }),
+        }
     }
 
-    fn secret_key<T: Copy>(&self, vector: [T; N]) -> SecretKey<N>
-    where
-        Natural: From<T>,
-    {
-        let scal = vector
+    fn secret_key_with<T, F: Fn(T) -> Natural>(&self, vector: [T; N], f: F) -> SecretKey<N> {
+        let x = vector.map(f);
+        let scal = x
             .iter()
-            .map(|v_i| Natural::from(*v_i))
             .zip(&self.msk)
-            .map(|(v_i, e_i)| (&e_i.s * &v_i, &e_i.t * v_i))
+            .map(|(x_i, e_i)| (&e_i.s * x_i, &e_i.t * x_i))
             .reduce(|acc, e| (acc.0 + e.0, acc.1 + e.1))
             .unwrap();
 
         DdhFeSecretKey {
-            g: self.g.clone(),
+            g: self.pk.g.clone(),
             sx: scal.0,
             tx: scal.1,
-            x: array::from_fn(|i| Natural::from(vector[i])),
+            x,
         }
     }
 
-    fn public_key<T: Copy>(&self) -> PublicKey<N>
-    where
-        Natural: From<T>,
-    {
-        DdhFePublicKey {
-            g: self.g.clone(),
-            h: self.h.clone(),
-            mpk: self.mpk.clone(),
-        }
+    fn public_key(&self) -> PublicKey<N> {
+        self.pk.as_ref().clone()
     }
 }
 
@@ -146,27 +216,37 @@ where
     Natural: From<T>,
     T: Copy,
 {
-    fn encrypt<R: CryptoRng + ?Sized>(&self, seeder: &mut R, vector: [T; N]) -> CipherText<N> {
-        let seed = Seed::from_bytes(array::from_fn(|_| seeder.random::<u8>()));
-        let mut rng = random::uniform_random_natural_range(seed, consts::CST2, DH15_PRIME.clone());
-
-        let r = rng
-            .next()
-            .expect("Unable to generate a random value for encryption");
+    fn encrypt_ref<R: CryptoRng + ?Sized>(&self, seeder: &mut R, vector: &[T; N]) -> CipherText<N> {
+        self.encrypt_natural(
+            array::from_fn(|_| seeder.random::<u8>()),
+            array::from_fn(|i| Natural::from(vector[i])),
+        )
+    }
 
-        let c = self.g.clone().mod_pow(&r, &*DH15_PRIME);
-        let d = self.h.clone().mod_pow(&r, &*DH15_PRIME);
-        let e: [Natural; N] = array::from_fn(|i| {
-            self.g
-                .clone()
-                .mod_pow(Natural::from(vector[i]), &*DH15_PRIME)
-                .mod_mul(&self.mpk[i].clone().mod_pow(&r, &*DH15_PRIME), &*DH15_PRIME)
-        });
+    fn encrypt_with_seed(&self, seed: [u8; 32], vector: [T; N]) -> CipherText<N> {
+        self.encrypt_natural(seed, vector.map(Natural::from))
+    }
 
-        DdhFeCiphertext { c, d, e }
+    fn encrypt_batch<R: CryptoRng + ?Sized>(
+        &self,
+        seeder: &mut R,
+        vectors: Vec<[T; N]>,
+    ) -> Vec<CipherText<N>> {
+        // A single stream of random exponents is seeded for the whole batch,
+        // and a fresh exponent is drawn from it for each vector
+        vectors
+            .into_iter()
+            .zip(random_exponents(array::from_fn(|_| seeder.random::<u8>())))
+            .map(|(v, r)| self.encrypt_with_exponent(&r, v.map(Natural::from)))
+            .collect()
     }
 }
 
+/// Stream of uniformly random exponents used for encryption, derived from the given seed.
+fn random_exponents(seed: [u8; 32]) -> impl Iterator<Item = Natural> {
+    uniform_range(seed, CST2.clone(), DH15_PRIME.clone())
+}
+
 impl<const N: usize> FECipherText<Natural> for CipherText<N> {
     fn get_c(&self) -> Natural {
         self.c.clone()
@@ -175,32 +255,56 @@ impl<const N: usize> FECipherText<Natural> for CipherText<N> {
         self.d.clone()
     }
     fn get_e(&self) -> &[Natural] {
-        &self.e
+        self.e.as_slice()
     }
 }
 
-impl<const N: usize> FESecretKey<N, Natural, u16> for SecretKey<N> {
-    fn decrypt(&self, ct: impl FECipherText<Natural>, bound: u16) -> Option<u16> {
-        let ex = ct
+impl<const N: usize> SecretKey<N> {
+    /// Compute g^<x, y> from the given ciphertext of y, where x is the vector of the secret key.
+    fn decrypt_group_element(&self, ct: &impl FECipherText<Natural>) -> Natural {
+        let numerator = ct
             .get_e()
             .iter()
-            .zip(self.x.clone())
-            .fold(Natural::const_from(1), |acc, (ei, xi)| {
-                acc.mod_mul(ei.mod_pow(xi, &*DH15_PRIME), &*DH15_PRIME)
-            })
-            .mod_mul(
-                ct.get_c()
-                    .mod_pow(&self.sx, &*DH15_PRIME)
-                    .mod_mul(ct.get_d().mod_pow(&self.tx, &*DH15_PRIME), &*DH15_PRIME)
-                    .mod_pow(&*DH15_PRIME - consts::CST2, &*DH15_PRIME),
-                &*DH15_PRIME,
-            );
+            .zip(&self.x)
+            .fold(Natural::from(1u8), |acc, (ei, xi)| {
+                mod_mul(&acc, &mod_pow(ei, xi, &DH15_PRIME), &DH15_PRIME)
+            });
+        let denominator = mod_mul(
+            &mod_pow(&ct.get_c(), &self.sx, &DH15_PRIME),
+            &mod_pow(&ct.get_d(), &self.tx, &DH15_PRIME),
+            &DH15_PRIME,
+        );
+        // Inverse by Fermat's little theorem
+        let inverse = mod_pow(&denominator, &(&*DH15_PRIME - &*CST2), &DH15_PRIME);
+        mod_mul(&numerator, &inverse, &DH15_PRIME)
+    }
+
+    /// Decrypt the given ciphertext like [FESecretKey::decrypt], but the inner product is
+    /// not limited to an u16 value. Return None if the inner product is greater or equal
+    /// than the given bound.
+    pub fn decrypt_big(&self, ct: impl FECipherText<Natural>, bound: Natural) -> Option<Natural> {
+        let ex = self.decrypt_group_element(&ct);
+
+        let mut i = Natural::from(0u8);
+        let mut p = Natural::from(1u8);
+        while i < bound && p != ex {
+            i += Natural::from(1u8);
+            p = mod_mul(&p, &self.g, &DH15_PRIME);
+        }
+
+        if i == bound { None } else { Some(i) }
+    }
+}
+
+impl<const N: usize> FESecretKey<N, Natural, u16> for SecretKey<N> {
+    fn decrypt(&self, ct: impl FECipherText<Natural>, bound: u16) -> Option<u16> {
+        let ex = self.decrypt_group_element(&ct);
 
         let mut i = 0u16;
         let mut p = Natural::from(1u8);
         while i < bound && p != ex {
             i += 1;
-            p.mod_mul_assign(&self.g, &*DH15_PRIME);
+            p = mod_mul(&p, &self.g, &DH15_PRIME);
         }
 
         if i == bound { None } else { Some(i) }
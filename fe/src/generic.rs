@@ -1,5 +1,8 @@
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "serde")]
 use serde_big_array::BigArray;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub(crate) struct MskItem<T> {
@@ -11,62 +14,159 @@ pub(crate) struct MskItem<T> {
 /// * `N` : size of the vector used in the scheme
 /// * `T` : internal type to represent a vector element/scalar (not necessarily the one given by the user)
 /// * `U` : internal type representing a group element used by the FE scheme
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DdhFeSecretKey<const N: usize, T, U> {
     pub(crate) g: U,
     pub(crate) sx: T,
     pub(crate) tx: T,
-    #[serde(with = "BigArray")]
+    #[cfg_attr(feature = "serde", serde(with = "BigArray"))]
     pub(crate) x: [T; N],
 }
 
 /// Generic structure representing a public key for the FE scheme.
 /// * `U` : internal type representing a group element used by the FE scheme
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DdhFePublicKey<const N: usize, U> {
     pub(crate) g: U,
     pub(crate) h: U,
-    #[serde(with = "BigArray")]
-    pub(crate) mpk: [U; N],
+    #[cfg_attr(feature = "serde", serde(with = "boxed_array"))]
+    pub(crate) mpk: Box<[U; N]>,
 }
 
 /// Generic structure representing a ciphertext for the FE scheme.
 /// * `U` : internal type representing a group element used by the FE scheme
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DdhFeCiphertext<const N: usize, U> {
     pub(crate) c: U,
     pub(crate) d: U,
-    #[serde(with = "BigArray")]
-    pub(crate) e: [U; N],
+    #[cfg_attr(feature = "serde", serde(with = "boxed_array"))]
+    pub(crate) e: Box<[U; N]>,
 }
 
-/// Generic structure representing a secret key for the FE scheme.
+/// Array of N group elements built on the heap, element by element. The public keys and
+/// ciphertexts hold N group elements (80 KiB for N = 512 on Ristretto255), too large to be
+/// moved around on the stack of a thread.
+pub(crate) fn boxed_array<U, const N: usize>(f: impl FnMut(usize) -> U) -> Box<[U; N]> {
+    let elements: Box<[U]> = (0..N).map(f).collect();
+    match elements.try_into() {
+        Ok(array) => array,
+        Err(_) => unreachable!("N elements were collected"),
+    }
+}
+
+/// Serialization of the boxed arrays in the format of BigArray (i.e a tuple, without
+/// length), the elements are deserialized straight to the heap.
+#[cfg(feature = "serde")]
+mod boxed_array {
+    use serde::de::{Error, SeqAccess, Visitor};
+    use serde::ser::SerializeTuple;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::fmt;
+    use std::marker::PhantomData;
+
+    pub fn serialize<S: Serializer, U: Serialize, const N: usize>(
+        array: &[U; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(N)?;
+        for element in array {
+            tuple.serialize_element(element)?;
+        }
+        tuple.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, U: Deserialize<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<Box<[U; N]>, D::Error> {
+        deserializer.deserialize_tuple(N, ArrayVisitor(PhantomData))
+    }
+
+    struct ArrayVisitor<U, const N: usize>(PhantomData<U>);
+
+    impl<'de, U: Deserialize<'de>, const N: usize> Visitor<'de> for ArrayVisitor<U, N> {
+        type Value = Box<[U; N]>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "an array of length {}", N)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut elements = Vec::with_capacity(N);
+            for i in 0..N {
+                match seq.next_element()? {
+                    Some(element) => elements.push(element),
+                    None => return Err(A::Error::invalid_length(i, &self)),
+                }
+            }
+            match elements.into_boxed_slice().try_into() {
+                Ok(array) => Ok(array),
+                Err(_) => unreachable!("N elements were deserialized"),
+            }
+        }
+    }
+}
+
+/// Generic structure representing an instance of the FE scheme (i.e the master keys).
 /// * `N` : size of the vector used in the scheme
 /// * `T` : internal type to represent a vector element/scalar (not necessarily the one given by the user)
 /// * `U` : internal type representing a group element used by the FE scheme
+///
+/// The instance (as well as the keys) is `Send + Sync` for both backends, and deriving keys
+/// only requires `&self`, so a single instance can be shared between tasks behind an `Arc`.
 #[derive(Debug, Clone)]
 pub struct DdhFeInstance<const N: usize, T, U> {
-    pub(crate) g: U,
-    pub(crate) h: U,
     pub(crate) msk: [MskItem<T>; N],
-    pub(crate) mpk: [U; N],
+    // Public key (i.e g, h and the MPK) of the instance, computed once at setup
+    pub(crate) pk: Arc<DdhFePublicKey<N, U>>,
+}
+
+impl<const N: usize, T, U> DdhFeInstance<N, T, U> {
+    /// Return the public key of the instance without copying it, unlike
+    /// `FEInstance::public_key` that returns a fresh copy of the N elements of the key.
+    pub fn shared_public_key(&self) -> Arc<DdhFePublicKey<N, U>> {
+        Arc::clone(&self.pk)
+    }
+}
+
+impl<const N: usize, T, U> DdhFeSecretKey<N, T, U> {
+    /// Bound to give to `decrypt` to recover the inner product of any two bit vectors of
+    /// size N (i.e up to N, the bound is exclusive).
+    pub const BIT_VECTOR_BOUND: u16 = N as u16 + 1;
+
+    /// Largest inner product `decrypt` faithfully returns with the given bound (i.e
+    /// `bound - 1`), None for a bound of 0 that can't recover anything. A None returned by
+    /// `decrypt` means the inner product is greater than this value (or negative, or the
+    /// ciphertext is malformed).
+    pub const fn max_recoverable(bound: u16) -> Option<u16> {
+        bound.checked_sub(1)
+    }
 }
 
 /*
     "Compressed" variants to improve protocol efficiency
 */
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Generic structure representing a compressed secret key for the FE scheme.
+/// * `T` : internal type to represent a scalar
+/// * `U` : internal type representing a (compressed) group element
+/// * `V` : internal type representing the (compressed) vector of the key
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct CompressedDdhFeSecretKey<T, U, V> {
     pub(crate) g: U,
     pub(crate) sx: T,
     pub(crate) tx: T,
-    pub(crate) x: Vec<V>,
+    pub(crate) x: V,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct CompressedDdhFePublicKey<const N: usize, U> {
     pub(crate) g: U,
     pub(crate) h: U,
-    #[serde(with = "BigArray")]
+    #[cfg_attr(feature = "serde", serde(with = "BigArray"))]
     pub(crate) mpk: [U; N],
 }
@@ -1,8 +1,14 @@
 #![warn(missing_docs, rust_2018_idioms)]
 
 //! Crate that implements functionnal encryption over :
-//! * Ristretto255 (feature `elliptic-curve`, enabled by default)
-//! * Diffie Hellman group n°15 (feature `finite-field`, disabled by default)
+//! * Ristretto255 (feature `elliptic-curve`, enabled by default), or the Edwards form of
+//!   Curve25519 instead (feature `edwards`)
+//! * Diffie Hellman group n°15 (feature `finite-field`, disabled by default), with the big
+//!   integers of malachite (feature `malachite`) or of num-bigint (feature `num-bigint`),
+//!   exactly one of them has to be enabled
+//!
+//! The keys and ciphertexts can be (de)serialized with serde (feature `serde`, enabled by
+//! default), it can be disabled when only the math is needed.
 //!
 //! Here is a basic example of how it's working :
 //!
@@ -21,8 +27,7 @@
 //! let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
 //!
 //! let instance = Instance::<4>::setup();
-//! // We want to encrypt vectors of u8
-//! let pk = instance.public_key::<u8>();
+//! let pk = instance.public_key();
 //!
 //! let sk = instance.secret_key(v1);
 //! // Encrypt v2
@@ -30,7 +35,7 @@
 //! // Decrypt and bound the result by let say 1000. If the scalar
 //! // product of v1 and v2 exceed that bound then you'll get an error
 //! let scalar_product = sk.decrypt(encrypted, 1000).unwrap();
-//! assert_eq!(scalar_product, (0..4).map(|i| (v1[i] as u16) * (v2[i] as u16)).sum());
+//! assert_eq!(scalar_product, (0..4).map(|i| (v1[i] as u16) * (v2[i] as u16)).sum::<u16>());
 //! ```
 #[cfg(all(feature = "finite-field", feature = "elliptic-curve"))]
 compile_error!(
@@ -42,37 +47,68 @@ compile_error!(
     "Must enable either `elliptic-curve` or `finite-field` features (they are mutualy exclusive)."
 );
 
+#[cfg(all(feature = "malachite", feature = "num-bigint"))]
+compile_error!("Can't use both `malachite` and `num-bigint` features. They are mutualy exclusive.");
+
+#[cfg(all(
+    feature = "finite-field",
+    not(feature = "malachite"),
+    not(feature = "num-bigint")
+))]
+compile_error!(
+    "The `finite-field` feature needs its big integers : enable either `malachite` or `num-bigint`."
+);
+
 cfg_if::cfg_if! {
     if #[cfg(feature = "elliptic-curve")] {
         mod ec_fe;
         pub use ec_fe::*;
     } else if #[cfg(feature = "finite-field")] {
+        mod bigint;
         mod ff_fe;
         mod consts;
         pub use ff_fe::*;
     }
 }
 
+mod error;
 mod generic;
 pub mod traits;
 
+pub use error::FeError;
+
+// Instances and keys must be shareable between threads for both backends
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Instance<1>>();
+    assert_send_sync::<PublicKey<1>>();
+    assert_send_sync::<SecretKey<1>>();
+    assert_send_sync::<CompressedSecretKey>();
+    assert_send_sync::<CipherText<1>>();
+};
+
 #[cfg(test)]
 mod tests {
     use super::traits::*;
     use super::*;
+    use core::array;
+    #[cfg(feature = "elliptic-curve")]
+    use curve25519_dalek::scalar::Scalar;
     use proptest::prelude::*;
     use proptest::test_runner::{TestError, TestRunner};
     use rand::{
-        SeedableRng,
+        RngExt, SeedableRng,
         rngs::{StdRng, SysRng},
     };
+    use std::sync::Arc;
+    use std::thread;
 
     const N: usize = 512;
 
     fn fresh_instance() -> (Instance<N>, PublicKey<N>) {
         println!("[test] Generating instance...");
         let instance: Instance<N> = Instance::<N>::setup();
-        let pk = instance.public_key::<u8>();
+        let pk = instance.public_key();
 
         (instance, pk)
     }
@@ -170,4 +206,607 @@ mod tests {
             result => panic!("Unexpected result {:?}", result),
         }
     }
+
+    fn compressed_roundtrip<const M: usize>() -> CompressedSecretKey {
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let instance = Instance::<M>::setup();
+
+        // Random bit vector with the trailing bit set, so that a dropped tail is noticed
+        let mut vector: [u8; M] = array::from_fn(|_| rng.random::<u8>() & 1);
+        vector[M - 1] = 1;
+
+        let sk = instance.secret_key(vector);
+        let compressed = CompressedSecretKey::from(&sk);
+        let decompressed = SecretKey::<M>::try_from(&compressed).unwrap();
+
+        assert_eq!(decompressed.g, sk.g);
+        assert_eq!(decompressed.sx, sk.sx);
+        assert_eq!(decompressed.tx, sk.tx);
+        assert_eq!(decompressed.x, sk.x);
+
+        compressed
+    }
+
+    #[test]
+    fn test_compression_non_byte_aligned() {
+        compressed_roundtrip::<500>();
+        compressed_roundtrip::<512>();
+        let compressed = compressed_roundtrip::<513>();
+
+        // The compressed key should not be accepted for another vector size
+        assert!(SecretKey::<512>::try_from(&compressed).is_err());
+    }
+
+    #[cfg(feature = "elliptic-curve")]
+    #[test]
+    fn test_compression_rejects_padding() {
+        let mut compressed = compressed_roundtrip::<500>();
+        // Set one of the padding bits of the last byte
+        match &mut compressed.x {
+            CompressedVector::Binary(bits) => *bits.last_mut().unwrap() |= 1,
+            CompressedVector::Scalars(_) => panic!("A bit vector should be packed"),
+        }
+
+        assert!(SecretKey::<500>::try_from(&compressed).is_err());
+    }
+
+    #[cfg(feature = "elliptic-curve")]
+    #[test]
+    fn test_compression_truncated() {
+        let compressed = compressed_roundtrip::<500>();
+        let CompressedVector::Binary(bits) = &compressed.x else {
+            panic!("A bit vector should be packed")
+        };
+
+        // Any missing or extra byte is rejected, without panicking
+        for len in [0, 1, bits.len() / 2, bits.len() - 1, bits.len() + 1] {
+            let mut resized = bits.clone();
+            resized.resize(len, 0);
+            let truncated = CompressedSecretKey {
+                x: CompressedVector::Binary(resized),
+                ..compressed.clone()
+            };
+            assert!(SecretKey::<500>::try_from(&truncated).is_err());
+        }
+    }
+
+    #[cfg(feature = "elliptic-curve")]
+    #[test]
+    fn test_compression_div_ceil() {
+        let CompressedVector::Binary(bits) = compressed_roundtrip::<520>().x else {
+            panic!("A bit vector should be packed")
+        };
+        assert_eq!(bits.len(), 65);
+
+        // The last bit of 521 is in a byte of its own
+        let compressed = compressed_roundtrip::<521>();
+        let CompressedVector::Binary(bits) = &compressed.x else {
+            panic!("A bit vector should be packed")
+        };
+        assert_eq!(bits.len(), 66);
+        // The tail bit (set by compressed_roundtrip) is the most significant one of the
+        // last byte, the others are padding
+        assert_eq!(bits[65], 0x80);
+
+        // Dropping the last byte loses the tail bit
+        let truncated = CompressedSecretKey {
+            x: CompressedVector::Binary(bits[..65].to_vec()),
+            ..compressed.clone()
+        };
+        assert!(SecretKey::<521>::try_from(&truncated).is_err());
+        assert!(SecretKey::<520>::try_from(&compressed).is_err());
+        assert!(SecretKey::<512>::try_from(&compressed).is_err());
+    }
+
+    #[cfg(feature = "elliptic-curve")]
+    #[test]
+    fn test_secret_key_binary_fast_path() {
+        let (instance, _) = fresh_instance();
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let vector: [u8; N] = array::from_fn(|_| rng.random::<u8>() & 1);
+
+        // Products computed as for any other vector
+        let (sx, tx) = instance
+            .msk
+            .iter()
+            .zip(vector)
+            .map(|(e_i, x_i)| (e_i.s * Scalar::from(x_i), e_i.t * Scalar::from(x_i)))
+            .fold((Scalar::ZERO, Scalar::ZERO), |acc, e| {
+                (acc.0 + e.0, acc.1 + e.1)
+            });
+
+        let sk = instance.secret_key(vector);
+        assert_eq!((sk.sx, sk.tx), (sx, tx));
+        assert_eq!(instance.secret_key([0u8; N]).sx, Scalar::ZERO);
+    }
+
+    #[test]
+    fn test_compression_weighted() {
+        let instance = Instance::<10>::setup();
+        let sk = instance.secret_key([0u8, 1, 2, 3, 255, 1, 0, 0, 7, 1]);
+
+        let compressed = CompressedSecretKey::from(&sk);
+        #[cfg(feature = "elliptic-curve")]
+        assert!(matches!(compressed.x, CompressedVector::Scalars(_)));
+
+        let decompressed = SecretKey::<10>::try_from(&compressed).unwrap();
+        assert_eq!(decompressed.x, sk.x);
+        assert_eq!(decompressed.sx, sk.sx);
+        assert_eq!(decompressed.tx, sk.tx);
+        assert!(SecretKey::<9>::try_from(&compressed).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_compact_bytes() {
+        let (instance, _) = fresh_instance();
+        let secret_vec: [u8; N] = array::from_fn(|i| (i % 3 == 0) as u8);
+        let sk = instance.secret_key(secret_vec);
+
+        let bytes = sk.to_compact_bytes();
+        let loaded = SecretKey::<N>::from_compact_bytes(&bytes).unwrap();
+        assert_eq!(loaded.to_compact_bytes(), bytes);
+
+        // Wrong vector size
+        assert_eq!(
+            SecretKey::<{ N - 8 }>::from_compact_bytes(&bytes).err(),
+            Some(FeError::InvalidSecretKey)
+        );
+        // Truncated, or followed by garbage
+        assert!(SecretKey::<N>::from_compact_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(SecretKey::<N>::from_compact_bytes(&[&bytes[..], &[0]].concat()).is_err());
+        assert!(SecretKey::<N>::from_compact_bytes(&[]).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_with_seed() {
+        let (instance, pk) = fresh_instance();
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+
+        let secret_vec: [u8; N] = array::from_fn(|_| rng.random::<u8>() & 1);
+        let client_vec: [u8; N] = array::from_fn(|_| rng.random::<u8>() & 1);
+        let seed: [u8; 32] = rng.random();
+
+        let ct1 = pk.encrypt_with_seed(seed, client_vec);
+        let ct2 = pk.encrypt_with_seed(seed, client_vec);
+        assert_eq!(ct1.c, ct2.c);
+        assert_eq!(ct1.d, ct2.d);
+        assert_eq!(ct1.e, ct2.e);
+
+        // Another seed gives another ciphertext
+        let ct3 = pk.encrypt_with_seed([!seed[0]; 32], client_vec);
+        assert_ne!(ct1.c, ct3.c);
+
+        // Decryption is unaffected
+        let sk = instance.secret_key(secret_vec);
+        let expected: u16 = secret_vec
+            .iter()
+            .zip(client_vec)
+            .map(|(a, b)| (*a as u16) * (b as u16))
+            .sum();
+        assert_eq!(sk.decrypt(ct1, N as u16), Some(expected));
+        assert_eq!(sk.decrypt(ct3, N as u16), Some(expected));
+    }
+
+    #[test]
+    fn test_encrypt_ref() {
+        let (instance, pk) = fresh_instance();
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+
+        let client_vec: [u8; N] = array::from_fn(|_| rng.random::<u8>() & 1);
+        let seed: [u8; 32] = rng.random();
+
+        // Same randomness, same ciphertext
+        let ct1 = pk.encrypt_ref(&mut StdRng::from_seed(seed), &client_vec);
+        let ct2 = pk.encrypt(&mut StdRng::from_seed(seed), client_vec);
+        assert_eq!(ct1.c, ct2.c);
+        assert_eq!(ct1.d, ct2.d);
+        assert_eq!(ct1.e, ct2.e);
+
+        let sk = instance.secret_key([1u8; N]);
+        let expected: u16 = client_vec.iter().map(|b| *b as u16).sum();
+        assert_eq!(sk.decrypt(ct1, N as u16), Some(expected));
+    }
+
+    #[cfg(feature = "finite-field")]
+    #[test]
+    fn test_decrypt_big() {
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let instance = Instance::<4>::setup();
+        let pk = instance.public_key();
+
+        let sk = instance.secret_key([35_000u32, 0, 7, 0]);
+        let ct = pk.encrypt(&mut rng, [2u32, 5, 0, 1]);
+
+        // The inner product exceeds u16::MAX
+        let expected = Natural::from(70_000u32);
+        assert_eq!(
+            sk.decrypt_big(ct.clone(), Natural::from(70_001u32)),
+            Some(expected.clone())
+        );
+        assert_eq!(sk.decrypt_big(ct, expected), None);
+    }
+
+    #[cfg(feature = "elliptic-curve")]
+    #[test]
+    fn test_decrypt_context() {
+        let (instance, pk) = fresh_instance();
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+
+        let secret_vec: [u8; N] = array::from_fn(|_| rng.random::<u8>() & 1);
+        let sk = instance.secret_key(secret_vec);
+        let context = sk.decrypt_context();
+
+        // Several ciphertexts decrypted with the same context
+        for _ in 0..5 {
+            let client_vec: [u8; N] = array::from_fn(|_| rng.random::<u8>() & 1);
+            let ct = pk.encrypt(&mut rng, client_vec);
+            let expected: u16 = secret_vec
+                .iter()
+                .zip(client_vec)
+                .map(|(a, b)| (*a as u16) * (b as u16))
+                .sum();
+
+            assert_eq!(context.decrypt(ct.clone(), N as u16), Some(expected));
+            assert_eq!(context.decrypt(ct.clone(), expected), None);
+            assert_eq!(sk.decrypt(ct, N as u16), Some(expected));
+        }
+    }
+
+    #[cfg(feature = "elliptic-curve")]
+    #[test]
+    fn test_encrypt_bound() {
+        let (instance, pk) = fresh_instance();
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let vector: [u8; N] = array::from_fn(|_| rng.random::<u8>() & 1);
+
+        let (ct, proof) = pk.encrypt_bound(&mut rng, vector, b"session 1");
+        assert_eq!(pk.verify_bound(&ct, &proof, b"session 1"), Ok(()));
+        // Still a regular ciphertext
+        let sk = instance.secret_key([1u8; N]);
+        let expected = vector.iter().map(|b| *b as u16).sum();
+        assert_eq!(sk.decrypt(ct.clone(), N as u16 + 1), Some(expected));
+
+        // Replayed in another context, with the proof of another ciphertext, or under
+        // another key
+        assert_eq!(
+            pk.verify_bound(&ct, &proof, b"session 2"),
+            Err(FeError::InvalidProof)
+        );
+        let (other_ct, _) = pk.encrypt_bound(&mut rng, vector, b"session 1");
+        assert_eq!(
+            pk.verify_bound(&other_ct, &proof, b"session 1"),
+            Err(FeError::InvalidProof)
+        );
+        let (_, other_pk) = fresh_instance();
+        assert_eq!(
+            other_pk.verify_bound(&ct, &proof, b"session 1"),
+            Err(FeError::InvalidProof)
+        );
+    }
+
+    #[test]
+    fn test_max_recoverable() {
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let instance = Instance::<4>::setup();
+        let sk = instance.secret_key([1u8; 4]);
+
+        assert_eq!(SecretKey::<4>::max_recoverable(0), None);
+        assert_eq!(SecretKey::<4>::BIT_VECTOR_BOUND, 5);
+        assert_eq!(
+            SecretKey::<4>::max_recoverable(SecretKey::<4>::BIT_VECTOR_BOUND),
+            Some(4)
+        );
+
+        // Inner products up to max_recoverable are recovered, the next ones aren't
+        for inner_product in 0..=4u8 {
+            let ct = instance.public_key().encrypt(
+                &mut rng,
+                array::from_fn(|i| (i < inner_product as usize) as u8),
+            );
+            for bound in 0..=5 {
+                let expected = SecretKey::<4>::max_recoverable(bound)
+                    .filter(|max| inner_product as u16 <= *max)
+                    .map(|_| inner_product as u16);
+                assert_eq!(sk.decrypt(ct.clone(), bound), expected);
+            }
+        }
+    }
+
+    #[cfg(all(feature = "elliptic-curve", feature = "serde"))]
+    #[test]
+    fn test_dlog_table_roundtrip() {
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let instance = Instance::<4>::setup();
+        let pk = instance.public_key();
+        let sk = instance.secret_key([200u8, 0, 7, 255]);
+
+        let bytes = DlogTable::new(&pk, 60_000).to_bytes().unwrap();
+        let table = DlogTable::from_bytes(&bytes, &pk).unwrap();
+        assert_eq!(table.bound(), 60_000);
+
+        let context = sk.decrypt_context();
+        for (client_vec, expected) in [
+            ([0u8, 0, 0, 0], Some(0)),
+            ([1, 0, 0, 1], Some(455)),
+            ([255, 1, 1, 3], Some(51_772)),
+            ([255, 1, 1, 255], None),
+        ] {
+            let ct = pk.encrypt(&mut rng, client_vec);
+            assert_eq!(context.decrypt_with_table(ct, &table), expected);
+        }
+    }
+
+    #[cfg(all(feature = "elliptic-curve", feature = "serde"))]
+    #[test]
+    fn test_dlog_table_size_hint() {
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let instance = Instance::<4>::setup();
+        let pk = instance.public_key();
+        let context = instance.secret_key([1u8; 4]).decrypt_context();
+        let bound = 1000;
+
+        // From a single baby step (a linear search) to a baby step for each value
+        for hint in [0, 1, 7, 32, 100, 999, 1000, u16::MAX] {
+            let bytes = DlogTable::with_table_size(&pk, bound, hint)
+                .to_bytes()
+                .unwrap();
+            let table = DlogTable::from_bytes(&bytes, &pk).unwrap();
+            for (client_vec, expected) in [
+                ([0u8, 0, 0, 0], Some(0)),
+                ([1, 2, 3, 4], Some(10)),
+                ([250, 249, 250, 250], Some(999)),
+                ([250, 250, 250, 250], None),
+            ] {
+                let ct = pk.encrypt(&mut rng, client_vec);
+                assert_eq!(context.decrypt_with_table(ct, &table), expected);
+            }
+        }
+    }
+
+    #[cfg(all(feature = "elliptic-curve", feature = "serde"))]
+    #[test]
+    fn test_dlog_table_rejects_other_key() {
+        let instance = Instance::<4>::setup();
+        let pk = instance.public_key();
+        let other_pk = Instance::<4>::setup().public_key();
+        let bytes = DlogTable::new(&pk, 1000).to_bytes().unwrap();
+
+        assert_eq!(
+            DlogTable::from_bytes(&bytes, &other_pk).unwrap_err(),
+            FeError::InvalidDlogTable
+        );
+        assert_eq!(
+            DlogTable::from_bytes(&bytes[..bytes.len() - 1], &pk).unwrap_err(),
+            FeError::InvalidDlogTable
+        );
+
+        // 10 baby steps, each one is a point followed by its value (a single byte)
+        let bytes = DlogTable::new(&pk, 100).to_bytes().unwrap();
+        let (last, second_to_last) = (bytes.len() - 1, bytes.len() - 34);
+        let mut corrupted_bytes = bytes.clone();
+        corrupted_bytes[last] = (bytes[last] + 1) % 10;
+        assert_eq!(
+            DlogTable::from_bytes(&corrupted_bytes, &pk).unwrap_err(),
+            FeError::InvalidDlogTable
+        );
+
+        // Swapped baby steps are loaded, but never give a wrong inner product
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let mut swapped_bytes = bytes.clone();
+        swapped_bytes.swap(last, second_to_last);
+        let swapped = DlogTable::from_bytes(&swapped_bytes, &pk).unwrap();
+        let context = instance.secret_key([1u8; 4]).decrypt_context();
+        let decrypted: Vec<_> = (0..100u8)
+            .map(|inner_product| {
+                let ct = pk.encrypt(&mut rng, [inner_product, 0, 0, 0]);
+                context.decrypt_with_table(ct, &swapped)
+            })
+            .collect();
+        assert!(decrypted.contains(&None));
+        assert!(
+            decrypted.iter().enumerate().all(
+                |(inner_product, &dlog)| dlog.is_none_or(|dlog| dlog as usize == inner_product)
+            )
+        );
+
+        // Nor can a table be used with the secret keys of another instance
+        let table = DlogTable::from_bytes(&bytes, &pk).unwrap();
+        let other = Instance::<4>::setup();
+        let ct = other.public_key().encrypt(&mut rng, [1u8, 1, 1, 1]);
+        let context = other.secret_key([1u8, 1, 1, 1]).decrypt_context();
+        assert_eq!(context.decrypt_with_table(ct, &table), None);
+    }
+
+    #[cfg(feature = "elliptic-curve")]
+    #[test]
+    fn test_decrypt_signed() {
+        use curve25519_dalek::Scalar;
+
+        let (instance, pk) = fresh_instance();
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+
+        // Secret vector with entries in {-1, 0, 1}
+        let secret_vec: [i8; N] = array::from_fn(|_| (rng.random::<u8>() % 3) as i8 - 1);
+        let signed = |v: i8| {
+            if v < 0 {
+                -Scalar::from(v.unsigned_abs())
+            } else {
+                Scalar::from(v as u8)
+            }
+        };
+        let sk = instance.secret_key_with(secret_vec, signed);
+
+        let inner_product = |client_vec: [u8; N]| -> i16 {
+            secret_vec
+                .iter()
+                .zip(client_vec)
+                .map(|(a, b)| (*a as i16) * (b as i16))
+                .sum()
+        };
+
+        // Only the negative entries of the secret vector are selected
+        let client_vec: [u8; N] = secret_vec.map(|v| (v < 0) as u8);
+        let expected = inner_product(client_vec);
+        assert!(expected < 0);
+
+        let ct = pk.encrypt(&mut rng, client_vec);
+        assert_eq!(sk.decrypt_signed(ct.clone(), N as i16), Some(expected));
+        assert_eq!(sk.decrypt_signed(ct.clone(), -expected), None);
+        assert_eq!(sk.decrypt(ct, N as u16), None);
+
+        // Random vectors, either positive or negative
+        for _ in 0..5 {
+            let client_vec: [u8; N] = array::from_fn(|_| rng.random::<u8>() & 1);
+            let ct = pk.encrypt(&mut rng, client_vec);
+
+            assert_eq!(
+                sk.decrypt_signed(ct, N as i16),
+                Some(inner_product(client_vec))
+            );
+        }
+    }
+
+    #[test]
+    fn test_encrypt_batch() {
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let instance = Instance::<8>::setup();
+        let pk = instance.public_key();
+        let sk = instance.secret_key([1u8, 0, 1, 1, 0, 0, 1, 1]);
+
+        let vectors: Vec<[u8; 8]> = (0..6u8)
+            .map(|i| array::from_fn(|j| (j as u8 + i) % 2))
+            .collect();
+        let cts = pk.encrypt_batch(&mut rng, vectors.clone());
+        assert_eq!(cts.len(), vectors.len());
+
+        for (ct, v) in cts.iter().zip(&vectors) {
+            let expected = [1u8, 0, 1, 1, 0, 0, 1, 1]
+                .iter()
+                .zip(v)
+                .map(|(a, b)| (a * b) as u16)
+                .sum();
+            assert_eq!(sk.decrypt(ct.clone(), 9), Some(expected));
+        }
+
+        // Each vector is encrypted with its own randomness, even identical ones
+        assert_eq!(vectors[0], vectors[2]);
+        for (i, ct) in cts.iter().enumerate() {
+            assert!(cts[i + 1..].iter().all(|other| other.c != ct.c));
+        }
+    }
+
+    #[test]
+    fn test_shared_public_key() {
+        let (instance, pk) = fresh_instance();
+        let shared = instance.shared_public_key();
+
+        assert_eq!(shared.g, pk.g);
+        assert_eq!(shared.h, pk.h);
+        assert_eq!(shared.mpk, pk.mpk);
+
+        // No copy of the key
+        assert!(Arc::ptr_eq(&shared, &instance.shared_public_key()));
+    }
+
+    #[test]
+    fn test_custom_mappings() {
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let instance = Instance::<4>::setup();
+        let pk = instance.public_key();
+
+        // Values centered around zero, i.e 0, 1, 2 are mapped to -1, 0, 1
+        #[cfg(feature = "elliptic-curve")]
+        let center = |v: u8| curve25519_dalek::Scalar::from(v) - curve25519_dalek::Scalar::ONE;
+        // Exponents are taken modulo p - 1, so -1 is p - 2
+        #[cfg(feature = "finite-field")]
+        let center =
+            |v: u8| bigint::from_limbs_desc(&consts::DH15_PRIME_LIMBS) - Natural::from(2u8 - v);
+        let sk = instance.secret_key_with([2u8, 0, 1, 2], center);
+
+        // Booleans scaled by 3
+        let ct = pk.encrypt_with(&mut rng, [true, true, false, true], |b| {
+            (3 * b as u8).into()
+        });
+
+        // 3 * (1 - 1 + 0 + 1)
+        assert_eq!(sk.decrypt(ct, 10), Some(3));
+    }
+
+    #[test]
+    fn test_shared_instance() {
+        let instance = Arc::new(Instance::<N>::setup());
+        let pk = instance.public_key();
+
+        let handles: Vec<_> = (0..4u8)
+            .map(|i| {
+                let instance = Arc::clone(&instance);
+                thread::spawn(move || instance.secret_key([i; N]))
+            })
+            .collect();
+
+        let client_vec: [u8; N] = array::from_fn(|j| (j % 2) as u8);
+        for (i, handle) in handles.into_iter().enumerate() {
+            let sk = handle.join().unwrap();
+            let ct = pk.encrypt_with_seed([i as u8; 32], client_vec);
+            assert_eq!(sk.decrypt(ct, 4 * N as u16), Some((i * N / 2) as u16));
+        }
+    }
+
+    /// Run the whole FE flow only through the traits, so that any backend implementing
+    /// them can be used interchangeably.
+    fn trait_based_flow<I, G, S, const M: usize>(secret_vec: [u8; M], client_vec: [u8; M]) -> u16
+    where
+        I: FEInstance<M, G, S>,
+        S: From<u8>,
+        generic::DdhFePublicKey<M, G>: FEPubKey<M, u8, G>,
+        generic::DdhFeSecretKey<M, S, G>: FESecretKey<M, G, u16>,
+        generic::DdhFeCiphertext<M, G>: FECipherText<G>,
+    {
+        let mut rng = StdRng::try_from_rng(&mut SysRng).unwrap();
+        let instance = I::setup();
+        let pk = instance.public_key();
+        let sk = instance.secret_key(secret_vec);
+
+        let ct = pk.encrypt(&mut rng, client_vec);
+        sk.decrypt(ct, u16::MAX).unwrap()
+    }
+
+    #[test]
+    fn test_trait_based_flow() {
+        let scalar_prod = trait_based_flow::<Instance<4>, _, _, 4>([1, 2, 3, 4], [4, 3, 2, 1]);
+        assert_eq!(scalar_prod, 20);
+    }
+
+    #[cfg(feature = "edwards")]
+    #[test]
+    fn test_validate_rejects_torsion() {
+        let instance = Instance::<4>::setup();
+        let mut pk = instance.public_key();
+
+        // Point with a small order component
+        pk.mpk[1] += curve25519_dalek::constants::EIGHT_TORSION[1];
+        assert_eq!(pk.validate(), Err(FeError::InvalidPublicKey));
+    }
+
+    #[test]
+    fn test_validate_public_key() {
+        let instance = Instance::<4>::setup();
+        let mut pk = instance.public_key();
+        assert_eq!(pk.validate(), Ok(()));
+
+        #[cfg(feature = "elliptic-curve")]
+        let identity = <Point as curve25519_dalek::traits::Identity>::identity();
+        #[cfg(feature = "finite-field")]
+        let identity = Natural::from(1u8);
+
+        // Identity element as generator
+        let mut other_pk = instance.public_key();
+        other_pk.g = identity;
+        assert_eq!(other_pk.validate(), Err(FeError::InvalidPublicKey));
+
+        // Identity element in the master public key
+        pk.mpk[2] = other_pk.g;
+        assert_eq!(pk.validate(), Err(FeError::InvalidPublicKey));
+    }
 }
@@ -9,47 +9,96 @@
 
 use crate::generic::{DdhFeCiphertext, DdhFePublicKey, DdhFeSecretKey};
 use rand::CryptoRng;
+#[cfg(feature = "serde")]
 use serde::{Serialize, de::DeserializeOwned};
 use std::marker::Copy;
 
+/// Serialize + DeserializeOwned with the `serde` feature, so that the keys and ciphertexts
+/// can be sent over the network. Without it, this is implemented by any type.
+#[cfg(feature = "serde")]
+pub trait MaybeSerde: Serialize + DeserializeOwned {}
+#[cfg(feature = "serde")]
+impl<T: Serialize + DeserializeOwned> MaybeSerde for T {}
+
+/// Serialize + DeserializeOwned with the `serde` feature, so that the keys and ciphertexts
+/// can be sent over the network. Without it, this is implemented by any type.
+#[cfg(not(feature = "serde"))]
+pub trait MaybeSerde {}
+#[cfg(not(feature = "serde"))]
+impl<T> MaybeSerde for T {}
+
 /// Trait for a generic functionnal encryption instance. The idea is that an instance should
 /// be able to generate a public key made of group element for an arbitrary sized vector, and
 /// should compute an secret key for any given input vector of that same size.
 pub trait FEInstance<const N: usize, U, V> {
     /// Return a fresh instance of the FE scheme
     fn setup() -> Self;
-    /// Return a fresh public key for the FE scheme
-    fn public_key<T: Copy>(&self) -> DdhFePublicKey<N, U>
-    where
-        V: From<T>;
+    /// Return the public key of the instance, the type of the vectors to encrypt is
+    /// only chosen when encrypting.
+    fn public_key(&self) -> DdhFePublicKey<N, U>;
     /// Return a secret key associated to the input vector
     fn secret_key<T: Copy>(&self, vector: [T; N]) -> DdhFeSecretKey<N, V, U>
     where
-        V: From<T>;
+        V: From<T>,
+    {
+        self.secret_key_with(vector, V::from)
+    }
+    /// Return a secret key associated to the input vector, each element being mapped
+    /// to a scalar using the given closure (e.g for signed or scaled encodings).
+    fn secret_key_with<T, F: Fn(T) -> V>(&self, vector: [T; N], f: F) -> DdhFeSecretKey<N, V, U>;
 }
 
 /// Trait for a generic public key of the functionnal encryption scheme. A public key should
 /// be able to encrypt a vector of the same size of itself and return the associated ciphertext.
-pub trait FEPubKey<const N: usize, T, U>: Serialize + DeserializeOwned {
+pub trait FEPubKey<const N: usize, T, U>: MaybeSerde {
     /// Encrypt the given vector
-    fn encrypt<R: CryptoRng + ?Sized>(&self, rng: &mut R, vector: [T; N]) -> DdhFeCiphertext<N, U>;
+    fn encrypt<R: CryptoRng + ?Sized>(&self, rng: &mut R, vector: [T; N]) -> DdhFeCiphertext<N, U> {
+        self.encrypt_ref(rng, &vector)
+    }
+    /// Encrypt the given vector, borrowed so that the caller keeps it without copying it
+    fn encrypt_ref<R: CryptoRng + ?Sized>(
+        &self,
+        rng: &mut R,
+        vector: &[T; N],
+    ) -> DdhFeCiphertext<N, U>;
+    /// Encrypt the given vector using randomness derived from the given seed, i.e the
+    /// ciphertext is the same for the same seed. This must only be used for testing purpose.
+    fn encrypt_with_seed(&self, seed: [u8; 32], vector: [T; N]) -> DdhFeCiphertext<N, U>;
+    /// Encrypt several vectors, a backend may share some work between the encryptions
+    /// (each vector is still encrypted with its own randomness).
+    fn encrypt_batch<R: CryptoRng + ?Sized>(
+        &self,
+        rng: &mut R,
+        vectors: Vec<[T; N]>,
+    ) -> Vec<DdhFeCiphertext<N, U>> {
+        vectors.into_iter().map(|v| self.encrypt(rng, v)).collect()
+    }
 }
 
 /// Trait for a generic secret key for the functionnal encryption scheme. The idea is that it
 /// should allow any structure that implements the FECipherText trait to be "decrypted", and
 /// returns the scalar product between the encrypted vector and the one given in the secret key
 // if its value is less than a user-supplied bound.
-pub trait FESecretKey<const N: usize, U, S>: Serialize + DeserializeOwned {
+pub trait FESecretKey<const N: usize, U, S>: MaybeSerde {
     /// Decrypt the given ciphertext (i.e compute an inner product) using the secret key
     fn decrypt(&self, ct: impl FECipherText<U>, bound: S) -> Option<S>;
 }
 
 /// Trait that a ciphertext has to implement (i.e just getter for the field of the struct).
-pub trait FECipherText<U>: Serialize + DeserializeOwned {
+pub trait FECipherText<U>: MaybeSerde {
     /// Getter for the field "c" of the ciphertext struct.
     fn get_c(&self) -> U;
     /// Getter for the field "d" of the ciphertext struct.
     fn get_d(&self) -> U;
     /// Getter for the field "e" of the ciphertext struct.
     fn get_e(&self) -> &[U];
+    /// Size of the encrypted vector, useful to check the dimension of a ciphertext
+    /// received from an untrusted source.
+    fn len(&self) -> usize {
+        self.get_e().len()
+    }
+    /// Return true if the encrypted vector is empty.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
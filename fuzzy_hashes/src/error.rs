@@ -0,0 +1,49 @@
+use std::fmt;
+
+/// Errors returned when building or converting fuzzy hash vectors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FuzzyHashError {
+    /// No kind of fuzzy hash vector has this length (in bytes).
+    InvalidLength(usize),
+    /// The feature index is out of the dimension of the feature vectors.
+    FeatureOutOfRange(usize),
+    /// The vector can't be expanded to a FE vector of this size (in bits).
+    VectorSizeMismatch {
+        /// Size requested for the FE vector
+        expected: usize,
+        /// Size of the expanded vector
+        actual: usize,
+    },
+    /// The string is not the hex encoding of a Nilsimsa digest.
+    InvalidHexDigest,
+}
+
+impl fmt::Display for FuzzyHashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FuzzyHashError::InvalidLength(len) => {
+                write!(f, "no fuzzy hash vector is {} bytes long", len)
+            }
+            FuzzyHashError::FeatureOutOfRange(feature) => {
+                write!(
+                    f,
+                    "feature {} is out of the {} features of a feature vector",
+                    feature,
+                    crate::FEATURE_VECTOR_SIZE_BITS
+                )
+            }
+            FuzzyHashError::VectorSizeMismatch { expected, actual } => write!(
+                f,
+                "the fuzzy hash vector expands to {} bits instead of {}",
+                actual, expected
+            ),
+            FuzzyHashError::InvalidHexDigest => write!(
+                f,
+                "expected {} hex characters",
+                2 * crate::NILSIMSA_FH_SIZE_BYTES
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FuzzyHashError {}
@@ -2,15 +2,15 @@
 //! Module containing implementation of fuzzy hashes and their related constants.
 
 use core::array;
-use core::array::TryFromSliceError;
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use serde_big_array::BigArray;
 use std::fmt::Debug;
-use std::ops::BitAnd;
-use std::ops::Shr;
+
+mod error;
+pub use error::FuzzyHashError;
 
 mod nilsimsa;
-pub use nilsimsa::Nilsimsa;
+pub use nilsimsa::{Nilsimsa, compare as nilsimsa_compare, parse_digest_hex};
 
 /// Length of a Nilsimsa fuzzy hash
 pub const NILSIMSA_FH_SIZE_BYTES: usize = 32;
@@ -20,37 +20,104 @@ pub const NILSIMSA_VECTOR_SIZE_BYTES: usize = 64;
 /// Length in bytes of a Nilsimsa fuzzy hash vector
 /// (i.e the fuzzy hash itself, and its opposite concatenated).
 pub const NILSIMSA_VECTOR_SIZE_BITS: usize = 512;
+/// Dimension of a feature vector (i.e the number of distinct features).
+pub const FEATURE_VECTOR_SIZE_BITS: usize = 1024;
+/// Length in bytes of a feature vector, one bit per feature.
+pub const FEATURE_VECTOR_SIZE_BYTES: usize = FEATURE_VECTOR_SIZE_BITS / 8;
+
+/// Fuzzy hash that can be computed incrementally, allowing several fuzzy hashes
+/// to be fed the same chunks of data (e.g to hash a file only once).
+pub trait FuzzyHasher {
+    /// Feed a chunk of data to the hasher
+    fn update(&mut self, data: &[u8]);
+    /// Consume the hasher and return the vector of the fuzzy hash
+    fn finalize(self: Box<Self>) -> FHVector<u8>;
+    /// Consume the hasher and return the usual text representation of the fuzzy hash
+    /// (e.g the canonical hex of a Nilsimsa digest)
+    fn finalize_hex(self: Box<Self>) -> String;
+}
+
+impl FuzzyHasher for Nilsimsa {
+    fn update(&mut self, data: &[u8]) {
+        Nilsimsa::update(self, data)
+    }
+
+    fn finalize(self: Box<Self>) -> FHVector<u8> {
+        FHVector::from(self.digest())
+    }
+
+    fn finalize_hex(self: Box<Self>) -> String {
+        self.digest_hex()
+    }
+}
 
 /// Enum representing a fuzzy hash vector. For now, only Nilsimsa fuzzy hashes
 /// are supported, but this will allow easy implementation for new hashes.
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+/// Two vectors are equal if they are of the same kind and have the same bytes, e.g to
+/// deduplicate fuzzy hashes in a HashSet.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum FHVector<T: Serialize + DeserializeOwned> {
     /// Nilsimsa vector variant
     #[serde(with = "BigArray")]
     NilsimsaVector([T; NILSIMSA_VECTOR_SIZE_BYTES]),
+    /// Set of features encoded as an indicator vector (bit i is set if feature i is
+    /// in the set), so that the inner product of two vectors is the size of the intersection.
+    #[serde(with = "BigArray")]
+    FeatureVector([T; FEATURE_VECTOR_SIZE_BYTES]),
 }
 
 impl FHVector<u8> {
-    /// Convert a byte vector to a bit vector
-    pub fn to_bits<const N: usize>(&self) -> Result<[u8; N], TryFromSliceError> {
-        let vector = match self {
+    /// Convert a byte vector to the bit vector used as input of the FE scheme
+    /// (most significant bit first). This is the expansion used by both the client to
+    /// encrypt its fuzzy hash, and the authority to derive the secret keys.
+    pub fn to_fe_vector<const N: usize>(&self) -> Result<[u8; N], FuzzyHashError> {
+        let vector: &[u8] = match self {
             Self::NilsimsaVector(v) => v,
+            Self::FeatureVector(v) => v,
         };
 
         vector
             .iter()
             .flat_map(|b| -> [u8; 8] { array::from_fn(|i| 1u8 & (b >> (7 - i))) })
             .collect::<Vec<u8>>()
-            .as_slice()
             .try_into()
+            .map_err(|_| FuzzyHashError::VectorSizeMismatch {
+                expected: N,
+                actual: vector.len() * 8,
+            })
+    }
+
+    /// Nilsimsa digest of a Nilsimsa vector (its first half), None for other vectors.
+    pub fn nilsimsa_digest(&self) -> Option<[u8; NILSIMSA_FH_SIZE_BYTES]> {
+        match self {
+            Self::NilsimsaVector(v) => Some(array::from_fn(|i| v[i])),
+            Self::FeatureVector(_) => None,
+        }
+    }
+
+    /// Encode a (sparse) set of features, given by their index, as a dense feature vector.
+    /// Fail if a feature index is out of the dimension of the vector.
+    pub fn from_features(
+        features: impl IntoIterator<Item = usize>,
+    ) -> Result<Self, FuzzyHashError> {
+        let mut vector = [0u8; FEATURE_VECTOR_SIZE_BYTES];
+        for feature in features {
+            if feature >= FEATURE_VECTOR_SIZE_BITS {
+                return Err(FuzzyHashError::FeatureOutOfRange(feature));
+            }
+            vector[feature / 8] |= 1 << (7 - (feature % 8));
+        }
+
+        Ok(FHVector::FeatureVector(vector))
     }
 }
 
 /*
     Allow to easily convert between FHVector, arrays and vec, based on size.
+    Each variant has its own length, a new variant only needs a new length arm.
 */
 impl<T: Serialize + Debug + DeserializeOwned> TryFrom<Vec<T>> for FHVector<T> {
-    type Error = ();
+    type Error = FuzzyHashError;
 
     fn try_from(value: Vec<T>) -> Result<Self, Self::Error> {
         match value.len() {
@@ -59,22 +126,313 @@ impl<T: Serialize + Debug + DeserializeOwned> TryFrom<Vec<T>> for FHVector<T> {
                     <[T; NILSIMSA_VECTOR_SIZE_BYTES]>::try_from(value).unwrap();
                 Ok(FHVector::NilsimsaVector(arr))
             }
-            _ => Err(()),
+            FEATURE_VECTOR_SIZE_BYTES => {
+                let arr: [T; FEATURE_VECTOR_SIZE_BYTES] =
+                    <[T; FEATURE_VECTOR_SIZE_BYTES]>::try_from(value).unwrap();
+                Ok(FHVector::FeatureVector(arr))
+            }
+            len => Err(FuzzyHashError::InvalidLength(len)),
         }
     }
 }
 
+impl TryFrom<&[u8]> for FHVector<u8> {
+    type Error = FuzzyHashError;
+
+    /// Parse either a raw Nilsimsa digest (that is then expanded), an already
+    /// expanded Nilsimsa vector or a feature vector, depending on the length.
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        match value.len() {
+            NILSIMSA_FH_SIZE_BYTES => {
+                let digest: [u8; NILSIMSA_FH_SIZE_BYTES] = value.try_into().unwrap();
+                Ok(FHVector::from(digest))
+            }
+            NILSIMSA_VECTOR_SIZE_BYTES => Ok(FHVector::NilsimsaVector(value.try_into().unwrap())),
+            FEATURE_VECTOR_SIZE_BYTES => Ok(FHVector::FeatureVector(value.try_into().unwrap())),
+            len => Err(FuzzyHashError::InvalidLength(len)),
+        }
+    }
+}
+
+/// Concatenate a vector and its complement (each element XORed with `mask`, e.g 0xff for
+/// bytes or 1 for bits), as done to build Nilsimsa vectors. The output must be twice as long
+/// as the input, which is checked at compile time.
+pub fn complement_concat<const N: usize, const M: usize>(v: [u8; N], mask: u8) -> [u8; M] {
+    const { assert!(M == 2 * N, "The output must be twice as long as the input") };
+    array::from_fn(|i| if i < N { v[i] } else { mask ^ v[i - N] })
+}
+
 impl From<[u8; 32]> for FHVector<u8> {
     fn from(value: [u8; 32]) -> FHVector<u8> {
-        let vec: [u8; NILSIMSA_VECTOR_SIZE_BYTES] = array::from_fn(|i| {
-            let index = i % (NILSIMSA_VECTOR_SIZE_BYTES / 2);
-            if i < NILSIMSA_VECTOR_SIZE_BYTES / 2 {
-                value[index]
+        FHVector::<_>::NilsimsaVector(complement_concat(value, 0xff))
+    }
+}
+
+/// Finalize a copy of the hasher state, so the hasher can still be fed more data afterwards.
+impl From<&Nilsimsa> for FHVector<u8> {
+    fn from(hasher: &Nilsimsa) -> FHVector<u8> {
+        FHVector::from(hasher.peek_digest())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_fe_vector() {
+        let digest: [u8; NILSIMSA_FH_SIZE_BYTES] = array::from_fn(|i| (i * 37 + 11) as u8);
+        let v: [u8; NILSIMSA_VECTOR_SIZE_BITS] = FHVector::from(digest).to_fe_vector().unwrap();
+
+        for (i, bit) in v.iter().enumerate() {
+            let expected = 1 & (digest[(i / 8) % NILSIMSA_FH_SIZE_BYTES] >> (7 - (i % 8)));
+            if i < NILSIMSA_VECTOR_SIZE_BITS / 2 {
+                assert_eq!(*bit, expected);
             } else {
-                0xff ^ value[index]
+                assert_eq!(*bit, 1 - expected);
+            }
+        }
+
+        // Wrong size
+        assert_eq!(
+            FHVector::from(digest).to_fe_vector::<256>().unwrap_err(),
+            FuzzyHashError::VectorSizeMismatch {
+                expected: 256,
+                actual: NILSIMSA_VECTOR_SIZE_BITS
             }
-        });
+        );
+    }
+
+    #[test]
+    fn test_complement_concat() {
+        let digest: [u8; NILSIMSA_FH_SIZE_BYTES] = array::from_fn(|i| (i * 37 + 11) as u8);
+        let bytes: [u8; NILSIMSA_VECTOR_SIZE_BYTES] = complement_concat(digest, 0xff);
+        assert_eq!(bytes[..NILSIMSA_FH_SIZE_BYTES], digest);
+        for (byte, complement) in digest.iter().zip(&bytes[NILSIMSA_FH_SIZE_BYTES..]) {
+            assert_eq!(*complement, !byte);
+        }
+
+        let bits: [u8; 6] = complement_concat([0, 1, 1], 1);
+        assert_eq!(bits, [0, 1, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_nilsimsa_digest_hex() {
+        // Reference digest, as printed by other Nilsimsa implementations
+        let mut hasher = Nilsimsa::new();
+        hasher.update(b"abcdefgh");
+        assert_eq!(
+            hasher.digest_hex(),
+            "14c8118000000000030800000004042004189020001308014088003280000078"
+        );
+    }
 
-        FHVector::<_>::NilsimsaVector(vec)
+    #[test]
+    fn test_parse_digest_hex() {
+        let digest = parse_digest_hex(&"0f".repeat(NILSIMSA_FH_SIZE_BYTES)).unwrap();
+        assert_eq!(digest, [0x0f; NILSIMSA_FH_SIZE_BYTES]);
+
+        // Same order as digest_hex
+        let mut hasher = Nilsimsa::new();
+        hasher.update(b"abcdefgh");
+        let expected = hasher.clone().digest();
+        assert_eq!(parse_digest_hex(&hasher.digest_hex()).unwrap(), expected);
+        assert_eq!(FHVector::from(expected).nilsimsa_digest(), Some(expected));
+
+        assert_eq!(
+            parse_digest_hex("0f").unwrap_err(),
+            FuzzyHashError::InvalidHexDigest
+        );
+        assert!(parse_digest_hex(&"zz".repeat(NILSIMSA_FH_SIZE_BYTES)).is_err());
+        assert!(
+            FHVector::from_features([1])
+                .unwrap()
+                .nilsimsa_digest()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_from_nilsimsa_hasher() {
+        let mut hasher = Nilsimsa::new();
+        hasher.update(b"The quick brown fox jumps over the lazy dog");
+
+        let vector = FHVector::from(&hasher);
+        let expected = FHVector::from(hasher.clone().digest());
+        assert_eq!(format!("{:?}", vector), format!("{:?}", expected));
+
+        // The hasher can still be updated
+        hasher.update(b" again");
+        let mut reference = Nilsimsa::new();
+        reference.update(b"The quick brown fox jumps over the lazy dog again");
+        assert_eq!(hasher.digest(), reference.digest());
+    }
+
+    #[test]
+    fn test_nilsimsa_peek_digest() {
+        let data: Vec<u8> = (0..5_000u32).map(|i| (i * 31 % 253) as u8).collect();
+        let mut reference = Nilsimsa::new();
+        reference.update(&data);
+
+        // Peek at several points, including within the first trigram
+        let mut hasher = Nilsimsa::new();
+        let mut peeked = vec![];
+        for chunk in [&data[..1], &data[1..3], &data[3..1000], &data[1000..]] {
+            hasher.update(chunk);
+            peeked.push(hasher.peek_digest());
+        }
+        assert_eq!(hasher.digest(), reference.digest());
+
+        // A peeked digest is the digest of the data hashed so far
+        let mut prefix = Nilsimsa::new();
+        prefix.update(&data[..1000]);
+        assert_eq!(peeked[2], prefix.digest());
+        assert_eq!(peeked[0], Nilsimsa::new().digest());
+    }
+
+    #[test]
+    fn test_try_from_vec() {
+        let nilsimsa: Vec<u8> = (0..NILSIMSA_VECTOR_SIZE_BYTES as u8).collect();
+        let Ok(FHVector::NilsimsaVector(v)) = FHVector::try_from(nilsimsa.clone()) else {
+            panic!("Expected a Nilsimsa vector");
+        };
+        assert_eq!(v[..], nilsimsa[..]);
+
+        let features: Vec<u8> = (0..FEATURE_VECTOR_SIZE_BYTES as u8).collect();
+        let Ok(FHVector::FeatureVector(v)) = FHVector::try_from(features.clone()) else {
+            panic!("Expected a feature vector");
+        };
+        assert_eq!(v[..], features[..]);
+
+        // Unknown lengths are rejected
+        for len in [0, NILSIMSA_FH_SIZE_BYTES, NILSIMSA_VECTOR_SIZE_BYTES + 1] {
+            assert_eq!(
+                FHVector::try_from(vec![0u8; len]).unwrap_err(),
+                FuzzyHashError::InvalidLength(len)
+            );
+        }
+    }
+
+    #[test]
+    fn test_try_from_slice() {
+        let digest: [u8; NILSIMSA_FH_SIZE_BYTES] = array::from_fn(|i| i as u8);
+
+        // Raw digest is expanded
+        let expanded = FHVector::try_from(&digest[..]).unwrap();
+        let FHVector::NilsimsaVector(v) = expanded else {
+            panic!("Expected a Nilsimsa vector");
+        };
+        assert_eq!(v[..NILSIMSA_FH_SIZE_BYTES], digest);
+        assert!(
+            v[NILSIMSA_FH_SIZE_BYTES..]
+                .iter()
+                .zip(digest)
+                .all(|(a, b)| *a == !b)
+        );
+
+        // Expanded vector is used directly
+        let Ok(FHVector::NilsimsaVector(w)) = FHVector::try_from(&v[..]) else {
+            panic!("Expected a Nilsimsa vector");
+        };
+        assert_eq!(v, w);
+
+        // Any other length is rejected
+        assert!(FHVector::try_from(&v[..NILSIMSA_VECTOR_SIZE_BYTES - 1]).is_err());
+        assert!(FHVector::try_from(&[][..]).is_err());
+
+        // Feature vectors are used directly
+        let features = [0x5au8; FEATURE_VECTOR_SIZE_BYTES];
+        let Ok(FHVector::FeatureVector(f)) = FHVector::try_from(&features[..]) else {
+            panic!("Expected a feature vector");
+        };
+        assert_eq!(f, features);
+    }
+
+    #[test]
+    fn test_from_features() {
+        let features = [0, 9, 10, 1023];
+        let v: [u8; FEATURE_VECTOR_SIZE_BITS] = FHVector::from_features(features)
+            .unwrap()
+            .to_fe_vector()
+            .unwrap();
+
+        for (i, bit) in v.iter().enumerate() {
+            assert_eq!(*bit == 1, features.contains(&i));
+        }
+
+        // Duplicated features are only set once
+        let FHVector::FeatureVector(w) = FHVector::from_features([3, 3]).unwrap() else {
+            panic!("Expected a feature vector");
+        };
+        assert_eq!(w.iter().map(|b| b.count_ones()).sum::<u32>(), 1);
+
+        // Out of the dimension of the vector
+        assert_eq!(
+            FHVector::from_features([FEATURE_VECTOR_SIZE_BITS]).unwrap_err(),
+            FuzzyHashError::FeatureOutOfRange(FEATURE_VECTOR_SIZE_BITS)
+        );
+    }
+
+    #[test]
+    fn test_dedup() {
+        use std::collections::HashSet;
+
+        let a = FHVector::from([7u8; NILSIMSA_FH_SIZE_BYTES]);
+        let mut b_digest = [7u8; NILSIMSA_FH_SIZE_BYTES];
+        b_digest[31] ^= 1;
+        let b = FHVector::from(b_digest);
+        let features = FHVector::from_features([1, 5]).unwrap();
+
+        let hashes = [
+            a,
+            b,
+            a,
+            features,
+            FHVector::from([7u8; NILSIMSA_FH_SIZE_BYTES]),
+            features,
+        ];
+        let unique: HashSet<FHVector<u8>> = hashes.into_iter().collect();
+        assert_eq!(unique.len(), 3);
+        assert!(unique.contains(&a) && unique.contains(&b) && unique.contains(&features));
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_boxed_hasher() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+        let hasher = || -> Box<dyn FuzzyHasher> { Box::new(Nilsimsa::new()) };
+
+        // Fed in chunks through the trait object, same as hashing at once
+        let mut boxed = hasher();
+        for chunk in data.chunks(5) {
+            boxed.update(chunk);
+        }
+        let mut expected = Nilsimsa::new();
+        expected.update(data);
+        assert_eq!(boxed.finalize(), FHVector::from(expected.clone().digest()));
+
+        let mut boxed = hasher();
+        boxed.update(data);
+        assert_eq!(boxed.finalize_hex(), expected.digest_hex());
+    }
+
+    #[test]
+    fn test_error_display() {
+        assert_eq!(
+            FuzzyHashError::InvalidLength(12).to_string(),
+            "no fuzzy hash vector is 12 bytes long"
+        );
+        assert_eq!(
+            FuzzyHashError::FeatureOutOfRange(2000).to_string(),
+            "feature 2000 is out of the 1024 features of a feature vector"
+        );
+        assert_eq!(
+            FuzzyHashError::VectorSizeMismatch {
+                expected: 256,
+                actual: 512
+            }
+            .to_string(),
+            "the fuzzy hash vector expands to 512 bits instead of 256"
+        );
     }
 }
@@ -1,5 +1,3 @@
-#![feature(test)]
-
 //! Implementation of the Nilsimsa locality-sensitive hashing algorithm.
 //!
 //! Compared to "traditional" hash functions (cryptographic or not), a small modification to the input does not
@@ -7,14 +5,16 @@
 //! hash digests, as well as a [compare](compare) function for given digests.
 //!
 //! ```rust
-//! # use nilsimsa::*;
+//! # use fuzzy_hashes::Nilsimsa;
 //! # fn main() {
 //! let mut hasher = Nilsimsa::new();
-//! hasher.update("test string");
+//! hasher.update(b"test string");
 //! let digest = hasher.digest();
 //! # }
 //! ```
 
+use crate::FuzzyHashError;
+
 const TRAN: [u8; 256] = [
     0x02, 0xd6, 0x9e, 0x6f, 0xf9, 0x1d, 0x04, 0xab, 0xd0, 0x22, 0x16, 0x1f, 0xd8, 0x73, 0xa1, 0xac,
     0x3b, 0x70, 0x62, 0x96, 0x1e, 0x6e, 0x8f, 0x39, 0x9d, 0x05, 0x14, 0x4a, 0xa6, 0xbe, 0xae, 0x0e,
@@ -109,8 +109,16 @@ impl Nilsimsa {
         }
     }
 
-    /// Finalise and consume the digest and return the computed Nilsimsa hash digest as a hex string.
+    /// Finalise and consume the digest and return the computed Nilsimsa hash digest.
     pub fn digest(self) -> [u8; 32] {
+        self.peek_digest()
+    }
+
+    /// Return the digest of the data hashed so far, without consuming the hasher. The last
+    /// bytes are kept in the trigram window, so the trigrams overlapping the data given
+    /// before and after the peek are still counted by later updates : peeking doesn't
+    /// change the final digest.
+    pub fn peek_digest(&self) -> [u8; 32] {
         let num_trigrams = match self.num_char {
             0..=2 => 0,
             3 => 1,
@@ -129,22 +137,46 @@ impl Nilsimsa {
 
         digest
     }
+
+    /// Finalise and consume the digest and return it as a hex string, in the canonical
+    /// order of Nilsimsa implementations (i.e the last byte of the digest first).
+    pub fn digest_hex(self) -> String {
+        self.digest()
+            .iter()
+            .rev()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
 }
 
-/*pub fn compare(digest_a: &str, digest_b: &str) -> i16 {
-    assert!(digest_a.len() == digest_b.len());
+/// Parse a digest from its canonical hex, as returned by `Nilsimsa::digest_hex` (e.g given
+/// on the command line).
+pub fn parse_digest_hex(s: &str) -> Result<[u8; 32], FuzzyHashError> {
+    if s.len() != 64 || !s.is_ascii() {
+        return Err(FuzzyHashError::InvalidHexDigest);
+    }
+
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().rev().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16)
+            .map_err(|_| FuzzyHashError::InvalidHexDigest)?;
+    }
 
-    let hex_a = hex::decode(digest_a).expect("failed to decode digest A into hex");
-    let hex_b = hex::decode(digest_b).expect("failed to decode digest B into hex");
+    Ok(digest)
+}
+
+/// Compare two Nilsimsa digests in plaintext and return their similarity score,
+/// i.e 128 minus the number of differing bits (from -128 to 128).
+pub fn compare(digest_a: &[u8; 32], digest_b: &[u8; 32]) -> i16 {
     let mut bits = 0;
 
-    for (a, b) in hex_a.into_iter().zip(hex_b) {
+    for (a, b) in digest_a.iter().zip(digest_b) {
         bits += POPC[(a ^ b) as usize];
     }
 
     128 - bits
 }
-*/
+
 fn tran_hash(a: u8, b: u8, c: u8, n: u8) -> u8 {
     (TRAN[(a.wrapping_add(n)) as usize]
         ^ (TRAN[b as usize].wrapping_mul(n.wrapping_add(n).wrapping_add(1))))
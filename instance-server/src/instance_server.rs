@@ -1,34 +1,45 @@
+use crate::quota::QuotaTracker;
 use anyhow::{Error, Result, anyhow};
-use core::array;
 use fe::traits::FEInstance;
-use fe::{Instance, PublicKey, SecretKey};
+use fe::{CompressedSecretKey, Instance, PublicKey, SecretKey};
 use futures::SinkExt;
 use futures::StreamExt;
-use fuzzy_hashes::{FHVector, NILSIMSA_VECTOR_SIZE_BITS};
+use fuzzy_hashes::{FEATURE_VECTOR_SIZE_BITS, FHVector, NILSIMSA_VECTOR_SIZE_BITS};
 use log::{error, info};
-use messages::{GenerateInstanceRequest, GenerateInstanceResponse};
+use messages::{
+    AuthorityRequest, AuthorityResponse, GenerateInstanceRequest, GenerateInstanceResponse, codec,
+    decode_versioned, received,
+};
 use std::mem;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
 #[derive(Debug)]
 pub struct Server {
     listener: TcpListener,
+    quota: Option<Arc<QuotaTracker>>,
 }
 
-// Max number of vectors that a single instance can encrypt
-// if the request contains more (or at least as much) than this
-// the request is simply ignored.
-const SERVER_MAX_LEN: usize = NILSIMSA_VECTOR_SIZE_BITS;
-
 impl Server {
     pub fn new(listener: TcpListener) -> Self {
-        Self { listener }
+        Self {
+            listener,
+            quota: None,
+        }
+    }
+
+    /// Limit the number of vectors each compute server can request (unlimited by default).
+    pub fn with_quota(mut self, quota: QuotaTracker) -> Self {
+        self.quota = Some(Arc::new(quota));
+        self
     }
 
     pub async fn run(&mut self) -> Result<()> {
         loop {
-            let mut s = match self.accept_conn().await {
+            let (s, peer) = match self.accept_conn().await {
                 Ok(stream) => stream,
                 Err(e) => {
                     error!("{}", e);
@@ -37,9 +48,14 @@ impl Server {
             };
 
             // Create a dedicated thread for any incomming client
+            let quota = self.quota.clone();
             tokio::spawn(async move {
                 // Init a client handler
-                let mut client_handler = ClientHandler { stream: s };
+                let mut client_handler = ClientHandler {
+                    stream: Framed::new(s, codec()),
+                    peer,
+                    quota,
+                };
                 // Start handling it
                 match client_handler.handle_client().await {
                     Ok(_) => {
@@ -53,55 +69,77 @@ impl Server {
         }
     }
 
-    async fn accept_conn(&mut self) -> Result<TcpStream> {
+    async fn accept_conn(&mut self) -> Result<(TcpStream, IpAddr)> {
         match self.listener.accept().await {
-            Ok((sock, _)) => Ok(sock),
+            Ok((sock, addr)) => Ok((sock, addr.ip())),
             Err(e) => Err(Error::from(e)),
         }
     }
 }
 
-// Struct to handle a client
-struct ClientHandler {
-    stream: TcpStream,
+// Struct to handle a client, over any stream (a TcpStream, or an in-memory pipe in the tests)
+struct ClientHandler<S> {
+    // Held for the whole connection, so that the bytes buffered after a frame are not lost
+    stream: Framed<S, LengthDelimitedCodec>,
+    peer: IpAddr,
+    quota: Option<Arc<QuotaTracker>>,
 }
 
-impl ClientHandler {
+impl<S: AsyncRead + AsyncWrite + Unpin> ClientHandler<S> {
     /// The protocol is using framed content, encoded by prefixing the length of the payload
     /// This reads an entire frame and returns what the readed frame.
     async fn read_frame(&mut self) -> Result<Vec<u8>> {
-        let mut reader = FramedRead::new(&mut self.stream, LengthDelimitedCodec::new());
-        let frame = reader.next().await.unwrap().unwrap().to_vec();
+        let frame = received(self.stream.next().await)?.to_vec();
         Ok(frame)
     }
 
     /// The protocol is using framed content, encoded by prefixing the length of the payload
     /// This write an entire frame made of the given bytes.
     async fn write_frame(&mut self, bytes: Vec<u8>) -> Result<()> {
-        let mut writer = FramedWrite::new(&mut self.stream, LengthDelimitedCodec::new());
-        writer.send(bytes.into()).await?;
+        self.stream.send(bytes.into()).await?;
         Ok(())
     }
 
+    /// Convert the request to vectors of size N and check that a single instance can serve
+    /// them. The quota of the peer is only consumed once the request passed these checks,
+    /// a refused request costs nothing.
+    fn accept_vectors<const N: usize>(
+        &self,
+        incomming_vectors: &GenerateInstanceRequest<u8>,
+    ) -> Result<Vec<[u8; N]>> {
+        let vectors = incomming_vectors
+            .iter()
+            .map(|vector| Ok(vector.to_fe_vector::<N>()?))
+            .collect::<Result<Vec<_>>>()?;
+        check_instance_capacity::<N>(vectors.len())?;
+
+        if let Some(quota) = &self.quota {
+            quota.consume(self.peer, vectors.len())?;
+        }
+        Ok(vectors)
+    }
+
     /// Main function, this contains the handling flow of a request
     async fn handle_client(&mut self) -> Result<()> {
         info!("Handling new client");
 
         // Read the incomming request and deserialize it to retrieve the GenerateInstanceRequest
         let frame = self.read_frame().await?;
-        let incomming_vectors: GenerateInstanceRequest<u8> = match postcard::from_bytes(&frame) {
+        let request: AuthorityRequest = match decode_versioned(&frame) {
             Ok(v) => v,
             Err(error) => {
                 error!("Unable to understand client payload");
-                return Err(error.into());
+                return Err(error);
             }
         };
+        let streamed = matches!(request, AuthorityRequest::Stream(_));
+        let incomming_vectors = request.vectors();
         info!("Received {} vectors from client", incomming_vectors.len());
 
         // Ensure that incomming vectors are homogeneous in their length, type
         // and that the number of request vectors are less that the maximum allowed
         // number of vectors per instance.
-        match check_incomming_vectors(&incomming_vectors) {
+        match check_incomming_vectors(incomming_vectors) {
             Ok(_) => {}
             Err(error) => {
                 error!("Error : {}", error);
@@ -110,27 +148,86 @@ impl ClientHandler {
         }
 
         // Once the vectors are "accepted", then generate an instance and derive a public key
-        // and compute all the secrets keys for the requested vectors
+        // and compute all the secrets keys for the requested vectors, using the vector size
+        // associated to the kind of fuzzy hash.
         info!("Generate parameters");
-        match incomming_vectors[0] {
-            FHVector::<_>::NilsimsaVector(_) => {
-                let response = generate_parameters_nilsimsa(incomming_vectors);
-                info!("Encoding response");
-                self.write_frame(postcard::to_stdvec(&response)?).await?;
-                info!("Sended public key/secret keys to client")
+        match (&incomming_vectors[0], streamed) {
+            (FHVector::<_>::NilsimsaVector(_), false) => {
+                self.send_parameters::<NILSIMSA_VECTOR_SIZE_BITS>(incomming_vectors)
+                    .await
             }
+            (FHVector::<_>::NilsimsaVector(_), true) => {
+                self.stream_parameters::<NILSIMSA_VECTOR_SIZE_BITS>(incomming_vectors)
+                    .await
+            }
+            (FHVector::<_>::FeatureVector(_), false) => {
+                self.send_parameters::<FEATURE_VECTOR_SIZE_BITS>(incomming_vectors)
+                    .await
+            }
+            (FHVector::<_>::FeatureVector(_), true) => {
+                self.stream_parameters::<FEATURE_VECTOR_SIZE_BITS>(incomming_vectors)
+                    .await
+            }
+        }
+    }
+
+    /// Generate the parameters for vectors of size N and send them to the client
+    async fn send_parameters<const N: usize>(
+        &mut self,
+        incomming_vectors: &GenerateInstanceRequest<u8>,
+    ) -> Result<()>
+    where
+        AuthorityResponse: From<GenerateInstanceResponse<N>>,
+    {
+        let vectors = self.accept_vectors::<N>(incomming_vectors)?;
+        let response = AuthorityResponse::from(generate_parameters(&vectors)?);
+
+        info!("Encoding response");
+        self.write_frame(postcard::to_stdvec(&response)?).await?;
+        // Nothing else is sent, shut down the write half before the connection is dropped
+        self.stream.close().await?;
+        info!("Sended public key/secret keys to client");
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ClientHandler<S> {
+    /// Same as send_parameters, but the public key is sent first, then each secret key in
+    /// its own frame as soon as it is derived (one after the other, even with the
+    /// `parallel` feature), so that the client can process the first keys early.
+    async fn stream_parameters<const N: usize>(
+        &mut self,
+        incomming_vectors: &GenerateInstanceRequest<u8>,
+    ) -> Result<()>
+    where
+        AuthorityResponse: From<GenerateInstanceResponse<N>>,
+    {
+        let vectors = self.accept_vectors::<N>(incomming_vectors)?;
+
+        // The instance is large, keep it on the heap rather than in the future
+        let instance = Box::new(Instance::<N>::setup());
+        let header = postcard::to_stdvec(&AuthorityResponse::from(GenerateInstanceResponse(
+            instance.public_key(),
+            vec![],
+        )))?;
+        self.write_frame(header).await?;
+
+        for vector in &vectors {
+            let sk =
+                postcard::to_stdvec(&CompressedSecretKey::from(&instance.secret_key(*vector)))?;
+            self.write_frame(sk).await?;
         }
+        self.stream.close().await?;
+        info!("Streamed public key/secret keys to client");
         Ok(())
     }
 }
 
 /// Helper function, this function ensures that the vectors are all the same length, the same type
-/// and that it as at least one vector (and no more than the maximum number of vectors allowed )
+/// and that it as at least one vector.
 fn check_incomming_vectors(incomming_vectors: &GenerateInstanceRequest<u8>) -> Result<()> {
-    match incomming_vectors.len() {
-        0 => return Err(anyhow!("Received empty message, abort")),
-        SERVER_MAX_LEN => return Err(anyhow!("Received too much vectors, abort")),
-        _ => {}
+    if incomming_vectors.is_empty() {
+        return Err(anyhow!("Received empty message, abort"));
     }
 
     let all_same_kind = incomming_vectors
@@ -146,23 +243,223 @@ fn check_incomming_vectors(incomming_vectors: &GenerateInstanceRequest<u8>) -> R
 
 /// Generate the instance, the public key and all the secret keys given
 /// a "checked" request from a compute server.
-fn generate_parameters_nilsimsa(
-    requested_vectors: GenerateInstanceRequest<u8>,
-) -> GenerateInstanceResponse<NILSIMSA_VECTOR_SIZE_BITS> {
+///
+/// A single instance can't be used for N vectors (or more) of size N, otherwise the
+/// compute server could recover the client vector. Such requests are simply refused.
+/// For the same reason an instance is never reused across requests, a new one is set up
+/// for each request and its master secret key is dropped once the keys are derived.
+fn generate_parameters<const N: usize>(
+    requested_vectors: &[[u8; N]],
+) -> Result<GenerateInstanceResponse<N>> {
+    check_instance_capacity::<N>(requested_vectors.len())?;
+
     let instance = Instance::setup();
-    let pk: PublicKey<NILSIMSA_VECTOR_SIZE_BITS> = instance.public_key::<u8>();
-    let sk_vec: Vec<SecretKey<NILSIMSA_VECTOR_SIZE_BITS>> = requested_vectors
+    let pk: PublicKey<N> = instance.public_key();
+    let sk_vec = derive_secret_keys(&instance, requested_vectors);
+
+    Ok(GenerateInstanceResponse::from((pk, sk_vec)))
+}
+
+/// A single instance can't derive keys for N vectors (or more) of size N.
+fn check_instance_capacity<const N: usize>(vectors: usize) -> Result<()> {
+    if vectors >= N {
+        return Err(anyhow!("Received too much vectors, abort"));
+    }
+    Ok(())
+}
+
+/// Derive the secret key of each vector, the keys are in the same order as the vectors
+/// (the compute server matches them with its fuzzy hashes by position).
+fn derive_secret_keys<const N: usize>(
+    instance: &Instance<N>,
+    vectors: &[[u8; N]],
+) -> Vec<SecretKey<N>> {
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        vectors
+            .par_iter()
+            .map(|vector| instance.secret_key(*vector))
+            .collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    vectors
         .iter()
-        .map(|vector| {
-            match vector {
-                FHVector::<_>::NilsimsaVector(v_bytes) => {
-                    let v: [u8; NILSIMSA_VECTOR_SIZE_BITS] =
-                        array::from_fn(|i| 1 & (v_bytes[i / 8] >> (7 - (i % 8))));
-                    return instance.secret_key(v);
-                }
-            };
-        })
-        .collect();
+        .map(|vector| instance.secret_key(*vector))
+        .collect()
+}
 
-    GenerateInstanceResponse::from((pk, sk_vec))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quota::Quota;
+    use fe::traits::{FEPubKey, FESecretKey};
+    use std::time::Duration;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    fn check_parameters<const N: usize>(vectors: &[[u8; N]]) {
+        let response = generate_parameters(vectors).unwrap();
+        let (pk, sks) = response.decompress().unwrap();
+        assert_eq!(sks.len(), vectors.len());
+
+        let client_vec = [1u8; N];
+        for (sk, vector) in sks.iter().zip(vectors) {
+            let ct = pk.encrypt_with_seed([7; 32], client_vec);
+            let expected: u16 = vector.iter().map(|b| *b as u16).sum();
+            assert_eq!(sk.decrypt(ct, N as u16 + 1), Some(expected));
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_parallel_secret_keys() {
+        let instance = Instance::<NILSIMSA_VECTOR_SIZE_BITS>::setup();
+        let vectors: Vec<[u8; NILSIMSA_VECTOR_SIZE_BITS]> = (0..100)
+            .map(|i| core::array::from_fn(|j| ((i * 7 + j) % 3 == 0) as u8))
+            .collect();
+
+        let sequential: Vec<SecretKey<NILSIMSA_VECTOR_SIZE_BITS>> = vectors
+            .iter()
+            .map(|vector| instance.secret_key(*vector))
+            .collect();
+        let parallel = derive_secret_keys(&instance, &vectors);
+
+        let bytes = |sks: &[SecretKey<NILSIMSA_VECTOR_SIZE_BITS>]| -> Vec<Vec<u8>> {
+            sks.iter().map(|sk| sk.to_compact_bytes()).collect()
+        };
+        assert_eq!(bytes(&parallel), bytes(&sequential));
+    }
+
+    #[test]
+    fn test_generate_parameters_any_size() {
+        // Size of another kind of fuzzy hash
+        check_parameters::<16>(&[[1; 16], [0; 16], core::array::from_fn(|i| (i % 2) as u8)]);
+
+        let nilsimsa = FHVector::from([0xa5u8; 32]);
+        check_parameters::<NILSIMSA_VECTOR_SIZE_BITS>(&[nilsimsa.to_fe_vector().unwrap()]);
+    }
+
+    /// Ask the authority at addr for the keys of n vectors, the answer is None if the
+    /// connection is closed without one.
+    async fn request_vectors(addr: std::net::SocketAddr, n: usize) -> Option<Vec<u8>> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = AuthorityRequest::Generate(vec![FHVector::from([0x5au8; 32]); n]);
+        let mut writer = FramedWrite::new(&mut stream, codec());
+        writer
+            .send(messages::encode_versioned(&request).unwrap().into())
+            .await
+            .unwrap();
+
+        let mut reader = FramedRead::new(&mut stream, codec());
+        reader
+            .next()
+            .await
+            .and_then(|frame| frame.ok())
+            .map(|frame| frame.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_quota_exceeded() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let quota = QuotaTracker::new(Quota {
+            max_vectors: 10,
+            window: Duration::from_secs(60),
+        });
+        let mut server = Server::new(listener).with_quota(quota);
+        tokio::spawn(async move { server.run().await });
+
+        assert!(request_vectors(addr, 6).await.is_some());
+        // The connection is closed without answer once the quota is exceeded
+        assert!(request_vectors(addr, 6).await.is_none());
+        assert!(request_vectors(addr, 4).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_refused_request_keeps_quota() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let quota = QuotaTracker::new(Quota {
+            max_vectors: NILSIMSA_VECTOR_SIZE_BITS + 4,
+            window: Duration::from_secs(60),
+        });
+        let mut server = Server::new(listener).with_quota(quota);
+        tokio::spawn(async move { server.run().await });
+
+        // Too much vectors for a single instance, within the quota but refused anyway
+        assert!(
+            request_vectors(addr, NILSIMSA_VECTOR_SIZE_BITS)
+                .await
+                .is_none()
+        );
+        assert!(request_vectors(addr, 6).await.is_some());
+    }
+
+    #[test]
+    fn test_new_instance_per_request() {
+        let vectors = [[1u8; 16], [0; 16]];
+        let (pk, sks) = generate_parameters(&vectors).unwrap().decompress().unwrap();
+        let (other_pk, other_sks) = generate_parameters(&vectors).unwrap().decompress().unwrap();
+        assert_ne!(
+            messages::key_fingerprint(&pk).unwrap(),
+            messages::key_fingerprint(&other_pk).unwrap()
+        );
+
+        // The keys of a request don't decrypt the ciphertexts of another one
+        let ct = other_pk.encrypt_with_seed([7; 32], [1u8; 16]);
+        assert_eq!(other_sks[0].decrypt(ct.clone(), 17), Some(16));
+        assert_eq!(sks[0].decrypt(ct, 17), None);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_parameters() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = Server::new(listener);
+        tokio::spawn(async move { server.run().await });
+
+        let hashes: Vec<FHVector<u8>> = (0..3u8).map(|i| FHVector::from([i; 32])).collect();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut framed = tokio_util::codec::Framed::new(&mut stream, codec());
+        let request = AuthorityRequest::Stream(hashes.clone());
+        framed
+            .send(messages::encode_versioned(&request).unwrap().into())
+            .await
+            .unwrap();
+
+        // The public key first, without secret keys
+        let header: AuthorityResponse =
+            postcard::from_bytes(&framed.next().await.unwrap().unwrap()).unwrap();
+        let header =
+            GenerateInstanceResponse::<NILSIMSA_VECTOR_SIZE_BITS>::try_from(header).unwrap();
+        assert!(header.1.is_empty());
+        let pk = header.0;
+
+        // Then one frame per secret key, in the order of the vectors
+        let client_vec = hashes[1]
+            .to_fe_vector::<NILSIMSA_VECTOR_SIZE_BITS>()
+            .unwrap();
+        let ct = pk.encrypt_with_seed([7; 32], client_vec);
+        for hash in &hashes {
+            let frame = framed.next().await.unwrap().unwrap();
+            let compressed: CompressedSecretKey = postcard::from_bytes(&frame).unwrap();
+            let sk = SecretKey::<NILSIMSA_VECTOR_SIZE_BITS>::try_from(&compressed).unwrap();
+
+            let vector = hash.to_fe_vector::<NILSIMSA_VECTOR_SIZE_BITS>().unwrap();
+            let expected: u16 = vector
+                .iter()
+                .zip(client_vec)
+                .map(|(a, b)| (*a & b) as u16)
+                .sum();
+            assert_eq!(sk.decrypt(ct.clone(), 257), Some(expected));
+        }
+        assert!(framed.next().await.is_none());
+    }
+
+    #[test]
+    fn test_generate_parameters_too_much_vectors() {
+        assert!(generate_parameters(&[[1u8; 4]; 3]).is_ok());
+        assert!(generate_parameters(&[[1u8; 4]; 4]).is_err());
+        assert!(generate_parameters(&[[1u8; 4]; 5]).is_err());
+    }
 }
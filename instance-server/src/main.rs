@@ -1,27 +1,48 @@
 mod instance_server;
+mod quota;
 use crate::instance_server::Server;
+use crate::quota::{Quota, QuotaTracker};
 
 use anyhow::Result;
+use clap::Parser;
 use log::info;
+use std::time::Duration;
 use tokio::net::TcpListener;
 
+#[derive(Parser)]
+struct Cli {
+    /// Address:port to bind
+    bind: String,
+    /// Maximum number of vectors each compute server can request per quota window
+    /// (unlimited by default)
+    #[clap(long)]
+    max_vectors: Option<usize>,
+    /// Length of the quota window in seconds
+    #[clap(long, default_value_t = 60, requires = "max_vectors")]
+    quota_window: u64,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
 
-    let to_bind = std::env::args()
-        .nth(1)
-        .expect("Please provide an address:port to bind");
+    let args = Cli::parse();
 
-    let socket = match TcpListener::bind(&to_bind).await {
+    let socket = match TcpListener::bind(&args.bind).await {
         Ok(listener) => {
             info!("Successfuly started server");
             listener
         }
-        Err(e) => panic!("Unable to bind {} : {}", &to_bind, e),
+        Err(e) => panic!("Unable to bind {} : {}", &args.bind, e),
     };
 
     let mut server = Server::new(socket);
+    if let Some(max_vectors) = args.max_vectors {
+        server = server.with_quota(QuotaTracker::new(Quota {
+            max_vectors,
+            window: Duration::from_secs(args.quota_window),
+        }));
+    }
     server.run().await?;
     Ok(())
 }
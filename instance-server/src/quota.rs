@@ -0,0 +1,90 @@
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Maximum number of vectors the authority derives secret keys for, for each compute
+/// server (i.e peer address) over a time window. A compute server opens a new connection
+/// for each batch, so the limit is not tied to a connection.
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    pub max_vectors: usize,
+    pub window: Duration,
+}
+
+/// Vectors requested by each peer during its current window.
+#[derive(Debug)]
+pub struct QuotaTracker {
+    quota: Quota,
+    usage: Mutex<HashMap<IpAddr, (Instant, usize)>>,
+}
+
+impl QuotaTracker {
+    pub fn new(quota: Quota) -> Self {
+        Self {
+            quota,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count the vectors requested by the peer, the request is refused (and not counted)
+    /// if it would exceed the quota of the peer.
+    pub fn consume(&self, peer: IpAddr, vectors: usize) -> Result<()> {
+        let now = Instant::now();
+        let mut usage = self.usage.lock().unwrap();
+        // Forget the peers whose window is over
+        usage.retain(|_, (start, _)| now.duration_since(*start) < self.quota.window);
+
+        let (_, used) = usage.entry(peer).or_insert((now, 0));
+        if *used + vectors > self.quota.max_vectors {
+            return Err(anyhow!(
+                "{} exceeded its quota of {} vectors per {:?}, abort",
+                peer,
+                self.quota.max_vectors,
+                self.quota.window
+            ));
+        }
+        *used += vectors;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_quota_per_peer() {
+        let tracker = QuotaTracker::new(Quota {
+            max_vectors: 10,
+            window: Duration::from_secs(60),
+        });
+        let peer = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        assert!(tracker.consume(peer, 6).is_ok());
+        assert!(tracker.consume(peer, 5).is_err());
+        // A refused request is not counted
+        assert!(tracker.consume(peer, 4).is_ok());
+        assert!(tracker.consume(peer, 1).is_err());
+
+        // Each peer has its own quota
+        assert!(tracker.consume(other, 10).is_ok());
+    }
+
+    #[test]
+    fn test_quota_window() {
+        let tracker = QuotaTracker::new(Quota {
+            max_vectors: 10,
+            window: Duration::from_millis(50),
+        });
+        let peer = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        assert!(tracker.consume(peer, 10).is_ok());
+        assert!(tracker.consume(peer, 1).is_err());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(tracker.consume(peer, 10).is_ok());
+    }
+}
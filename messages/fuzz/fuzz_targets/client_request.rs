@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use messages::{ClientRequest, decode_versioned};
+
+// First frame received by the compute server from a client
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_versioned::<ClientRequest>(data);
+});
@@ -0,0 +1,14 @@
+#![no_main]
+
+use fuzzy_hashes::NILSIMSA_VECTOR_SIZE_BITS as N;
+use libfuzzer_sys::fuzz_target;
+use messages::EncryptionRequest;
+
+// Public key (and score) received by the client from the compute server
+fuzz_target!(|data: &[u8]| {
+    if let Ok(request) = postcard::from_bytes::<EncryptionRequest<N, i16>>(data)
+        && let Some(pk) = request.pk
+    {
+        let _ = pk.validate();
+    }
+});
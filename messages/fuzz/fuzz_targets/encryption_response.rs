@@ -0,0 +1,10 @@
+#![no_main]
+
+use fuzzy_hashes::NILSIMSA_VECTOR_SIZE_BITS as N;
+use libfuzzer_sys::fuzz_target;
+use messages::EncryptionResponse;
+
+// Ciphertext received by the compute server from the client
+fuzz_target!(|data: &[u8]| {
+    let _ = postcard::from_bytes::<EncryptionResponse<N>>(data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use messages::{AuthorityRequest, decode_versioned};
+
+// First frame received by the authority from a compute server
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_versioned::<AuthorityRequest>(data);
+});
@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use messages::AuthorityResponse;
+
+// Keys received by the compute server from the authority, including their decompression
+fuzz_target!(|data: &[u8]| {
+    if let Ok(response) = postcard::from_bytes::<AuthorityResponse>(data) {
+        let _ = match response {
+            AuthorityResponse::NilsimsaVector(response) => response.decompress().map(|_| ()),
+            AuthorityResponse::FeatureVector(response) => response.decompress().map(|_| ()),
+        };
+    }
+});
@@ -3,9 +3,62 @@
 //! Module containing all the messages exchanged over the network
 // between the Authority, the Compute Server and the Client.
 use anyhow::{Error, Result, anyhow};
-use fe::{CipherText, CompressedSecretKey, PublicKey, SecretKey};
-use fuzzy_hashes::FHVector;
-use serde::{Deserialize, Serialize};
+use fe::{CipherText, CompressedSecretKey, EncryptionProof, PublicKey, SecretKey};
+use fuzzy_hashes::{FEATURE_VECTOR_SIZE_BITS, FHVector, NILSIMSA_VECTOR_SIZE_BITS};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sha3::{Digest, Sha3_256};
+use tokio_util::codec::LengthDelimitedCodec;
+
+/// Version of the protocol, it has to be increased on any change of the messages layout.
+/// It is sent at the beginning of the first frame of any exchange, so that peers using
+/// different versions of the protocol are rejected with a clear error.
+pub const PROTOCOL_VERSION: u16 = 10;
+
+/// Maximum length of a frame. The largest message is a GenerateInstanceResponse for Nilsimsa
+/// vectors (a public key and up to 511 secret keys). With binary keys (i.e of fuzzy hashes) it
+/// is less than 1 MiB with the finite field backend (and far less with the elliptic curve one).
+/// Keys of weighted vectors are kept as scalars, about 16 KiB each for Nilsimsa vectors with
+/// the elliptic curve backend, so fewer of them fit in a frame (see
+/// GenerateInstanceResponse::max_batch_size).
+pub const MAX_FRAME_LENGTH: usize = 2 * 1024 * 1024;
+
+/// Codec used to read and write the frames of the protocol. Frames announcing a length
+/// greater than MAX_FRAME_LENGTH are rejected with an error before anything is allocated.
+pub fn codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .max_frame_length(MAX_FRAME_LENGTH)
+        .new_codec()
+}
+
+/// Unwrap the result of reading the next frame from a peer, so that a closed connection
+/// or an invalid frame (e.g too long) is returned as an error instead of a panic.
+pub fn received<T>(frame: Option<std::io::Result<T>>) -> Result<T> {
+    match frame {
+        Some(frame) => Ok(frame?),
+        None => Err(anyhow!("Connection closed by the peer")),
+    }
+}
+
+/// Serialize a message prefixed by the protocol version. This is used for the
+/// first frame of an exchange (i.e ClientRequest and AuthorityRequest).
+pub fn encode_versioned<T: Serialize>(message: &T) -> Result<Vec<u8>> {
+    Ok(postcard::to_stdvec(&(PROTOCOL_VERSION, message))?)
+}
+
+/// Deserialize a message prefixed by the protocol version, the version is checked
+/// before trying to deserialize the message itself.
+pub fn decode_versioned<T: DeserializeOwned>(frame: &[u8]) -> Result<T> {
+    let (version, message) = postcard::take_from_bytes::<u16>(frame)?;
+    if version != PROTOCOL_VERSION {
+        return Err(anyhow!(
+            "Protocol version mismatch : received {}, expected {}",
+            version,
+            PROTOCOL_VERSION
+        ));
+    }
+
+    Ok(postcard::from_bytes(message)?)
+}
 
 /*
     Messages between an Authority and a Compute server
@@ -14,12 +67,45 @@ use serde::{Deserialize, Serialize};
 /// generate a public key and encrypt the provided vectors in the GenerateInstanceRequest.
 pub type GenerateInstanceRequest<T> = Vec<FHVector<T>>;
 
+/// Request send to the Authority by the Compute server, the vectors are replied to with
+/// a GenerateInstanceResponse in a single frame, or streamed.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AuthorityRequest {
+    /// Reply with a single AuthorityResponse carrying the public key and every secret key.
+    Generate(GenerateInstanceRequest<u8>),
+    /// Reply with an AuthorityResponse carrying only the public key, followed by one frame
+    /// per CompressedSecretKey (in the order of the vectors), each one sent as soon as it
+    /// is derived.
+    Stream(GenerateInstanceRequest<u8>),
+}
+
+impl AuthorityRequest {
+    /// Vectors whose secret keys are requested.
+    pub fn vectors(&self) -> &GenerateInstanceRequest<u8> {
+        match self {
+            AuthorityRequest::Generate(vectors) | AuthorityRequest::Stream(vectors) => vectors,
+        }
+    }
+}
+
 /// Reply send to the Compute server by the Authority. It contains the secret keys for the
 /// previously requested vectors and the associated public key.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GenerateInstanceResponse<const N: usize>(pub PublicKey<N>, pub Vec<CompressedSecretKey>);
 
 impl<const N: usize> GenerateInstanceResponse<N> {
+    /// Maximum number of secret keys as large as `key` in a response along `pk`, so that it
+    /// fits in a frame. It is at most N - 1 (the size of a batch), which is reached by binary
+    /// keys but not by weighted ones with the elliptic curve backend.
+    pub fn max_batch_size(pk: &PublicKey<N>, key: &CompressedSecretKey) -> Result<usize> {
+        // Tag of the AuthorityResponse and length of the vector of keys
+        const OVERHEAD: usize = 16;
+        let pk_len = postcard::to_stdvec(pk)?.len();
+        let key_len = postcard::to_stdvec(key)?.len();
+        let available = MAX_FRAME_LENGTH.saturating_sub(pk_len + OVERHEAD);
+        Ok((available / key_len).min(N - 1))
+    }
+
     /// "Decompress" the response to retrieve the PublicKey and the SecretKey with
     /// the correct types for the underlying FE implementation.
     pub fn decompress(&self) -> Result<(PublicKey<N>, Vec<SecretKey<N>>), Error> {
@@ -48,17 +134,112 @@ impl<const N: usize> From<(PublicKey<N>, Vec<SecretKey<N>>)> for GenerateInstanc
     }
 }
 
+/// Reply send to the Compute server by the Authority, tagged with the size of the vectors
+/// (i.e the kind of fuzzy hash) so that it can be deserialized without knowing the size.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AuthorityResponse {
+    /// Keys for Nilsimsa vectors
+    NilsimsaVector(GenerateInstanceResponse<NILSIMSA_VECTOR_SIZE_BITS>),
+    /// Keys for feature vectors
+    FeatureVector(GenerateInstanceResponse<FEATURE_VECTOR_SIZE_BITS>),
+}
+
+impl AuthorityResponse {
+    /// Size of the vectors of the keys carried by the response.
+    pub fn vector_size(&self) -> usize {
+        match self {
+            AuthorityResponse::NilsimsaVector(_) => NILSIMSA_VECTOR_SIZE_BITS,
+            AuthorityResponse::FeatureVector(_) => FEATURE_VECTOR_SIZE_BITS,
+        }
+    }
+}
+
+impl From<GenerateInstanceResponse<NILSIMSA_VECTOR_SIZE_BITS>> for AuthorityResponse {
+    fn from(value: GenerateInstanceResponse<NILSIMSA_VECTOR_SIZE_BITS>) -> Self {
+        AuthorityResponse::NilsimsaVector(value)
+    }
+}
+
+impl From<GenerateInstanceResponse<FEATURE_VECTOR_SIZE_BITS>> for AuthorityResponse {
+    fn from(value: GenerateInstanceResponse<FEATURE_VECTOR_SIZE_BITS>) -> Self {
+        AuthorityResponse::FeatureVector(value)
+    }
+}
+
+impl TryFrom<AuthorityResponse> for GenerateInstanceResponse<NILSIMSA_VECTOR_SIZE_BITS> {
+    type Error = Error;
+
+    fn try_from(value: AuthorityResponse) -> Result<Self> {
+        match value {
+            AuthorityResponse::NilsimsaVector(response) => Ok(response),
+            other => Err(unexpected_size(NILSIMSA_VECTOR_SIZE_BITS, &other)),
+        }
+    }
+}
+
+impl TryFrom<AuthorityResponse> for GenerateInstanceResponse<FEATURE_VECTOR_SIZE_BITS> {
+    type Error = Error;
+
+    fn try_from(value: AuthorityResponse) -> Result<Self> {
+        match value {
+            AuthorityResponse::FeatureVector(response) => Ok(response),
+            other => Err(unexpected_size(FEATURE_VECTOR_SIZE_BITS, &other)),
+        }
+    }
+}
+
+fn unexpected_size(expected: usize, response: &AuthorityResponse) -> Error {
+    anyhow!(
+        "Expected keys for vectors of size {}, received keys for vectors of size {}",
+        expected,
+        response.vector_size()
+    )
+}
+
 /*
     Messages between a Client and a Compute server.
 */
+/// First request send to the compute server by the client.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClientRequest {
+    /// The client wants to compare its fuzzy hash of the given type, optionally only
+    /// against the fuzzy hashes of the corpus with the given tag (e.g a dataset label).
+    Compare(HashComparisonRequest, Option<String>),
+    /// The client wants to compare its fuzzy hash of the given type against the given fuzzy
+    /// hashes instead of the corpus (e.g for tests). They are compared as a single batch, so
+    /// there can't be more than the vector size minus one of them.
+    CompareInline(HashComparisonRequest, Vec<FHVector<u8>>),
+    /// The client wants to know which fuzzy hashes can be compared by the server.
+    ListSupportedHashes,
+    /// The client wants to know which vectors to encrypt for the given fuzzy hash. The
+    /// connection stays open afterwards, for the request of the comparison.
+    DescribeHash(HashComparisonRequest),
+}
+
 /// Request send to the compute server by the client
 /// to indicate which fuzzy hash to compare.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum HashComparisonRequest {
     /// Indicate that the client wants to compare Nilsimsa fuzzy hash.
     NILSIMSA,
 }
 
+/// Reply of the compute server to a ListSupportedHashes request.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SupportedHashesResponse(pub Vec<HashComparisonRequest>);
+
+/// Reply of the compute server to a DescribeHash request, i.e the vectors it expects the
+/// client to encrypt for this fuzzy hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HashParameters {
+    /// Number of entries of the vectors (i.e the N of the public keys sent to the client)
+    pub dimension: u32,
+    /// Bound (exclusive) of the inner products decrypted by the compute server, larger
+    /// ones can't be recovered
+    pub bound: u16,
+}
+
 /// Request to the client to encrypt its hash using
 /// the given public key in the request
 #[derive(Debug, Serialize, Deserialize)]
@@ -68,11 +249,251 @@ pub struct EncryptionRequest<const N: usize, T> {
     /// Potential similarity score of any computed by the server
     /// before sending that encryption request
     pub similarity_score: Option<T>,
+    /// Nonce of the session, if the server binds the ciphertexts to it (the client then
+    /// replies with a BoundVector)
+    pub session: Option<SessionNonce>,
 }
 
+/// Random nonce chosen by the compute server for a comparison session.
+pub type SessionNonce = [u8; 32];
+
+impl<const N: usize, T> EncryptionRequest<N, T> {
+    /// Request the client to encrypt its fuzzy hash under the given public key, along
+    /// with the similarity score computed so far.
+    pub fn with_key(pk: PublicKey<N>, similarity_score: T) -> Self {
+        Self {
+            pk: Some(pk),
+            similarity_score: Some(similarity_score),
+            session: None,
+        }
+    }
+
+    /// Ask the client to bind its ciphertext to the given session, so that it can't be
+    /// replayed in another session.
+    pub fn with_session(mut self, session: SessionNonce) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Indicate to the client that the whole database has been compared,
+    /// along with the final similarity score.
+    pub fn end(similarity_score: T) -> Self {
+        Self {
+            pk: None,
+            similarity_score: Some(similarity_score),
+            session: None,
+        }
+    }
+
+    /// Return true if this is the last request of the comparison.
+    pub fn is_end(&self) -> bool {
+        self.pk.is_none()
+    }
+}
+
+/// Fingerprint of a public key (SHA3-256 of the serialized key).
+pub type KeyFingerprint = [u8; 32];
+
+/// Compute the fingerprint of a public key. The client sends it along its ciphertext, so that
+/// the compute server can check that it was encrypted under the expected key (e.g not under
+/// the stale key of a previous batch).
+pub fn key_fingerprint<const N: usize>(pk: &PublicKey<N>) -> Result<KeyFingerprint> {
+    Ok(Sha3_256::digest(postcard::to_stdvec(pk)?).into())
+}
+
+/// Response of the client to an EncryptionRequest.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Serialize, Deserialize)]
 pub enum EncryptionResponse<const N: usize> {
-    /// The client send an encrypted fuzzy hash to compare
-    EncryptedVector(CipherText<N>),
+    /// The client send an encrypted fuzzy hash to compare, along with the fingerprint
+    /// of the public key used to encrypt it
+    EncryptedVector(CipherText<N>, KeyFingerprint),
+    /// Same as EncryptedVector, along with the proof that the ciphertext was computed for
+    /// the session of the request
+    BoundVector(CipherText<N>, KeyFingerprint, EncryptionProof),
+    /// The client does not want to compare more fuzzy hashes
     EndOfComparison,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fe::Instance;
+    use fe::traits::FEInstance;
+    use fuzzy_hashes::NILSIMSA_VECTOR_SIZE_BITS as N;
+    use tokio_util::bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+
+    #[test]
+    fn test_key_fingerprint() {
+        let pk = Instance::<16>::setup().public_key();
+        let other = Instance::<16>::setup().public_key();
+        let bytes = postcard::to_stdvec(&pk).unwrap();
+        let received: PublicKey<16> = postcard::from_bytes(&bytes).unwrap();
+
+        assert_eq!(
+            key_fingerprint(&pk).unwrap(),
+            key_fingerprint(&received).unwrap()
+        );
+        assert_ne!(
+            key_fingerprint(&pk).unwrap(),
+            key_fingerprint(&other).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_binary_and_weighted_keys_roundtrip() {
+        let instance = Instance::<16>::setup();
+        let binary = instance.secret_key([0u8, 1].repeat(8).try_into().unwrap());
+        let weighted = instance.secret_key(core::array::from_fn(|i| i as u8 * 3));
+        let expected = vec![binary.to_compact_bytes(), weighted.to_compact_bytes()];
+
+        let response =
+            GenerateInstanceResponse::from((instance.public_key(), vec![binary, weighted]));
+        let bytes = postcard::to_stdvec(&response).unwrap();
+        let received: GenerateInstanceResponse<16> = postcard::from_bytes(&bytes).unwrap();
+
+        let (_, sks) = received.decompress().unwrap();
+        let sks: Vec<_> = sks.iter().map(|sk| sk.to_compact_bytes()).collect();
+        assert_eq!(sks, expected);
+    }
+
+    #[test]
+    fn test_authority_response_sizes() {
+        let nilsimsa = Instance::<N>::setup();
+        let response = GenerateInstanceResponse::from((
+            nilsimsa.public_key(),
+            vec![nilsimsa.secret_key([1u8; N])],
+        ));
+        let bytes = postcard::to_stdvec(&AuthorityResponse::from(response)).unwrap();
+
+        let features = Instance::<FEATURE_VECTOR_SIZE_BITS>::setup();
+        let response = GenerateInstanceResponse::from((
+            features.public_key(),
+            vec![features.secret_key([0u8; FEATURE_VECTOR_SIZE_BITS]); 2],
+        ));
+        let feature_bytes = postcard::to_stdvec(&AuthorityResponse::from(response)).unwrap();
+
+        // Both are deserialized through the same type, the size being read from the message
+        let received: AuthorityResponse = postcard::from_bytes(&bytes).unwrap();
+        assert_eq!(received.vector_size(), N);
+        let (_, sks) = GenerateInstanceResponse::<N>::try_from(received)
+            .unwrap()
+            .decompress()
+            .unwrap();
+        assert_eq!(sks.len(), 1);
+
+        let received: AuthorityResponse = postcard::from_bytes(&feature_bytes).unwrap();
+        assert_eq!(received.vector_size(), FEATURE_VECTOR_SIZE_BITS);
+        assert!(GenerateInstanceResponse::<N>::try_from(received).is_err());
+
+        let received: AuthorityResponse = postcard::from_bytes(&feature_bytes).unwrap();
+        let (_, sks) = GenerateInstanceResponse::<FEATURE_VECTOR_SIZE_BITS>::try_from(received)
+            .unwrap()
+            .decompress()
+            .unwrap();
+        assert_eq!(sks.len(), 2);
+    }
+
+    #[test]
+    fn test_largest_message_fits_in_frame() {
+        let instance = Instance::<N>::setup();
+        let pk = instance.public_key();
+        let sk = instance.secret_key([1u8; N]);
+
+        let response = GenerateInstanceResponse::from((pk, vec![sk; N - 1]));
+        let bytes = postcard::to_stdvec(&response).unwrap();
+        assert!(bytes.len() < MAX_FRAME_LENGTH);
+    }
+
+    #[test]
+    fn test_weighted_batch_size() {
+        let instance = Instance::<N>::setup();
+        let pk = instance.public_key();
+        let binary = instance.secret_key([1u8; N]);
+        let weighted = instance.secret_key(core::array::from_fn(|i| (i % 251) as u8));
+
+        let max =
+            |sk| GenerateInstanceResponse::max_batch_size(&pk, &CompressedSecretKey::from(sk));
+        assert_eq!(max(&binary).unwrap(), N - 1);
+        // Less than N - 1 with the elliptic curve backend, then one more key doesn't fit
+        let max_weighted = max(&weighted).unwrap();
+
+        let encoded_len = |keys| {
+            let response = GenerateInstanceResponse::from((pk.clone(), keys));
+            postcard::to_stdvec(&AuthorityResponse::from(response))
+                .unwrap()
+                .len()
+        };
+        assert!(encoded_len(vec![weighted.clone(); max_weighted]) <= MAX_FRAME_LENGTH);
+        if max_weighted < N - 1 {
+            assert!(encoded_len(vec![weighted; max_weighted + 1]) > MAX_FRAME_LENGTH);
+        }
+    }
+
+    #[test]
+    fn test_oversized_frame_rejected() {
+        let mut codec = codec();
+
+        // Only the header announcing the length is needed to reject the frame
+        let mut buf = BytesMut::from(&((MAX_FRAME_LENGTH + 1) as u32).to_be_bytes()[..]);
+        assert!(codec.decode(&mut buf).is_err());
+
+        // A frame of the maximum length is fine
+        let mut buf = BytesMut::from(&(MAX_FRAME_LENGTH as u32).to_be_bytes()[..]);
+        buf.extend_from_slice(&vec![0; MAX_FRAME_LENGTH]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap().unwrap().len(),
+            MAX_FRAME_LENGTH
+        );
+    }
+
+    #[test]
+    fn test_versioned_roundtrip() {
+        let request = ClientRequest::Compare(HashComparisonRequest::NILSIMSA, Some("a".into()));
+        let frame = encode_versioned(&request).unwrap();
+        let request: ClientRequest = decode_versioned(&frame).unwrap();
+        assert!(matches!(
+            request,
+            ClientRequest::Compare(HashComparisonRequest::NILSIMSA, Some(tag)) if tag == "a"
+        ));
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let frame =
+            postcard::to_stdvec(&(PROTOCOL_VERSION + 1, HashComparisonRequest::NILSIMSA)).unwrap();
+        let error = decode_versioned::<HashComparisonRequest>(&frame).unwrap_err();
+        assert!(error.to_string().contains("version mismatch"));
+
+        // Frame without any version
+        assert!(decode_versioned::<HashComparisonRequest>(&[]).is_err());
+    }
+
+    #[test]
+    fn test_encryption_request_constructors() {
+        use fe::Instance;
+        use fe::traits::FEInstance;
+
+        let pk = Instance::<8>::setup().public_key();
+
+        let request = EncryptionRequest::<8, i16>::with_key(pk, 12);
+        assert!(request.pk.is_some());
+        assert_eq!(request.similarity_score, Some(12));
+        assert_eq!(request.session, None);
+        assert!(!request.is_end());
+        assert_eq!(request.with_session([7; 32]).session, Some([7; 32]));
+
+        let request = EncryptionRequest::<8, i16>::end(42);
+        assert!(request.pk.is_none());
+        assert_eq!(request.similarity_score, Some(42));
+        assert!(request.is_end());
+    }
+
+    #[test]
+    fn test_closed_connection() {
+        assert!(received::<Vec<u8>>(None).is_err());
+        assert!(received::<Vec<u8>>(Some(Err(std::io::ErrorKind::InvalidData.into()))).is_err());
+        assert_eq!(received(Some(Ok(vec![1u8]))).unwrap(), vec![1]);
+    }
+}
@@ -9,9 +9,10 @@ if os.path.exists(DB_NAME):
 
 con = sqlite3.connect(DB_NAME)
 cur = con.cursor()
-cur.execute("CREATE TABLE fuzzy_hashes(fh BLOB PRIMARY KEY, type TEXT)")
+cur.execute("CREATE TABLE fuzzy_hashes(fh BLOB PRIMARY KEY, type TEXT, tag TEXT)")
 
-data = [(os.urandom(32), "nilsimsa") for _ in range(POPULATION_SIZE)]
+# Two datasets, so that comparisons can be restricted to one of them using a tag
+data = [(os.urandom(32), "nilsimsa", "dataset-%d" % (i % 2)) for i in range(POPULATION_SIZE)]
 
-cur.executemany("INSERT INTO fuzzy_hashes VALUES (?, ?)", data)
+cur.executemany("INSERT INTO fuzzy_hashes VALUES (?, ?, ?)", data)
 con.commit()
\ No newline at end of file